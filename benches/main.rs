@@ -12,9 +12,11 @@ extern crate walkdir;
 
 use std::ffi::OsStr;
 use std::path::PathBuf;
+use std::sync::Mutex;
 
 use criterion::{Bencher, Criterion, black_box};
-use musium::{AlbumId, MetaIndex, MemoryMetaIndex};
+use musium::{AlbumId, MetaIndex, MemoryMetaIndex, ScanStatus};
+use musium::index_file::FileMetadata;
 
 fn build_index() -> MemoryMetaIndex {
     // TODO: Do not hard-code path.
@@ -25,16 +27,20 @@ fn build_index() -> MemoryMetaIndex {
 
     let flac_ext = OsStr::new("flac");
 
-    let paths: Vec<_> = wd
+    let mut files: Vec<_> = wd
         .into_iter()
         .map(|e| e.unwrap())
         .filter(|e| e.file_type().is_file())
-        .map(|e| PathBuf::from(e.path()))
-        .filter(|p| p.extension() == Some(flac_ext))
+        .filter(|e| e.path().extension() == Some(flac_ext))
+        .map(|e| (PathBuf::from(e.path()), FileMetadata::from_metadata(&e.metadata().unwrap())))
         .collect();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
 
     let sink = std::io::sink();
-    MemoryMetaIndex::from_paths(&paths[..], sink).expect("Failed to build index.")
+    let status = Mutex::new(ScanStatus::new());
+    let (index, _files, _issues) = MemoryMetaIndex::from_files(&files[..], Vec::new(), false, &status, sink)
+        .expect("Failed to build index.");
+    index
 }
 
 fn bench_get_artist(b: &mut Bencher) {
@@ -0,0 +1,201 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2021 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Selecting and grouping albums by genre, label, and other release details.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{AlbumId, ArtistId, MetaIndex};
+use crate::prim::{AlbumDetails, Mbid, ReleaseKind, StringRef};
+use crate::string_utils::normalize_words;
+
+fn normalize(value: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    normalize_words(value, &mut words);
+    words
+}
+
+/// Criteria to select albums with. Criteria that are not set match any album.
+///
+/// Values are compared after normalization, so `jazz` matches `Jazz`.
+#[derive(Default)]
+pub struct AlbumFilter {
+    genre: Option<Vec<String>>,
+    label: Option<Vec<String>>,
+    release_type: Option<Vec<String>>,
+    release_country: Option<Vec<String>>,
+    release_status: Option<Vec<String>>,
+}
+
+impl AlbumFilter {
+    pub fn new() -> AlbumFilter {
+        AlbumFilter::default()
+    }
+
+    /// Set a criterion from a query parameter. Returns false if the key is unknown.
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        let field = match key {
+            "genre" => &mut self.genre,
+            "label" => &mut self.label,
+            "release_type" => &mut self.release_type,
+            "release_country" => &mut self.release_country,
+            "release_status" => &mut self.release_status,
+            _ => return false,
+        };
+        *field = Some(normalize(value));
+        true
+    }
+
+    pub fn matches(&self, index: &dyn MetaIndex, id: AlbumId) -> bool {
+        let genre_ok = match self.genre {
+            None => true,
+            Some(ref genre) => index
+                .get_album_genres(id)
+                .iter()
+                .any(|&(_, g)| normalize(index.get_string(g)) == *genre),
+        };
+        let details = index.get_album_details(id);
+        let detail_ok = |wanted: &Option<Vec<String>>, field: fn(&AlbumDetails) -> StringRef| {
+            match *wanted {
+                None => true,
+                Some(ref wanted) => details.map_or(false, |d| {
+                    normalize(index.get_string(field(d))) == *wanted
+                }),
+            }
+        };
+        genre_ok
+            && detail_ok(&self.label, |d| d.label)
+            && detail_ok(&self.release_type, |d| d.release_type)
+            && detail_ok(&self.release_country, |d| d.release_country)
+            && detail_ok(&self.release_status, |d| d.release_status)
+    }
+}
+
+/// Count the albums per value, ordered by the normalized value.
+///
+/// Values that are equal after normalization are counted together, under the
+/// first spelling we encounter. Empty values are skipped. The input must be
+/// ordered by album id.
+fn count_albums<'a, I>(values: I) -> Vec<(&'a str, u32)>
+where I: Iterator<Item = (AlbumId, &'a str)> {
+    let mut counts: BTreeMap<Vec<String>, (&'a str, u32, AlbumId)> = BTreeMap::new();
+    for (album_id, value) in values {
+        if value.is_empty() { continue }
+        let entry = counts.entry(normalize(value)).or_insert((value, 0, AlbumId(0)));
+        // An album can have two spellings of the same genre, count it once.
+        if entry.1 == 0 || entry.2 != album_id {
+            entry.1 += 1;
+            entry.2 = album_id;
+        }
+    }
+    counts.into_iter().map(|(_, (value, n, _))| (value, n)).collect()
+}
+
+/// Return all genres, with the number of albums that have them.
+pub fn count_genres(index: &dyn MetaIndex) -> Vec<(&str, u32)> {
+    count_albums(
+        index
+            .get_all_album_genres()
+            .iter()
+            .map(|&(id, genre)| (id, index.get_string(genre)))
+    )
+}
+
+/// Return all labels, with the number of albums released on them.
+pub fn count_labels(index: &dyn MetaIndex) -> Vec<(&str, u32)> {
+    count_albums(
+        index
+            .get_albums()
+            .iter()
+            .filter_map(|&(id, _)| index.get_album_details(id).map(|d| (id, d.label)))
+            .map(|(id, label)| (id, index.get_string(label)))
+    )
+}
+
+/// Editions of the same release, grouped per kind of release.
+///
+/// Kinds are in the order of `ReleaseKind`, groups within a kind and albums
+/// within a group are in the order of the input.
+pub type Discography = Vec<(ReleaseKind, Vec<Vec<AlbumId>>)>;
+
+/// Group albums that belong to the same release group.
+///
+/// Albums without release group form a group of their own. A group is
+/// listed under the kind of its first album.
+fn group_editions<I>(albums: I) -> Discography
+where I: Iterator<Item = (AlbumId, ReleaseKind, Option<Mbid>)> {
+    let mut kinds: BTreeMap<ReleaseKind, Vec<Vec<AlbumId>>> = BTreeMap::new();
+    let mut groups: HashMap<Mbid, (ReleaseKind, usize)> = HashMap::new();
+    for (album_id, kind, release_group) in albums {
+        if let Some(&(group_kind, i)) = release_group.and_then(|rg| groups.get(&rg)) {
+            kinds.get_mut(&group_kind).unwrap()[i].push(album_id);
+            continue
+        }
+        let kind_groups = kinds.entry(kind).or_insert_with(Vec::new);
+        if let Some(rg) = release_group {
+            groups.insert(rg, (kind, kind_groups.len()));
+        }
+        kind_groups.push(vec![album_id]);
+    }
+    kinds.into_iter().collect()
+}
+
+/// Group the albums of an artist into a discography.
+///
+/// The albums are expected in the order of `get_albums_by_artist`, so the
+/// earliest edition of a release comes first.
+pub fn discography(index: &dyn MetaIndex, albums: &[(ArtistId, AlbumId)]) -> Discography {
+    group_editions(albums.iter().map(|&(_, album_id)| {
+        let kind = index
+            .get_album_details(album_id)
+            .map_or(ReleaseKind::Album, |d| d.release_kind());
+        let release_group = index
+            .get_album_mbids(album_id)
+            .and_then(|m| m.release_group.get());
+        (album_id, kind, release_group)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AlbumId;
+    use crate::prim::{Mbid, ReleaseKind};
+    use super::{count_albums, group_editions};
+
+    #[test]
+    fn count_albums_groups_normalized_values() {
+        let values = vec![
+            (AlbumId(1), "Jazz"),
+            (AlbumId(1), "jazz"),
+            (AlbumId(2), "Ambient"),
+            (AlbumId(2), ""),
+            (AlbumId(3), "JAZZ"),
+        ];
+        let counts = count_albums(values.into_iter());
+        assert_eq!(counts, vec![("Ambient", 1), ("Jazz", 2)]);
+    }
+
+    #[test]
+    fn group_editions_groups_by_release_group_and_kind() {
+        let rg1 = Some(Mbid([1; 16]));
+        let rg2 = Some(Mbid([2; 16]));
+        let albums = vec![
+            (AlbumId(1), ReleaseKind::Album, rg1),
+            (AlbumId(2), ReleaseKind::Live, None),
+            (AlbumId(3), ReleaseKind::Single, rg2),
+            (AlbumId(4), ReleaseKind::Album, None),
+            // A deluxe edition, tagged as compilation, stays with the original.
+            (AlbumId(5), ReleaseKind::Compilation, rg1),
+        ];
+        let groups = group_editions(albums.into_iter());
+        assert_eq!(groups, vec![
+            (ReleaseKind::Album, vec![vec![AlbumId(1), AlbumId(5)], vec![AlbumId(4)]]),
+            (ReleaseKind::Single, vec![vec![AlbumId(3)]]),
+            (ReleaseKind::Live, vec![vec![AlbumId(2)]]),
+        ]);
+    }
+}
@@ -16,15 +16,17 @@ use std::fmt;
 
 use crate::error::{Error, Result};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Config {
     pub listen: String,
     pub library_path: PathBuf,
     pub covers_path: PathBuf,
     pub data_path: PathBuf,
-    // TODO: Make this optional; pick the first one by default.
-    pub audio_device: String,
+    /// The Alsa card to play on. When not set, use the first usable card.
+    pub audio_device: Option<String>,
     pub audio_volume_control: String,
+    /// Whether to index files without MusicBrainz ids under derived ids.
+    pub fallback_ids: bool,
 }
 
 impl fmt::Display for Config {
@@ -33,8 +35,11 @@ impl fmt::Display for Config {
         write!(f, "  library_path = {}\n", self.library_path.to_string_lossy())?;
         write!(f, "  covers_path = {}\n", self.covers_path.to_string_lossy())?;
         write!(f, "  data_path = {}\n", self.data_path.to_string_lossy())?;
-        write!(f, "  audio_device = {}\n", self.audio_device)?;
-        write!(f, "  audio_volume_control = {}", self.audio_volume_control)?;
+        if let Some(ref audio_device) = self.audio_device {
+            write!(f, "  audio_device = {}\n", audio_device)?;
+        }
+        write!(f, "  audio_volume_control = {}\n", self.audio_volume_control)?;
+        write!(f, "  fallback_ids = {}", self.fallback_ids)?;
         Ok(())
     }
 }
@@ -51,6 +56,7 @@ impl Config {
         let mut data_path = None;
         let mut audio_device = None;
         let mut audio_volume_control = None;
+        let mut fallback_ids = false;
 
         for (lineno, line_raw) in lines.into_iter().enumerate() {
             let line = line_raw.as_ref();
@@ -75,10 +81,18 @@ impl Config {
                     "data_path" => data_path = Some(PathBuf::from(value)),
                     "audio_device" => audio_device = Some(String::from(value)),
                     "audio_volume_control" => audio_volume_control = Some(String::from(value)),
+                    "fallback_ids" => fallback_ids = match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => {
+                            let msg = "Invalid value. Expected 'true' or 'false'.";
+                            return Err(Error::InvalidConfig(lineno, msg))
+                        }
+                    },
                     _ => {
                         let msg = "Unknown key. Expected one of \
-                            'listen', 'library_path', 'covers_path', \
-                            or 'audio_device'.";
+                            'listen', 'library_path', 'covers_path', 'data_path', \
+                            'audio_device', 'audio_volume_control', or 'fallback_ids'.";
                         return Err(Error::InvalidConfig(lineno, msg))
                     }
                 }
@@ -112,18 +126,14 @@ impl Config {
                     "Data path not set. Expected 'data_path ='-line."
                 )),
             },
-            audio_device: match audio_device {
-                Some(d) => d,
-                None => return Err(Error::IncompleteConfig(
-                    "Audio device not set. Expected 'audio_device ='-line."
-                )),
-            },
+            audio_device: audio_device,
             audio_volume_control: match audio_volume_control {
                 Some(d) => d,
                 None => return Err(Error::IncompleteConfig(
                     "Audio volume control not set. Expected 'audio_volume_control ='-line."
                 )),
             },
+            fallback_ids: fallback_ids,
         };
 
         Ok(config)
@@ -152,7 +162,36 @@ mod test {
         assert_eq!(config.library_path.as_path(), Path::new("/home/user/music"));
         assert_eq!(config.covers_path.as_path(), Path::new("/home/user/.cache/musium/covers"));
         assert_eq!(config.data_path.as_path(), Path::new("/home/user/.local/share/musium"));
-        assert_eq!(&config.audio_device[..], "UCM404HD 192k");
+        assert_eq!(config.audio_device.as_ref().map(|d| &d[..]), Some("UCM404HD 192k"));
         assert_eq!(&config.audio_volume_control[..], "UMC404HD 192k Output");
     }
+
+    #[test]
+    pub fn config_audio_device_is_optional() {
+        let config_lines = [
+            "library_path = /home/user/music",
+            "covers_path = /home/user/.cache/musium/covers",
+            "data_path = /home/user/.local/share/musium",
+            "audio_volume_control = Master",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.audio_device, None);
+        assert_eq!(&config.audio_volume_control[..], "Master");
+        assert_eq!(config.fallback_ids, false);
+    }
+
+    #[test]
+    pub fn config_fallback_ids_must_be_bool() {
+        let config_lines = [
+            "library_path = /home/user/music",
+            "covers_path = /home/user/.cache/musium/covers",
+            "data_path = /home/user/.local/share/musium",
+            "audio_volume_control = Master",
+            "fallback_ids = true",
+        ];
+        assert_eq!(Config::parse(&config_lines).unwrap().fallback_ids, true);
+
+        let config_lines = ["fallback_ids = yes"];
+        assert!(Config::parse(&config_lines).is_err());
+    }
 }
@@ -0,0 +1,503 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2021 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Locating cover art, embedded in flac files or in the album directory.
+
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, SeekFrom};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The picture type of a front cover, as defined by the flac format.
+pub const PICTURE_TYPE_FRONT_COVER: u32 = 3;
+
+/// The metadata block type of a picture, as defined by the flac format.
+const BLOCK_TYPE_PICTURE: u32 = 6;
+
+/// File names that we recognize as cover art, in order of preference.
+const COVER_NAMES: &[&str] = &["cover", "folder", "front", "albumart"];
+
+/// Extensions of cover art files, in order of preference, with the mime type.
+const COVER_EXTENSIONS: &[(&str, &str)] = &[
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+];
+
+pub struct CoverArt {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Return the mime type of a cover art file, if the name is one we recognize.
+///
+/// Also returns the preference rank of the name, lower is better.
+fn match_cover_file_name(file_name: &str) -> Option<(usize, &'static str)> {
+    let lower = file_name.to_ascii_lowercase();
+    let dot = lower.rfind('.')?;
+    let (stem, ext) = (&lower[..dot], &lower[dot + 1..]);
+    let name_rank = COVER_NAMES.iter().position(|&n| n == stem)?;
+    let ext_rank = COVER_EXTENSIONS.iter().position(|&(e, _)| e == ext)?;
+    Some((name_rank * COVER_EXTENSIONS.len() + ext_rank, COVER_EXTENSIONS[ext_rank].1))
+}
+
+/// Find the preferred cover art file in the directory, if there is one.
+pub fn find_cover_file(dir: &Path) -> Option<(PathBuf, &'static str)> {
+    // If we can't list the directory, then there is no cover there for us.
+    let entries = fs::read_dir(dir).ok()?;
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let rank_mime = match_cover_file_name(entry.file_name().to_str()?)?;
+            Some((rank_mime, entry.path()))
+        })
+        .min_by_key(|&((rank, _), _)| rank)
+        .map(|((_, mime_type), path)| (path, mime_type))
+}
+
+/// Return a name for the flac picture type, for use in json.
+pub fn picture_type_name(picture_type: u32) -> &'static str {
+    match picture_type {
+        1 | 2 => "icon",
+        3 => "front_cover",
+        4 => "back_cover",
+        5 => "leaflet",
+        6 => "media",
+        7 | 8 | 9 | 10 | 11 | 12 => "artist",
+        13 | 14 | 15 => "recording",
+        18 => "illustration",
+        19 | 20 => "logo",
+        _ => "other",
+    }
+}
+
+/// Guess the picture type of an image file from its name.
+fn guess_picture_type(file_name: &str) -> u32 {
+    if match_cover_file_name(file_name).is_some() {
+        return PICTURE_TYPE_FRONT_COVER
+    }
+    let lower = file_name.to_ascii_lowercase();
+    let contains_any = |words: &[&str]| words.iter().any(|w| lower.contains(w));
+    if contains_any(&["back", "rear", "tray"]) { return 4 }
+    if contains_any(&["booklet", "leaflet", "inlay", "insert", "page"]) { return 5 }
+    if contains_any(&["disc", "disk", "cd", "media", "vinyl"]) { return 6 }
+    0
+}
+
+/// A picture embedded in a flac file, without its image data.
+pub struct PictureBlock {
+    pub picture_type: u32,
+    pub mime_type: String,
+    pub description: String,
+    /// Byte offset of the image data in the file.
+    pub data_offset: u64,
+    pub data_len: u64,
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u32_be<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0_u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Read a length-prefixed string from a picture block.
+fn read_picture_string<R: Read>(r: &mut io::Take<R>) -> io::Result<String> {
+    let len = read_u32_be(r)? as u64;
+    // The limit of the reader is what is left of the block, checking against
+    // it ensures that a corrupt length can't make us allocate a lot.
+    if len > r.limit() {
+        return Err(invalid_data("Picture block field extends beyond the block."))
+    }
+    let mut buf = vec![0_u8; len as usize];
+    r.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Parse the body of a picture metadata block that starts at `block_start`.
+fn read_picture_block<R: Read>(r: &mut R, block_start: u64, block_len: u64) -> io::Result<PictureBlock> {
+    let mut r = r.take(block_len);
+    let picture_type = read_u32_be(&mut r)?;
+    let mime_type = read_picture_string(&mut r)?;
+    let description = read_picture_string(&mut r)?;
+    // Width, height, color depth, and number of colors. We read the dimensions
+    // from the image itself, the tagger may have gotten these wrong.
+    let mut image_info = [0_u8; 16];
+    r.read_exact(&mut image_info)?;
+    let data_len = read_u32_be(&mut r)? as u64;
+    if data_len > r.limit() {
+        return Err(invalid_data("Picture data extends beyond the block."))
+    }
+    let block = PictureBlock {
+        picture_type: picture_type,
+        mime_type: mime_type,
+        description: description,
+        data_offset: block_start + block_len - r.limit(),
+        data_len: data_len,
+    };
+    Ok(block)
+}
+
+/// Read the picture metadata blocks of a flac file, without the image data.
+///
+/// Claxon can only hand us the front cover, so we walk the metadata blocks
+/// ourselves. That also lets us skip over the image data, which we may not
+/// need.
+pub fn read_picture_blocks<R: Read + Seek>(r: &mut R) -> io::Result<Vec<PictureBlock>> {
+    let mut magic = [0_u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        return Err(invalid_data("Not a flac file."))
+    }
+
+    let mut pictures = Vec::new();
+    let mut pos = 4;
+    loop {
+        // One bit that marks the last block, 7 bits of block type, and 24 bits
+        // of block length.
+        let header = read_u32_be(r)?;
+        let is_last = header >> 31 == 1;
+        let block_type = (header >> 24) & 0x7f;
+        let block_len = (header & 0xff_ffff) as u64;
+        let block_start = pos + 4;
+        if block_type == BLOCK_TYPE_PICTURE {
+            pictures.push(read_picture_block(r, block_start, block_len)?);
+        }
+        if is_last {
+            return Ok(pictures)
+        }
+        pos = block_start + block_len;
+        r.seek(SeekFrom::Start(pos))?;
+    }
+}
+
+/// Read the image data of a picture in the file, see `read_picture_blocks`.
+fn read_picture_data<R: Read + Seek>(r: &mut R, block: &PictureBlock) -> io::Result<Vec<u8>> {
+    r.seek(SeekFrom::Start(block.data_offset))?;
+    let mut data = vec![0_u8; block.data_len as usize];
+    r.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Read the width and height from a png or jpeg header.
+fn read_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| Some(((*data.get(i)? as u32) << 8) | *data.get(i + 1)? as u32);
+    let be32 = |i: usize| Some((be16(i)? << 16) | be16(i + 2)?);
+
+    // A png starts with an 8-byte signature, followed by the IHDR chunk.
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") && data.get(12..16) == Some(&b"IHDR"[..]) {
+        return Some((be32(16)?, be32(20)?))
+    }
+
+    // A jpeg is a sequence of segments, the start-of-frame one holds the
+    // dimensions. We skip over the others using their length.
+    if !data.starts_with(&[0xff, 0xd8]) { return None }
+    let mut i = 2;
+    loop {
+        if *data.get(i)? != 0xff { return None }
+        let marker = *data.get(i + 1)?;
+        match marker {
+            // SOF0 through SOF15, except DHT (c4), JPG (c8), and DAC (cc).
+            0xc0 ..= 0xcf if marker != 0xc4 && marker != 0xc8 && marker != 0xcc => {
+                return Some((be16(i + 7)?, be16(i + 5)?))
+            }
+            _ => i += 2 + be16(i + 2)? as usize,
+        }
+    }
+}
+
+/// Where the image data of a picture is stored.
+pub enum PictureSource {
+    /// Embedded in a flac file, starting at the given byte offset.
+    Embedded(PathBuf, u64),
+    /// An image file.
+    File(PathBuf),
+}
+
+/// A picture of an album, embedded in one of its files, or in its directory.
+///
+/// This does not hold the image data, only where to find it, so a listing of
+/// pictures is cheap to keep around.
+pub struct AlbumPicture {
+    pub picture_type: u32,
+    pub mime_type: String,
+    pub description: String,
+    /// Width and height in pixels, if we could read them from the image header.
+    pub dimensions: Option<(u32, u32)>,
+    pub size_bytes: u64,
+    pub source: PictureSource,
+}
+
+impl AlbumPicture {
+    pub fn is_embedded(&self) -> bool {
+        match self.source {
+            PictureSource::Embedded(..) => true,
+            PictureSource::File(..) => false,
+        }
+    }
+
+    /// Read the image data.
+    ///
+    /// If the file changed since the picture was listed, this may return the
+    /// wrong bytes. A change to the library triggers a reindex though, and we
+    /// list the pictures again after that.
+    pub fn read_data(&self) -> io::Result<Vec<u8>> {
+        match self.source {
+            PictureSource::Embedded(ref path, offset) => {
+                let mut f = fs::File::open(path)?;
+                f.seek(SeekFrom::Start(offset))?;
+                let mut data = vec![0_u8; self.size_bytes as usize];
+                f.read_exact(&mut data)?;
+                Ok(data)
+            }
+            PictureSource::File(ref path) => fs::read(path),
+        }
+    }
+}
+
+/// Return a hash of the image data, to recognize identical pictures.
+fn hash_data(data: &[u8]) -> (usize, u64) {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    (data.len(), hasher.finish())
+}
+
+/// Return the image files in the directory and its direct subdirectories.
+///
+/// Booklet scans are often in a subdirectory, such as `Scans`. The files are
+/// sorted by path, so the order is stable.
+fn find_image_files(dir: &Path, recurse: bool, into: &mut Vec<(PathBuf, &'static str)>) {
+    // If we can't list the directory, then there are no pictures there for us.
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(..) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            if recurse { find_image_files(&path, false, into); }
+            continue
+        }
+        let ext = match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) => ext.to_ascii_lowercase(),
+            None => continue,
+        };
+        if let Some(&(_, mime_type)) = COVER_EXTENSIONS.iter().find(|&&(e, _)| e == ext) {
+            into.push((path, mime_type));
+        }
+    }
+}
+
+/// List all pictures of an album, given the flac files of its tracks.
+///
+/// First the pictures embedded in the files, in track order, then the image
+/// files in the directories of the tracks. Most albums embed the same cover in
+/// every track, we list identical pictures only once. To find those, and to
+/// read the dimensions, this reads every picture once, so callers should keep
+/// the listing rather than calling this for every picture.
+///
+/// Files that cannot be read, for example because they are being replaced
+/// while we list, are skipped, we list the pictures of the other files.
+pub fn list_album_pictures(flac_filenames: &[&str]) -> Vec<AlbumPicture> {
+    let mut pictures = Vec::new();
+    let mut seen = HashSet::new();
+    let mut dirs = Vec::new();
+
+    for &flac_filename in flac_filenames {
+        // If one file is unreadable, the other tracks likely embed the same
+        // pictures anyway, so there is no need to fail the listing.
+        let _ = list_embedded_pictures(flac_filename, &mut seen, &mut pictures);
+        let dir = Path::new(flac_filename).parent().unwrap_or(Path::new("."));
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+
+    let mut image_files = Vec::new();
+    for dir in dirs {
+        find_image_files(dir, true, &mut image_files);
+    }
+    image_files.sort();
+    image_files.dedup();
+
+    for (path, mime_type) in image_files {
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(..) => continue,
+        };
+        if !seen.insert(hash_data(&data)) { continue }
+        let file_name = path.file_name().and_then(|f| f.to_str()).unwrap_or("").to_string();
+        pictures.push(AlbumPicture {
+            picture_type: guess_picture_type(&file_name),
+            mime_type: mime_type.to_string(),
+            description: file_name,
+            dimensions: read_dimensions(&data),
+            size_bytes: data.len() as u64,
+            source: PictureSource::File(path),
+        });
+    }
+
+    pictures
+}
+
+/// Append the pictures embedded in the flac file that we did not see yet.
+fn list_embedded_pictures(
+    flac_filename: &str,
+    seen: &mut HashSet<(usize, u64)>,
+    pictures: &mut Vec<AlbumPicture>,
+) -> io::Result<()> {
+    let mut f = io::BufReader::new(fs::File::open(flac_filename)?);
+    for block in read_picture_blocks(&mut f)? {
+        let data = read_picture_data(&mut f, &block)?;
+        if !seen.insert(hash_data(&data)) { continue }
+        pictures.push(AlbumPicture {
+            picture_type: block.picture_type,
+            mime_type: block.mime_type,
+            description: block.description,
+            dimensions: read_dimensions(&data),
+            size_bytes: block.data_len,
+            source: PictureSource::Embedded(PathBuf::from(flac_filename), block.data_offset),
+        });
+    }
+    Ok(())
+}
+
+/// Read the front cover of the album that the flac file belongs to.
+///
+/// Prefers an embedded front cover, then a cover file next to the flac file,
+/// such as `cover.jpg`, and then any other embedded picture. Thumbnails and
+/// the cover endpoint both use this, so they show the same picture.
+pub fn read_cover(flac_filename: &str) -> io::Result<Option<CoverArt>> {
+    let mut f = io::BufReader::new(fs::File::open(flac_filename)?);
+    let mut pictures = read_picture_blocks(&mut f)?;
+
+    let front = pictures.iter().position(|p| p.picture_type == PICTURE_TYPE_FRONT_COVER);
+    if let Some(i) = front {
+        let picture = pictures.swap_remove(i);
+        let data = read_picture_data(&mut f, &picture)?;
+        return Ok(Some(CoverArt { mime_type: picture.mime_type, data: data }))
+    }
+
+    let dir = Path::new(flac_filename).parent().unwrap_or(Path::new("."));
+    if let Some((path, mime_type)) = find_cover_file(dir) {
+        let data = fs::read(path)?;
+        return Ok(Some(CoverArt { mime_type: mime_type.to_string(), data: data }))
+    }
+
+    match pictures.pop() {
+        Some(picture) => {
+            let data = read_picture_data(&mut f, &picture)?;
+            Ok(Some(CoverArt { mime_type: picture.mime_type, data: data }))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{guess_picture_type, match_cover_file_name, read_dimensions, read_picture_blocks, read_picture_data};
+
+    /// Build a flac picture metadata block, including its header.
+    fn picture_block(is_last: bool, picture_type: u32, mime_type: &str, data: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&picture_type.to_be_bytes());
+        body.extend_from_slice(&(mime_type.len() as u32).to_be_bytes());
+        body.extend_from_slice(mime_type.as_bytes());
+        body.extend_from_slice(&5_u32.to_be_bytes());
+        body.extend_from_slice(b"Front");
+        body.extend_from_slice(&[0; 16]);
+        body.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        body.extend_from_slice(data);
+
+        let header = ((is_last as u32) << 31) | (6 << 24) | body.len() as u32;
+        let mut block = header.to_be_bytes().to_vec();
+        block.extend_from_slice(&body);
+        block
+    }
+
+    #[test]
+    fn read_picture_blocks_finds_pictures_between_other_blocks() {
+        let mut flac = b"fLaC".to_vec();
+        // A streaminfo block, which we should skip.
+        flac.extend_from_slice(&[0, 0, 0, 34]);
+        flac.extend_from_slice(&[0; 34]);
+        flac.extend_from_slice(&picture_block(false, 4, "image/png", b"back"));
+        flac.extend_from_slice(&picture_block(true, 3, "image/jpeg", b"front"));
+        // Audio data follows the last metadata block.
+        flac.extend_from_slice(&[0xff, 0xf8]);
+
+        let mut r = io::Cursor::new(&flac[..]);
+        let blocks = read_picture_blocks(&mut r).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].picture_type, 4);
+        assert_eq!(blocks[0].mime_type, "image/png");
+        assert_eq!(blocks[1].picture_type, 3);
+        assert_eq!(blocks[1].mime_type, "image/jpeg");
+        assert_eq!(blocks[1].description, "Front");
+        assert_eq!(read_picture_data(&mut r, &blocks[0]).unwrap(), b"back");
+        assert_eq!(read_picture_data(&mut r, &blocks[1]).unwrap(), b"front");
+    }
+
+    #[test]
+    fn read_picture_blocks_rejects_truncated_blocks() {
+        let mut flac = b"fLaC".to_vec();
+        let mut block = picture_block(true, 3, "image/jpeg", b"front");
+        // Claim more data than the block holds.
+        let data_len_pos = block.len() - 5 - 4;
+        block[data_len_pos + 3] = 6;
+        flac.extend_from_slice(&block);
+        assert!(read_picture_blocks(&mut io::Cursor::new(&flac[..])).is_err());
+
+        assert!(read_picture_blocks(&mut io::Cursor::new(&b"OggS"[..])).is_err());
+    }
+
+    #[test]
+    fn match_cover_file_name_ranks_names_then_extensions() {
+        assert_eq!(match_cover_file_name("cover.jpg"), Some((0, "image/jpeg")));
+        assert_eq!(match_cover_file_name("Cover.PNG"), Some((2, "image/png")));
+        assert_eq!(match_cover_file_name("folder.jpg"), Some((3, "image/jpeg")));
+        assert_eq!(match_cover_file_name("back.jpg"), None);
+        assert_eq!(match_cover_file_name("cover.gif"), None);
+        assert_eq!(match_cover_file_name("cover"), None);
+    }
+
+    #[test]
+    fn guess_picture_type_recognizes_common_names() {
+        assert_eq!(guess_picture_type("Folder.jpg"), 3);
+        assert_eq!(guess_picture_type("back.png"), 4);
+        assert_eq!(guess_picture_type("booklet-03.jpg"), 5);
+        assert_eq!(guess_picture_type("cd1.jpg"), 6);
+        assert_eq!(guess_picture_type("obi.jpg"), 0);
+    }
+
+    #[test]
+    fn read_dimensions_reads_png_and_jpeg_headers() {
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&[0, 0, 0x02, 0x58, 0, 0, 0x01, 0x2c]);
+        assert_eq!(read_dimensions(&png), Some((600, 300)));
+
+        let jpeg = [
+            0xff, 0xd8,
+            // An APP0 segment of length 4, which we should skip.
+            0xff, 0xe0, 0x00, 0x04, 0x00, 0x00,
+            // SOF0: length, precision, height, width.
+            0xff, 0xc0, 0x00, 0x11, 0x08, 0x01, 0x2c, 0x02, 0x58,
+        ];
+        assert_eq!(read_dimensions(&jpeg), Some((600, 300)));
+
+        assert_eq!(read_dimensions(&jpeg[..12]), None);
+        assert_eq!(read_dimensions(b"GIF89a"), None);
+    }
+}
@@ -13,12 +13,19 @@ use sqlite;
 use sqlite3_sys;
 
 use crate::{MetaIndex, TrackId};
+use crate::prim::{AlbumId, ArtistId, get_album_id, get_track_id};
+use crate::scan::FALLBACK_ID_BIT;
+use crate::swap::Swap;
 use crate::player::QueueId;
 
 /// Changes in the playback state to be recorded.
+///
+/// Events carry the time at which the change becomes audible. Samples are
+/// handed to the audio device some time before they come out of the speakers,
+/// so this time can lie slightly in the future when the event is sent.
 pub enum PlaybackEvent {
-    Started(QueueId, TrackId),
-    Completed(QueueId, TrackId),
+    Started(QueueId, TrackId, chrono::DateTime<chrono::Utc>),
+    Completed(QueueId, TrackId, chrono::DateTime<chrono::Utc>),
 }
 
 type Result<T> = sqlite::Result<T>;
@@ -67,10 +74,46 @@ fn initialize_db(connection: &sqlite::Connection) -> Result<Database> {
         -- ISO-8601 time with UTC offset at which we scrobbled the track to Last.fm.
         -- NULL if the track has not been scrobbled by us.
         , scrobbled_at     string  null     check (started_at < scrobbled_at)
+
+        -- Musicbrainz ids, NULL if the file was not tagged with them.
+        , recording_mbid     string  null
+        , track_mbid         string  null
+        , release_mbid       string  null
+        , release_group_mbid string  null
         );
         "
     )?;
 
+    // Databases created by older versions lack the Musicbrainz id columns.
+    // Finish the pragma statement before altering the table, or the table is locked.
+    let mut columns = Vec::new();
+    {
+        let mut table_info = connection.prepare("pragma table_info(listens);")?;
+        while table_info.next()? == sqlite::State::Row {
+            columns.push(table_info.read::<String>(1)?);
+        }
+    }
+    for &column in &["recording_mbid", "track_mbid", "release_mbid", "release_group_mbid"] {
+        if !columns.iter().any(|c| c == column) {
+            connection.execute(format!("alter table listens add column {} string null;", column))?;
+        }
+    }
+
+    // Databases created by older versions use an older track id layout, see
+    // `migrate_track_ids`. We track the layout in the user version pragma.
+    let mut user_version = 0;
+    {
+        let mut pragma = connection.prepare("pragma user_version;")?;
+        if pragma.next()? == sqlite::State::Row {
+            user_version = pragma.read::<i64>(0)?;
+        }
+    }
+    if user_version < 1 {
+        connection.execute("begin;")?;
+        migrate_track_ids(connection)?;
+        connection.execute("pragma user_version = 1; commit;")?;
+    }
+
     // We can record timestamps in sub-second granularity, but external systems
     // do not always support this. Last.fm only has second granularity. So if we
     // produce a listen, submit it to Last.fm, and later import it back, then we
@@ -98,10 +141,14 @@ fn initialize_db(connection: &sqlite::Connection) -> Result<Database> {
         , duration_seconds
         , track_number
         , disc_number
+        , recording_mbid
+        , track_mbid
+        , release_mbid
+        , release_group_mbid
         , source
         )
         values
-        ( ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 'musium');
+        ( ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 'musium');
         "
     )?;
 
@@ -125,6 +172,75 @@ fn initialize_db(connection: &sqlite::Connection) -> Result<Database> {
     Ok(result)
 }
 
+/// Compute the new track, album, and album artist id of a listen.
+///
+/// Older versions took the album id from the full 64 bits of the MusicBrainz
+/// id, and the track id from the high 52 bits of that, with 4 bits of disc
+/// number and 8 bits of track number. The artist id was the full 64 bits of
+/// the artist's MusicBrainz id. Now ids derived from MusicBrainz ids have the
+/// most significant bit cleared, see `FALLBACK_ID_BIT`, and the album id is
+/// the high 44 bits of that, see `get_track_id`.
+fn migrate_ids(old_track_id: u64, old_artist_id: u64, disc_number: u8) -> (TrackId, AlbumId, ArtistId) {
+    let album_id = get_album_id(old_track_id & !FALLBACK_ID_BIT);
+    let track_number = (old_track_id & 0xff) as u16;
+    let track_id = get_track_id(album_id, disc_number, track_number);
+    let artist_id = ArtistId(old_artist_id & !FALLBACK_ID_BIT);
+    (track_id, album_id, artist_id)
+}
+
+/// Convert the ids in the listens table to the current layout.
+///
+/// The new ids can be computed from the old ones, see `migrate_ids`, so after
+/// the conversion, listens use the same ids as the index does for those
+/// tracks, albums, and artists. Listens of files that were retagged since are
+/// not affected by this, their ids were already different before.
+fn migrate_track_ids(connection: &sqlite::Connection) -> Result<()> {
+    let mut rows = Vec::new();
+    {
+        // The disc number column holds all bits of the disc number, the old
+        // track id only the low 4.
+        let mut select = connection.prepare(
+            "
+            select
+              id,
+              track_id,
+              album_artist_id,
+              coalesce(disc_number, (track_id >> 8) & 15)
+            from
+              listens;
+            "
+        )?;
+        while select.next()? == sqlite::State::Row {
+            rows.push((
+                select.read::<i64>(0)?,
+                select.read::<i64>(1)?,
+                select.read::<i64>(2)?,
+                select.read::<i64>(3)?,
+            ));
+        }
+    }
+
+    let mut update = connection.prepare(
+        "update listens set track_id = ?, album_id = ?, album_artist_id = ? where id = ?;"
+    )?;
+    for (row_id, old_track_id, old_artist_id, disc_number) in rows {
+        let (track_id, album_id, artist_id) = migrate_ids(
+            old_track_id as u64,
+            old_artist_id as u64,
+            disc_number as u8,
+        );
+        update.reset()?;
+        update.bind(1, track_id.0 as i64)?;
+        update.bind(2, album_id.0 as i64)?;
+        update.bind(3, artist_id.0 as i64)?;
+        update.bind(4, row_id)?;
+        let result = update.next()?;
+        assert_eq!(result, sqlite::State::Done);
+    }
+
+    Ok(())
+}
+
 /// Insert a new row into the "listens" table.
 fn insert_started(
     db: &mut Database,
@@ -135,24 +251,45 @@ fn insert_started(
 ) -> Result<()> {
     let use_zulu_suffix = true;
     let time_str = time.to_rfc3339_opts(chrono::SecondsFormat::Millis, use_zulu_suffix);
-    let track = index.get_track(track_id).unwrap();
-    let album = index.get_album(track.album_id).unwrap();
-    let artist = index.get_artist(album.artist_id).unwrap();
+    // The track may be missing if it was removed from the library by a
+    // reindex while it was already playing. In that case we can't record it.
+    let track = match index.get_track(track_id) {
+        Some(t) => t,
+        None => {
+            println!("Not recording listen of track {}, it is no longer in the index.", track_id);
+            db.last_insert_id = None;
+            return Ok(())
+        }
+    };
+    let album = index.get_album(track_id.album_id()).unwrap();
 
     db.insert_started.reset()?;
     db.insert_started.bind(1, &time_str[..])?;
     db.insert_started.bind(2, queue_id.0 as i64)?;
     db.insert_started.bind(3, track_id.0 as i64)?;
-    db.insert_started.bind(4, track.album_id.0 as i64)?;
+    db.insert_started.bind(4, track_id.album_id().0 as i64)?;
     db.insert_started.bind(5, album.artist_id.0 as i64)?;
     db.insert_started.bind(6, index.get_string(track.title))?;
     db.insert_started.bind(7, index.get_string(album.title))?;
     db.insert_started.bind(8, index.get_string(track.artist))?;
-    db.insert_started.bind(9, index.get_string(artist.name))?;
+    db.insert_started.bind(9, index.get_string(album.artist))?;
     db.insert_started.bind(10, track.duration_seconds as i64)?;
     db.insert_started.bind(11, track.track_number as i64)?;
     db.insert_started.bind(12, track.disc_number as i64)?;
 
+    let track_mbids = index.get_track_mbids(track_id);
+    let album_mbids = index.get_album_mbids(track_id.album_id());
+    let mbids = [
+        track_mbids.and_then(|m| m.recording.get()),
+        track_mbids.and_then(|m| m.track.get()),
+        album_mbids.and_then(|m| m.release.get()),
+        album_mbids.and_then(|m| m.release_group.get()),
+    ];
+    for (i, mbid) in mbids.iter().enumerate() {
+        let mbid_str = mbid.map(|m| m.to_string());
+        db.insert_started.bind(13 + i, mbid_str.as_ref().map(|s| &s[..]))?;
+    }
+
     let result = db.insert_started.next()?;
     // This query returns no rows, it should be done immediately.
     assert_eq!(result, sqlite::State::Done);
@@ -195,15 +332,13 @@ fn append_event(
     index: &dyn MetaIndex,
     event: PlaybackEvent,
 ) -> Result<()> {
-    let now = chrono::Utc::now();
-
     match event {
-        PlaybackEvent::Started(queue_id, track_id) => {
-            insert_started(db, index, now, queue_id, track_id)?;
+        PlaybackEvent::Started(queue_id, track_id, time) => {
+            insert_started(db, index, time, queue_id, track_id)?;
         }
-        PlaybackEvent::Completed(queue_id, track_id) => {
+        PlaybackEvent::Completed(queue_id, track_id, time) => {
             if let Some(row_id) = db.last_insert_id {
-                update_completed(db, row_id, now, queue_id, track_id)?;
+                update_completed(db, row_id, time, queue_id, track_id)?;
             }
         }
     }
@@ -214,15 +349,46 @@ fn append_event(
 /// Main for the thread that logs historical playback events.
 pub fn main(
     db_path: PathBuf,
-    index: &dyn MetaIndex,
+    index_var: &Swap<dyn MetaIndex + Send + Sync>,
     events: Receiver<PlaybackEvent>,
 ) {
     let connection = sqlite::open(db_path).expect("Failed to open SQLite database.");
     let mut db = initialize_db(&connection).expect("Failed to initialize SQLite database.");
     for event in events {
-        match append_event(&mut db, index, event) {
+        let index = index_var.get();
+        match append_event(&mut db, &*index, event) {
             Ok(()) => {},
             Err(err) => eprintln!("Failed to write event to SQLite database: {}", err),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::prim::{AlbumId, ArtistId, get_track_id};
+    use super::migrate_ids;
+
+    #[test]
+    fn migrate_ids_clears_fallback_bit() {
+        // Disc 2, track 3, of an album whose MusicBrainz id has the top bit set,
+        // as the old layout stored it.
+        let old_album_id = 0x9f2a_0bc4_2c3e_1d10_u64;
+        let old_track_id = (old_album_id & 0xffff_ffff_ffff_f000) | (2 << 8) | 3;
+        let old_artist_id = 0xc2d1_1d2b_1b7f_a0b5_u64;
+
+        let (track_id, album_id, artist_id) = migrate_ids(old_track_id, old_artist_id, 2);
+        assert_eq!(album_id, AlbumId(0x1f2a_0bc4_2c30_0000));
+        assert_eq!(track_id, get_track_id(album_id, 2, 3));
+        assert_eq!(artist_id, ArtistId(0x42d1_1d2b_1b7f_a0b5));
+    }
+
+    #[test]
+    fn migrate_ids_keeps_disc_number_beyond_four_bits() {
+        let old_album_id = 0x1f2a_0bc4_2c3e_1d10_u64;
+        // The old track id holds only the low 4 bits of disc 17.
+        let old_track_id = (old_album_id & 0xffff_ffff_ffff_f000) | (1 << 8) | 12;
+        let (track_id, album_id, _) = migrate_ids(old_track_id, 1, 17);
+        assert_eq!(album_id, AlbumId(0x1f2a_0bc4_2c30_0000));
+        assert_eq!(track_id, get_track_id(album_id, 17, 12));
+    }
+}
@@ -0,0 +1,1703 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2021 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Reading and writing the index to disk, to avoid rescanning at startup.
+//!
+//! The index file is designed to be memory-mapped, so loading it is instant,
+//! and the index does not count towards resident memory. The layout is:
+//!
+//! * A header with a magic string, the format version, the flags that affect
+//!   how the index was built, and a section table. The section table lists the
+//!   byte offset and length of every section. The header is encoded
+//!   little-endian.
+//! * The sections. Every section starts at an 8-byte aligned offset. Most
+//!   sections are arrays stored in the in-memory representation of the
+//!   element type, so we can use them in place without decoding.
+//!
+//! The in-memory representation of tuples and non-`repr(C)` structs is not
+//! guaranteed to be stable across compiler versions, and it depends on the
+//! endianness of the machine. Therefore the file includes canary sections
+//! with known values of those types. If the canaries do not read back as the
+//! same values, the file was written by an incompatible build, and we treat
+//! it like a version mismatch: discard it and rebuild the index.
+//!
+//! The file also stores the size and modification time of every file that
+//! was scanned, so we can tell whether the index is still up to date, and the
+//! tags of every file, so we can rebuild the index without reading all files
+//! again when only a few of them changed. Finally, it stores the issues that
+//! were found while building the index, so we can report them without a scan.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::result;
+use std::slice;
+use std::str;
+
+use nix::sys::mman::{MapFlags, ProtFlags, mmap, munmap};
+
+use crate::{Bookmarks, MemoryMetaIndex, MetaIndex, find_album_tracks, find_all_by_id, find_by_id, search};
+use crate::scan::{FileTags, Issue, IssueDetail};
+use crate::prim::{AlbumId, Album, AlbumDetails, AlbumMbids, ArtistCredit, ArtistId, Artist, ComposerId, Composer, Date, FilenameRef, Lufs, Mbid, OptionLufs, ReleaseKind, StringRef, TrackId, Track, TrackMbids, TrackWork, WorkId, Work, get_track_id};
+use crate::word_index::{Key, Values, WordIndexRef, WordIndexSize, WordMeta};
+
+/// Magic bytes at the start of the index file.
+const MAGIC: &[u8; 8] = b"MUSIDX\r\n";
+
+/// The version of the file format, bump on every incompatible change.
+pub const FORMAT_VERSION: u32 = 18;
+
+/// Header flag, set when the index was built with `fallback_ids` enabled.
+const FLAG_FALLBACK_IDS: u32 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing the file failed.
+    IoError(io::Error),
+
+    /// The file does not start with the expected magic bytes.
+    NotAnIndex,
+
+    /// The file was written in a different format version.
+    VersionMismatch(u32),
+
+    /// The file has our format version, but a different memory layout,
+    /// because it was written by an incompatible build.
+    IncompatibleLayout,
+
+    /// The file is well-formed, but its contents are inconsistent.
+    Corrupt(&'static str),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IoError(ref err) => write!(f, "{}", err),
+            Error::NotAnIndex => write!(f, "The file is not a Musium index."),
+            Error::VersionMismatch(v) => write!(
+                f, "The index has format version {}, but I need version {}.", v, FORMAT_VERSION,
+            ),
+            Error::IncompatibleLayout => write!(
+                f, "The index was written by a build with a different memory layout.",
+            ),
+            Error::Corrupt(what) => write!(f, "The index is corrupt: {}", what),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Size and modification time of a file, used to detect changes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FileMetadata {
+    pub size: u64,
+    pub mtime_secs: i64,
+    pub mtime_nanos: u32,
+}
+
+impl FileMetadata {
+    pub fn from_metadata(meta: &fs::Metadata) -> FileMetadata {
+        FileMetadata {
+            size: meta.size(),
+            mtime_secs: meta.mtime(),
+            mtime_nanos: meta.mtime_nsec() as u32,
+        }
+    }
+}
+
+/// A file that the index was built from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScannedFile {
+    pub path: PathBuf,
+    pub metadata: FileMetadata,
+    pub tags: FileTags,
+}
+
+/// A value that can be written to and read back from an index file.
+pub trait Encode: Sized {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()>;
+    fn decode<R: Read>(r: &mut R) -> Result<Self>;
+}
+
+macro_rules! impl_encode_int {
+    ($t: ty, $n: expr) => {
+        impl Encode for $t {
+            fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                w.write_all(&self.to_le_bytes())
+            }
+
+            fn decode<R: Read>(r: &mut R) -> Result<$t> {
+                let mut buf = [0_u8; $n];
+                r.read_exact(&mut buf)?;
+                Ok(<$t>::from_le_bytes(buf))
+            }
+        }
+    }
+}
+
+impl_encode_int!(u8, 1);
+impl_encode_int!(u16, 2);
+impl_encode_int!(i16, 2);
+impl_encode_int!(u32, 4);
+impl_encode_int!(u64, 8);
+impl_encode_int!(i64, 8);
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.0.encode(w)?;
+        self.1.encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<(A, B)> {
+        let a = A::decode(r)?;
+        let b = B::decode(r)?;
+        Ok((a, b))
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (self.len() as u64).encode(w)?;
+        for x in self {
+            x.encode(w)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<Vec<T>> {
+        let len = u64::decode(r)? as usize;
+        // Don't trust the length for the allocation, a corrupt file could make
+        // us allocate way too much. Beyond this, the vector grows as needed.
+        let mut xs = Vec::with_capacity(len.min(1 << 16));
+        for _ in 0..len {
+            xs.push(T::decode(r)?);
+        }
+        Ok(xs)
+    }
+}
+
+impl Encode for PathBuf {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let bytes = self.as_os_str().as_bytes();
+        (bytes.len() as u32).encode(w)?;
+        w.write_all(bytes)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<PathBuf> {
+        let len = u32::decode(r)? as u64;
+        let mut buf = Vec::new();
+        r.take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(Error::Corrupt("path extends beyond end of file."))
+        }
+        Ok(PathBuf::from(OsStr::from_bytes(&buf)))
+    }
+}
+
+impl Encode for FileMetadata {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.size.encode(w)?;
+        self.mtime_secs.encode(w)?;
+        self.mtime_nanos.encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<FileMetadata> {
+        let result = FileMetadata {
+            size: u64::decode(r)?,
+            mtime_secs: i64::decode(r)?,
+            mtime_nanos: u32::decode(r)?,
+        };
+        Ok(result)
+    }
+}
+
+impl Encode for String {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (self.len() as u32).encode(w)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<String> {
+        let len = u32::decode(r)? as u64;
+        let mut buf = String::new();
+        r.take(len).read_to_string(&mut buf).map_err(|err| match err.kind() {
+            io::ErrorKind::InvalidData => Error::Corrupt("string is not valid UTF-8."),
+            _ => Error::IoError(err),
+        })?;
+        if buf.len() as u64 != len {
+            return Err(Error::Corrupt("string extends beyond end of file."))
+        }
+        Ok(buf)
+    }
+}
+
+impl Encode for FileTags {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.channels.encode(w)?;
+        self.bits_per_sample.encode(w)?;
+        self.sample_rate.encode(w)?;
+        // Claxon reports a sample count of 0 as unknown, so we can use 0 for None.
+        self.samples.unwrap_or(0).encode(w)?;
+        self.tags.encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<FileTags> {
+        let result = FileTags {
+            channels: u32::decode(r)?,
+            bits_per_sample: u32::decode(r)?,
+            sample_rate: u32::decode(r)?,
+            samples: match u64::decode(r)? {
+                0 => None,
+                n => Some(n),
+            },
+            tags: Vec::decode(r)?,
+        };
+        Ok(result)
+    }
+}
+
+impl Encode for ScannedFile {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.path.encode(w)?;
+        self.metadata.encode(w)?;
+        self.tags.encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<ScannedFile> {
+        let result = ScannedFile {
+            path: PathBuf::decode(r)?,
+            metadata: FileMetadata::decode(r)?,
+            tags: FileTags::decode(r)?,
+        };
+        Ok(result)
+    }
+}
+
+impl Encode for AlbumId {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.0.encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<AlbumId> {
+        Ok(AlbumId(u64::decode(r)?))
+    }
+}
+
+impl Encode for ArtistId {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.0.encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<ArtistId> {
+        Ok(ArtistId(u64::decode(r)?))
+    }
+}
+
+impl Encode for TrackId {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.0.encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<TrackId> {
+        Ok(TrackId(u64::decode(r)?))
+    }
+}
+
+impl Encode for Date {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.year.encode(w)?;
+        self.month.encode(w)?;
+        self.day.encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<Date> {
+        let result = Date {
+            year: u16::decode(r)?,
+            month: u8::decode(r)?,
+            day: u8::decode(r)?,
+        };
+        Ok(result)
+    }
+}
+
+impl Encode for Option<Lufs> {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        // Loudness is never 0.0 LUFS, so we can use 0 for None.
+        self.map_or(0, |lufs| lufs.0.get()).encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<Option<Lufs>> {
+        Ok(std::num::NonZeroI16::new(i16::decode(r)?).map(Lufs))
+    }
+}
+
+impl Encode for IssueDetail {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            IssueDetail::FieldMissingError(ref field) => { 0_u8.encode(w)?; field.encode(w) }
+            IssueDetail::FieldMissingWarning(ref field) => { 1_u8.encode(w)?; field.encode(w) }
+            IssueDetail::FieldParseFailedError(ref field) => { 2_u8.encode(w)?; field.encode(w) }
+            IssueDetail::TrackTitleContainsFeat => 3_u8.encode(w),
+            IssueDetail::AlbumTitleMismatch(id, ref title, ref alt) => {
+                4_u8.encode(w)?;
+                id.encode(w)?;
+                title.encode(w)?;
+                alt.encode(w)
+            }
+            IssueDetail::AlbumReleaseDateMismatch(id, date, alt) => {
+                5_u8.encode(w)?;
+                id.encode(w)?;
+                date.encode(w)?;
+                alt.encode(w)
+            }
+            IssueDetail::AlbumArtistMismatch(id, ref artist, ref alt) => {
+                6_u8.encode(w)?;
+                id.encode(w)?;
+                artist.encode(w)?;
+                alt.encode(w)
+            }
+            IssueDetail::AlbumLoudnessMismatch(id, loudness, alt) => {
+                7_u8.encode(w)?;
+                id.encode(w)?;
+                loudness.encode(w)?;
+                alt.encode(w)
+            }
+            IssueDetail::ArtistNameMismatch(id, ref name, ref alt) => {
+                8_u8.encode(w)?;
+                id.encode(w)?;
+                name.encode(w)?;
+                alt.encode(w)
+            }
+            IssueDetail::ArtistSortNameMismatch(id, ref name, ref alt) => {
+                9_u8.encode(w)?;
+                id.encode(w)?;
+                name.encode(w)?;
+                alt.encode(w)
+            }
+            IssueDetail::NotStereo => 10_u8.encode(w),
+            IssueDetail::UnsupportedBitDepth(bits) => { 11_u8.encode(w)?; bits.encode(w) }
+            IssueDetail::FallbackIdUsed(ref field) => { 12_u8.encode(w)?; field.encode(w) }
+            IssueDetail::DuplicateTrack(id, ref kept) => {
+                13_u8.encode(w)?;
+                id.encode(w)?;
+                kept.encode(w)
+            }
+            IssueDetail::ReadFailed(ref err) => { 14_u8.encode(w)?; err.encode(w) }
+            IssueDetail::FieldParseFailedWarning(ref field) => { 15_u8.encode(w)?; field.encode(w) }
+        }
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<IssueDetail> {
+        let result = match u8::decode(r)? {
+            0 => IssueDetail::FieldMissingError(String::decode(r)?),
+            1 => IssueDetail::FieldMissingWarning(String::decode(r)?),
+            2 => IssueDetail::FieldParseFailedError(String::decode(r)?),
+            3 => IssueDetail::TrackTitleContainsFeat,
+            4 => IssueDetail::AlbumTitleMismatch(AlbumId::decode(r)?, String::decode(r)?, String::decode(r)?),
+            5 => IssueDetail::AlbumReleaseDateMismatch(AlbumId::decode(r)?, Date::decode(r)?, Date::decode(r)?),
+            6 => IssueDetail::AlbumArtistMismatch(AlbumId::decode(r)?, String::decode(r)?, String::decode(r)?),
+            7 => IssueDetail::AlbumLoudnessMismatch(AlbumId::decode(r)?, Option::decode(r)?, Option::decode(r)?),
+            8 => IssueDetail::ArtistNameMismatch(ArtistId::decode(r)?, String::decode(r)?, String::decode(r)?),
+            9 => IssueDetail::ArtistSortNameMismatch(ArtistId::decode(r)?, String::decode(r)?, String::decode(r)?),
+            10 => IssueDetail::NotStereo,
+            11 => IssueDetail::UnsupportedBitDepth(u32::decode(r)?),
+            12 => IssueDetail::FallbackIdUsed(String::decode(r)?),
+            13 => IssueDetail::DuplicateTrack(TrackId::decode(r)?, String::decode(r)?),
+            14 => IssueDetail::ReadFailed(String::decode(r)?),
+            15 => IssueDetail::FieldParseFailedWarning(String::decode(r)?),
+            _ => return Err(Error::Corrupt("Unknown issue kind.")),
+        };
+        Ok(result)
+    }
+}
+
+impl Encode for Issue {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.filename.encode(w)?;
+        self.detail.encode(w)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<Issue> {
+        let result = Issue {
+            filename: String::decode(r)?,
+            detail: IssueDetail::decode(r)?,
+        };
+        Ok(result)
+    }
+}
+
+/// Indices into the section table.
+mod section {
+    pub const FILES: usize = 0;
+    pub const CANARY_ARTIST: usize = 1;
+    pub const CANARY_ALBUM: usize = 2;
+    pub const CANARY_TRACK: usize = 3;
+    pub const CANARY_ALBUM_BY_ARTIST: usize = 4;
+    pub const CANARY_KEY: usize = 5;
+    pub const CANARY_VALUES: usize = 6;
+    pub const ARTISTS: usize = 7;
+    pub const ALBUMS: usize = 8;
+    pub const TRACKS: usize = 9;
+    pub const ALBUMS_BY_ARTIST: usize = 10;
+    pub const ARTIST_BOOKMARKS: usize = 11;
+    pub const ALBUM_BOOKMARKS: usize = 12;
+    pub const TRACK_BOOKMARKS: usize = 13;
+    pub const ALBUMS_BY_ARTIST_BOOKMARKS: usize = 14;
+    pub const STRING_OFFSETS: usize = 15;
+    pub const STRING_DATA: usize = 16;
+    pub const FILENAME_OFFSETS: usize = 17;
+    pub const FILENAME_DATA: usize = 18;
+    /// Start of the word indexes, see `WORD_INDEX_*` for the sections within.
+    pub const WORDS_ARTIST: usize = 19;
+    pub const WORDS_ALBUM: usize = 24;
+    pub const WORDS_TRACK: usize = 29;
+    pub const ISSUES: usize = 34;
+    pub const CANARY_ALBUM_ARTIST: usize = 35;
+    pub const ALBUM_ARTISTS: usize = 36;
+    pub const ALBUM_ARTISTS_BOOKMARKS: usize = 37;
+    pub const CANARY_APPEARANCE: usize = 38;
+    pub const APPEARANCES: usize = 39;
+    pub const APPEARANCES_BOOKMARKS: usize = 40;
+    pub const CANARY_TRACK_MBIDS: usize = 41;
+    pub const CANARY_ALBUM_MBIDS: usize = 42;
+    pub const TRACK_MBIDS: usize = 43;
+    pub const ALBUM_MBIDS: usize = 44;
+    pub const TRACK_MBIDS_BOOKMARKS: usize = 45;
+    pub const ALBUM_MBIDS_BOOKMARKS: usize = 46;
+    pub const CANARY_ALBUM_DETAILS: usize = 47;
+    pub const CANARY_ALBUM_GENRE: usize = 48;
+    pub const CANARY_TRACK_COMPOSER: usize = 49;
+    pub const ALBUM_DETAILS: usize = 50;
+    pub const ALBUM_GENRES: usize = 51;
+    pub const TRACK_COMPOSERS: usize = 52;
+    pub const ALBUM_DETAILS_BOOKMARKS: usize = 53;
+    pub const ALBUM_GENRES_BOOKMARKS: usize = 54;
+    pub const TRACK_COMPOSERS_BOOKMARKS: usize = 55;
+    pub const CANARY_COMPOSER: usize = 56;
+    pub const CANARY_WORK: usize = 57;
+    pub const CANARY_TRACK_WORK: usize = 58;
+    pub const CANARY_TRACK_BY_COMPOSER: usize = 59;
+    pub const COMPOSERS: usize = 60;
+    pub const WORKS: usize = 61;
+    pub const TRACK_WORKS: usize = 62;
+    pub const TRACKS_BY_COMPOSER: usize = 63;
+    pub const COMPOSERS_BOOKMARKS: usize = 64;
+    pub const WORKS_BOOKMARKS: usize = 65;
+    pub const TRACK_WORKS_BOOKMARKS: usize = 66;
+    pub const TRACKS_BY_COMPOSER_BOOKMARKS: usize = 67;
+    pub const WORDS_COMPOSER: usize = 68;
+    pub const CANARY_DISC_SUBTITLE: usize = 73;
+    pub const DISC_SUBTITLES: usize = 74;
+    pub const DISC_SUBTITLES_BOOKMARKS: usize = 75;
+    pub const COUNT: usize = 76;
+
+    pub const WORD_INDEX_KEY_SLICES: usize = 0;
+    pub const WORD_INDEX_VALUE_SLICES: usize = 1;
+    pub const WORD_INDEX_KEY_DATA: usize = 2;
+    pub const WORD_INDEX_VALUE_DATA: usize = 3;
+    pub const WORD_INDEX_META_DATA: usize = 4;
+}
+
+/// Size of the header: magic, version, flags, section count, and section table.
+const HEADER_LEN: usize = 8 + 4 + 4 + 4 + section::COUNT * 16;
+
+/// Types that can be stored in a section as-is, and read back in place.
+///
+/// Implementing this is only safe for types that contain no pointers, for
+/// which every bit pattern is a valid value, and that have no padding bytes.
+/// The structs are `repr(C)` with explicit padding fields, and they contain
+/// no enums or niches, see also the `flat_types_have_no_padding` test.
+///
+/// Pairs of flat types are not flat themselves, because there can be padding
+/// between the id and the value. Sections of pairs are written with
+/// `pairs_as_bytes`, and read with `SectionReader::pairs`.
+unsafe trait Flat: Sized + 'static {}
+
+unsafe impl Flat for u32 {}
+unsafe impl Flat for [u32; 257] {}
+unsafe impl Flat for TrackId {}
+unsafe impl Flat for AlbumId {}
+unsafe impl Flat for ArtistId {}
+unsafe impl Flat for ComposerId {}
+unsafe impl Flat for WorkId {}
+unsafe impl Flat for StringRef {}
+unsafe impl Flat for Artist {}
+unsafe impl Flat for Album {}
+unsafe impl Flat for Track {}
+unsafe impl Flat for ArtistCredit {}
+unsafe impl Flat for TrackMbids {}
+unsafe impl Flat for AlbumMbids {}
+unsafe impl Flat for AlbumDetails {}
+unsafe impl Flat for Composer {}
+unsafe impl Flat for Work {}
+unsafe impl Flat for TrackWork {}
+unsafe impl Flat for Key {}
+unsafe impl Flat for Values {}
+unsafe impl Flat for WordMeta {}
+
+/// Return the in-memory representation of the elements.
+fn as_bytes<T: Flat>(xs: &[T]) -> &[u8] {
+    // Safe because flat types have no padding, so all bytes are initialized.
+    unsafe { slice::from_raw_parts(xs.as_ptr() as *const u8, xs.len() * mem::size_of::<T>()) }
+}
+
+/// Return the in-memory representation of the pairs, with zeros for padding.
+///
+/// We can't take the bytes of a pair as-is, because padding bytes are
+/// uninitialized. Instead we copy the fields one by one to where they are in
+/// the pair, so `SectionReader::pairs` can use the result in place.
+fn pairs_as_bytes<K: Flat, V: Flat>(xs: &[(K, V)]) -> Vec<u8> {
+    let size = mem::size_of::<(K, V)>();
+    let mut bytes = vec![0_u8; xs.len() * size];
+    for (pair, dst) in xs.iter().zip(bytes.chunks_mut(size)) {
+        let base = pair as *const (K, V) as usize;
+        let k_offset = &pair.0 as *const K as usize - base;
+        let v_offset = &pair.1 as *const V as usize - base;
+        let k_bytes = as_bytes(slice::from_ref(&pair.0));
+        let v_bytes = as_bytes(slice::from_ref(&pair.1));
+        dst[k_offset..k_offset + k_bytes.len()].copy_from_slice(k_bytes);
+        dst[v_offset..v_offset + v_bytes.len()].copy_from_slice(v_bytes);
+    }
+    bytes
+}
+
+fn canary_artist() -> (ArtistId, Artist) {
+    (ArtistId(0x0102_0304_0506_0708), Artist { name: StringRef(9), name_for_sort: StringRef(10) })
+}
+
+fn canary_album() -> (AlbumId, Album) {
+    let album = Album {
+        artist_id: ArtistId(0x1112_1314_1516_1718),
+        artist: StringRef(18),
+        title: StringRef(19),
+        original_release_date: Date::new(2021, 2, 3),
+        loudness: OptionLufs::new(Some(Lufs::new(-732))),
+        disc_total: 29,
+        _padding: 0,
+    };
+    (AlbumId(0x2122_2324_2526_2728), album)
+}
+
+fn canary_track() -> (TrackId, Track) {
+    let track = Track {
+        title: StringRef(39),
+        artist: StringRef(40),
+        filename: FilenameRef(41),
+        duration_seconds: 0x3132_3334,
+        disc_number: 43,
+        track_number: 0x3536,
+        track_total: 0x3738,
+        loudness: OptionLufs::new(Some(Lufs::new(-45))),
+        _padding: 0,
+    };
+    (TrackId(0x4142_4344_4546_4748), track)
+}
+
+fn canary_album_by_artist() -> (ArtistId, AlbumId) {
+    (ArtistId(0x5152_5354_5556_5758), AlbumId(0x6162_6364_6566_6768))
+}
+
+fn canary_album_artist() -> (AlbumId, ArtistCredit) {
+    (AlbumId(0x8182_8384_8586_8788), ArtistCredit { artist_id: ArtistId(0x9192_9394_9596_9798), join_phrase: StringRef(99), _padding: 0 })
+}
+
+fn canary_appearance() -> (ArtistId, TrackId) {
+    (ArtistId(0xa1a2_a3a4_a5a6_a7a8), TrackId(0xb1b2_b3b4_b5b6_b7b8))
+}
+
+fn canary_mbid(start: u8) -> Mbid {
+    let mut bytes = [0; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = start + i as u8;
+    }
+    Mbid(bytes)
+}
+
+fn canary_track_mbids() -> (TrackId, TrackMbids) {
+    let mbids = TrackMbids { recording: canary_mbid(0xc1), track: canary_mbid(0xd1) };
+    (TrackId(0xc1c2_c3c4_c5c6_c7c8), mbids)
+}
+
+fn canary_album_mbids() -> (AlbumId, AlbumMbids) {
+    let mbids = AlbumMbids { release: canary_mbid(0xe1), release_group: canary_mbid(0x01) };
+    (AlbumId(0xe1e2_e3e4_e5e6_e7e8), mbids)
+}
+
+fn canary_album_details() -> (AlbumId, AlbumDetails) {
+    let details = AlbumDetails {
+        label: StringRef(0xf1f2_f3f4),
+        catalogue_number: StringRef(0xf5f6_f7f8),
+        release_country: StringRef(0x1121_3141),
+        release_type: StringRef(0x5161_7181),
+        release_status: StringRef(0x91a1_b1c1),
+        release_kind: ReleaseKind::Compilation as u8,
+        _padding: [0; 3],
+    };
+    (AlbumId(0xf1f2_f3f4_f5f6_f7f8), details)
+}
+
+fn canary_album_genre() -> (AlbumId, StringRef) {
+    (AlbumId(0x1222_3242_5262_7282), StringRef(0x92a2_b2c2))
+}
+
+fn canary_track_composer() -> (TrackId, ComposerId) {
+    (TrackId(0x1323_3343_5363_7383), ComposerId(0x93a3_b3c3_d3e3_f303))
+}
+
+fn canary_composer() -> (ComposerId, Composer) {
+    (ComposerId(0x1424_3444_5464_7484), Composer { name: StringRef(0x94a4_b4c4) })
+}
+
+fn canary_work() -> (WorkId, Work) {
+    (WorkId(0x1525_3545_5565_7585), Work { title: StringRef(0x95a5_b5c5) })
+}
+
+fn canary_track_work() -> (TrackId, TrackWork) {
+    let track_work = TrackWork {
+        work_id: WorkId(0x1626_3646_5666_7686),
+        movement_name: StringRef(0x96a6_b6c6),
+        movement_number: 0xd6,
+        _padding: [0; 3],
+    };
+    (TrackId(0x1727_3747_5767_7787), track_work)
+}
+
+fn canary_track_by_composer() -> (ComposerId, TrackId) {
+    (ComposerId(0x1828_3848_5868_7888), TrackId(0x98a8_b8c8_d8e8_f808))
+}
+
+fn canary_disc_subtitle() -> (TrackId, StringRef) {
+    (TrackId(0x1929_3949_5969_7989), StringRef(0x99a9_b9c9))
+}
+
+fn canary_key() -> Key {
+    Key { offset: 71, len: 72 }
+}
+
+fn canary_values() -> Values {
+    Values { offset: 81, len: 82 }
+}
+
+/// Concatenate the strings, return the offsets (one more than there are strings) and data.
+fn build_arena(strings: &[String]) -> (Vec<u32>, String) {
+    let mut offsets = Vec::with_capacity(strings.len() + 1);
+    let mut data = String::new();
+    for s in strings {
+        offsets.push(data.len() as u32);
+        data.push_str(s);
+    }
+    assert!(data.len() < u32::MAX as usize, "String arena too large for u32 offsets.");
+    offsets.push(data.len() as u32);
+    (offsets, data)
+}
+
+/// Writes sections, and keeps track of their offsets.
+struct SectionWriter<W: Write> {
+    out: W,
+    pos: u64,
+    sections: [(u64, u64); section::COUNT],
+}
+
+impl<W: Write> SectionWriter<W> {
+    fn write(&mut self, index: usize, bytes: &[u8]) -> io::Result<()> {
+        let padding = ((8 - self.pos % 8) % 8) as usize;
+        self.out.write_all(&[0_u8; 8][..padding])?;
+        self.pos += padding as u64;
+
+        self.out.write_all(bytes)?;
+        self.sections[index] = (self.pos, bytes.len() as u64);
+        self.pos += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn write_word_index<T: Flat>(&mut self, start: usize, words: WordIndexRef<T>) -> io::Result<()> {
+        let (key_slices, value_slices, key_data, value_data, meta_data) = words.into_parts();
+        self.write(start + section::WORD_INDEX_KEY_SLICES, as_bytes(key_slices))?;
+        self.write(start + section::WORD_INDEX_VALUE_SLICES, as_bytes(value_slices))?;
+        self.write(start + section::WORD_INDEX_KEY_DATA, key_data.as_bytes())?;
+        self.write(start + section::WORD_INDEX_VALUE_DATA, as_bytes(value_data))?;
+        self.write(start + section::WORD_INDEX_META_DATA, as_bytes(meta_data))
+    }
+}
+
+/// Write the index, the files it was built from, and its issues, to the given path.
+///
+/// The file is written to a temporary file first, and then moved into place.
+/// This ensures that a crash halfway through does not leave a truncated index
+/// behind, and also that a memory-mapped previous version of the file remains
+/// valid: we never modify a file that may be mapped.
+///
+/// `fallback_ids` is the setting that the index was built with, it is stored
+/// so we can tell when the index needs to be rebuilt because it changed.
+pub fn write(
+    path: &Path,
+    files: &[ScannedFile],
+    issues: &[Issue],
+    index: &MemoryMetaIndex,
+    fallback_ids: bool,
+) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let f = fs::File::create(&tmp_path)?;
+    let mut w = SectionWriter {
+        out: io::BufWriter::new(f),
+        pos: HEADER_LEN as u64,
+        sections: [(0, 0); section::COUNT],
+    };
+
+    // Leave room for the header, we fill it in at the end, when we know the
+    // offsets of all sections.
+    w.out.write_all(&[0_u8; HEADER_LEN])?;
+
+    let mut files_bytes = Vec::new();
+    (files.len() as u64).encode(&mut files_bytes)?;
+    for file in files {
+        file.encode(&mut files_bytes)?;
+    }
+    w.write(section::FILES, &files_bytes)?;
+
+    w.write(section::CANARY_ARTIST, &pairs_as_bytes(&[canary_artist()]))?;
+    w.write(section::CANARY_ALBUM, &pairs_as_bytes(&[canary_album()]))?;
+    w.write(section::CANARY_TRACK, &pairs_as_bytes(&[canary_track()]))?;
+    w.write(section::CANARY_ALBUM_BY_ARTIST, &pairs_as_bytes(&[canary_album_by_artist()]))?;
+    w.write(section::CANARY_KEY, as_bytes(&[canary_key()]))?;
+    w.write(section::CANARY_VALUES, as_bytes(&[canary_values()]))?;
+    w.write(section::CANARY_ALBUM_ARTIST, &pairs_as_bytes(&[canary_album_artist()]))?;
+    w.write(section::CANARY_APPEARANCE, &pairs_as_bytes(&[canary_appearance()]))?;
+    w.write(section::CANARY_TRACK_MBIDS, &pairs_as_bytes(&[canary_track_mbids()]))?;
+    w.write(section::CANARY_ALBUM_MBIDS, &pairs_as_bytes(&[canary_album_mbids()]))?;
+    w.write(section::CANARY_ALBUM_DETAILS, &pairs_as_bytes(&[canary_album_details()]))?;
+    w.write(section::CANARY_ALBUM_GENRE, &pairs_as_bytes(&[canary_album_genre()]))?;
+    w.write(section::CANARY_TRACK_COMPOSER, &pairs_as_bytes(&[canary_track_composer()]))?;
+    w.write(section::CANARY_COMPOSER, &pairs_as_bytes(&[canary_composer()]))?;
+    w.write(section::CANARY_WORK, &pairs_as_bytes(&[canary_work()]))?;
+    w.write(section::CANARY_TRACK_WORK, &pairs_as_bytes(&[canary_track_work()]))?;
+    w.write(section::CANARY_TRACK_BY_COMPOSER, &pairs_as_bytes(&[canary_track_by_composer()]))?;
+    w.write(section::CANARY_DISC_SUBTITLE, &pairs_as_bytes(&[canary_disc_subtitle()]))?;
+
+    w.write(section::ARTISTS, &pairs_as_bytes(&index.artists))?;
+    w.write(section::ALBUMS, &pairs_as_bytes(&index.albums))?;
+    w.write(section::TRACKS, &pairs_as_bytes(&index.tracks))?;
+    w.write(section::ALBUMS_BY_ARTIST, &pairs_as_bytes(&index.albums_by_artist))?;
+    w.write(section::ALBUM_ARTISTS, &pairs_as_bytes(&index.album_artists))?;
+    w.write(section::APPEARANCES, &pairs_as_bytes(&index.appearances))?;
+    w.write(section::TRACK_MBIDS, &pairs_as_bytes(&index.track_mbids))?;
+    w.write(section::ALBUM_MBIDS, &pairs_as_bytes(&index.album_mbids))?;
+    w.write(section::ALBUM_DETAILS, &pairs_as_bytes(&index.album_details))?;
+    w.write(section::ALBUM_GENRES, &pairs_as_bytes(&index.album_genres))?;
+    w.write(section::TRACK_COMPOSERS, &pairs_as_bytes(&index.track_composers))?;
+    w.write(section::COMPOSERS, &pairs_as_bytes(&index.composers))?;
+    w.write(section::WORKS, &pairs_as_bytes(&index.works))?;
+    w.write(section::TRACK_WORKS, &pairs_as_bytes(&index.track_works))?;
+    w.write(section::TRACKS_BY_COMPOSER, &pairs_as_bytes(&index.tracks_by_composer))?;
+    w.write(section::DISC_SUBTITLES, &pairs_as_bytes(&index.disc_subtitles))?;
+
+    w.write(section::ARTIST_BOOKMARKS, as_bytes(slice::from_ref(&*index.artist_bookmarks.bookmarks)))?;
+    w.write(section::ALBUM_BOOKMARKS, as_bytes(slice::from_ref(&*index.album_bookmarks.bookmarks)))?;
+    w.write(section::TRACK_BOOKMARKS, as_bytes(slice::from_ref(&*index.track_bookmarks.bookmarks)))?;
+    w.write(section::ALBUMS_BY_ARTIST_BOOKMARKS, as_bytes(slice::from_ref(&*index.albums_by_artist_bookmarks.bookmarks)))?;
+    w.write(section::ALBUM_ARTISTS_BOOKMARKS, as_bytes(slice::from_ref(&*index.album_artists_bookmarks.bookmarks)))?;
+    w.write(section::APPEARANCES_BOOKMARKS, as_bytes(slice::from_ref(&*index.appearances_bookmarks.bookmarks)))?;
+    w.write(section::TRACK_MBIDS_BOOKMARKS, as_bytes(slice::from_ref(&*index.track_mbids_bookmarks.bookmarks)))?;
+    w.write(section::ALBUM_MBIDS_BOOKMARKS, as_bytes(slice::from_ref(&*index.album_mbids_bookmarks.bookmarks)))?;
+    w.write(section::ALBUM_DETAILS_BOOKMARKS, as_bytes(slice::from_ref(&*index.album_details_bookmarks.bookmarks)))?;
+    w.write(section::ALBUM_GENRES_BOOKMARKS, as_bytes(slice::from_ref(&*index.album_genres_bookmarks.bookmarks)))?;
+    w.write(section::TRACK_COMPOSERS_BOOKMARKS, as_bytes(slice::from_ref(&*index.track_composers_bookmarks.bookmarks)))?;
+    w.write(section::COMPOSERS_BOOKMARKS, as_bytes(slice::from_ref(&*index.composers_bookmarks.bookmarks)))?;
+    w.write(section::WORKS_BOOKMARKS, as_bytes(slice::from_ref(&*index.works_bookmarks.bookmarks)))?;
+    w.write(section::TRACK_WORKS_BOOKMARKS, as_bytes(slice::from_ref(&*index.track_works_bookmarks.bookmarks)))?;
+    w.write(section::TRACKS_BY_COMPOSER_BOOKMARKS, as_bytes(slice::from_ref(&*index.tracks_by_composer_bookmarks.bookmarks)))?;
+    w.write(section::DISC_SUBTITLES_BOOKMARKS, as_bytes(slice::from_ref(&*index.disc_subtitles_bookmarks.bookmarks)))?;
+
+    let (string_offsets, string_data) = build_arena(&index.strings);
+    w.write(section::STRING_OFFSETS, as_bytes(&string_offsets))?;
+    w.write(section::STRING_DATA, string_data.as_bytes())?;
+
+    let (filename_offsets, filename_data) = build_arena(&index.filenames);
+    w.write(section::FILENAME_OFFSETS, as_bytes(&filename_offsets))?;
+    w.write(section::FILENAME_DATA, filename_data.as_bytes())?;
+
+    w.write_word_index(section::WORDS_ARTIST, index.words_artist.as_index_ref())?;
+    w.write_word_index(section::WORDS_ALBUM, index.words_album.as_index_ref())?;
+    w.write_word_index(section::WORDS_TRACK, index.words_track.as_index_ref())?;
+    w.write_word_index(section::WORDS_COMPOSER, index.words_composer.as_index_ref())?;
+
+    let mut issues_bytes = Vec::new();
+    (issues.len() as u64).encode(&mut issues_bytes)?;
+    for issue in issues {
+        issue.encode(&mut issues_bytes)?;
+    }
+    w.write(section::ISSUES, &issues_bytes)?;
+
+    // Now that we know where the sections are, fill in the header.
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    FORMAT_VERSION.encode(&mut header)?;
+    let flags = if fallback_ids { FLAG_FALLBACK_IDS } else { 0 };
+    flags.encode(&mut header)?;
+    (section::COUNT as u32).encode(&mut header)?;
+    for &(offset, len) in w.sections.iter() {
+        offset.encode(&mut header)?;
+        len.encode(&mut header)?;
+    }
+    debug_assert_eq!(header.len(), HEADER_LEN);
+
+    w.out.flush()?;
+    let f = w.out.get_mut();
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(&header)?;
+    f.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+}
+
+/// A read-only memory mapping of a file.
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only, so sharing it between threads is fine.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    fn open(path: &Path) -> Result<Mmap> {
+        let f = fs::File::open(path)?;
+        let len = f.metadata()?.len() as usize;
+        if len < HEADER_LEN {
+            return Err(Error::NotAnIndex)
+        }
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                f.as_raw_fd(),
+                0,
+            )
+        };
+        match ptr {
+            Ok(ptr) => Ok(Mmap { ptr, len }),
+            Err(err) => Err(Error::IoError(io::Error::new(io::ErrorKind::Other, err))),
+        }
+    }
+
+    /// Return the mapped bytes.
+    ///
+    /// The lifetime is a lie: the bytes are only valid as long as the `Mmap`
+    /// lives. It is up to `MmapMetaIndex` to not let references escape.
+    fn bytes(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr, self.len).expect("Failed to unmap index.");
+        }
+    }
+}
+
+/// Reads sections from a mapped index file.
+struct SectionReader {
+    bytes: &'static [u8],
+    flags: u32,
+    sections: Vec<(u64, u64)>,
+}
+
+impl SectionReader {
+    fn new(bytes: &'static [u8]) -> Result<SectionReader> {
+        let mut r = io::Cursor::new(bytes);
+
+        let mut magic = [0_u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::NotAnIndex)
+        }
+
+        let version = u32::decode(&mut r)?;
+        if version != FORMAT_VERSION {
+            return Err(Error::VersionMismatch(version))
+        }
+
+        let flags = u32::decode(&mut r)?;
+        let count = u32::decode(&mut r)? as usize;
+        if count != section::COUNT {
+            return Err(Error::Corrupt("unexpected number of sections."))
+        }
+
+        let mut sections = Vec::with_capacity(count);
+        for _ in 0..count {
+            let offset = u64::decode(&mut r)?;
+            let len = u64::decode(&mut r)?;
+            if offset.checked_add(len).map_or(true, |end| end > bytes.len() as u64) {
+                return Err(Error::Corrupt("section extends beyond end of file."))
+            }
+            sections.push((offset, len));
+        }
+
+        Ok(SectionReader { bytes, flags, sections })
+    }
+
+    fn bytes(&self, index: usize) -> &'static [u8] {
+        let (offset, len) = self.sections[index];
+        &self.bytes[offset as usize..(offset + len) as usize]
+    }
+
+    fn str(&self, index: usize) -> Result<&'static str> {
+        str::from_utf8(self.bytes(index)).map_err(|_| Error::Corrupt("string data is not valid UTF-8."))
+    }
+
+    /// Interpret the section as a slice of `T`.
+    ///
+    /// Unsafe because every bit pattern must be a valid `T`.
+    unsafe fn cast<T>(&self, index: usize) -> Result<&'static [T]> {
+        let bytes = self.bytes(index);
+        if bytes.as_ptr() as usize % mem::align_of::<T>() != 0 {
+            return Err(Error::Corrupt("section is misaligned."))
+        }
+        if bytes.len() % mem::size_of::<T>() != 0 {
+            return Err(Error::Corrupt("section size is not a multiple of the element size."))
+        }
+        let len = bytes.len() / mem::size_of::<T>();
+        Ok(slice::from_raw_parts(bytes.as_ptr() as *const T, len))
+    }
+
+    fn slice<T: Flat>(&self, index: usize) -> Result<&'static [T]> {
+        unsafe { self.cast(index) }
+    }
+
+    /// Read a section written with `pairs_as_bytes`.
+    fn pairs<K: Flat, V: Flat>(&self, index: usize) -> Result<&'static [(K, V)]> {
+        // Every bit pattern is a valid `K` and `V`, and the padding bytes of
+        // the pair are never interpreted, so every bit pattern is a valid pair.
+        unsafe { self.cast(index) }
+    }
+
+    /// Read a section that holds a single value, and check that it equals `expected`.
+    fn canary<T: Flat + PartialEq>(&self, index: usize, expected: T) -> Result<()> {
+        match self.slice::<T>(index)? {
+            &[ref x] if *x == expected => Ok(()),
+            // If the layout is different, this file was written by a build
+            // that is incompatible with this one.
+            _ => Err(Error::IncompatibleLayout),
+        }
+    }
+
+    /// Like `canary`, for a section written with `pairs_as_bytes`.
+    fn canary_pair<K: Flat + PartialEq, V: Flat + PartialEq>(&self, index: usize, expected: (K, V)) -> Result<()> {
+        match self.pairs::<K, V>(index)? {
+            &[ref x] if *x == expected => Ok(()),
+            _ => Err(Error::IncompatibleLayout),
+        }
+    }
+
+    /// Read the bookmarks of the slice that has the given ids, see `Bookmarks`.
+    ///
+    /// Lookups trust the bookmarks to find the range to search in, so they
+    /// must be exactly the bookmarks that we would build for the ids.
+    fn bookmarks<I: Iterator<Item = u64>>(&self, index: usize, ids: I) -> Result<&'static [u32; 257]> {
+        let bookmarks = match self.slice::<[u32; 257]>(index)? {
+            &[ref b] => b,
+            _ => return Err(Error::Corrupt("bookmarks have the wrong size.")),
+        };
+        if bookmarks[..] != Bookmarks::new(ids).bookmarks[..] {
+            return Err(Error::Corrupt("bookmarks do not match the ids."))
+        }
+        Ok(bookmarks)
+    }
+
+    fn arena(&self, offsets_index: usize, data_index: usize) -> Result<(&'static [u32], &'static str)> {
+        let offsets = self.slice::<u32>(offsets_index)?;
+        let data = self.str(data_index)?;
+        let is_monotonic = offsets.windows(2).all(|w| w[0] <= w[1]);
+        let is_in_bounds = offsets.iter().all(|&i| data.is_char_boundary(i as usize));
+        if offsets.is_empty() || !is_monotonic || !is_in_bounds {
+            return Err(Error::Corrupt("string offsets out of bounds."))
+        }
+        Ok((offsets, data))
+    }
+
+    fn word_index<T: Flat>(&self, start: usize) -> Result<WordIndexRef<'static, T>> {
+        WordIndexRef::new(
+            self.slice(start + section::WORD_INDEX_KEY_SLICES)?,
+            self.slice(start + section::WORD_INDEX_VALUE_SLICES)?,
+            self.str(start + section::WORD_INDEX_KEY_DATA)?,
+            self.slice(start + section::WORD_INDEX_VALUE_DATA)?,
+            self.slice(start + section::WORD_INDEX_META_DATA)?,
+        ).ok_or(Error::Corrupt("word index slice out of bounds."))
+    }
+}
+
+/// Return whether the pairs are sorted by id, and if `unique`, without duplicates.
+fn is_sorted_by_id<K: Ord, V>(xs: &[(K, V)], unique: bool) -> bool {
+    xs.windows(2).all(|w| w[0].0 < w[1].0 || (!unique && w[0].0 == w[1].0))
+}
+
+/// Return string `i` from a string arena, or an empty string if out of bounds.
+fn get_arena_str<'a>(offsets: &[u32], data: &'a str, i: u32) -> &'a str {
+    match (offsets.get(i as usize), offsets.get(i as usize + 1)) {
+        (Some(&begin), Some(&end)) => &data[begin as usize..end as usize],
+        _ => "",
+    }
+}
+
+/// An index backed by a memory-mapped index file.
+///
+/// Lookups read directly from the mapping, nothing is decoded up front.
+pub struct MmapMetaIndex {
+    // These all point into `mmap`. The `'static` lifetime is a lie, they are
+    // only valid as long as `mmap` is. We never hand out references that
+    // outlive `&self`.
+    artists: &'static [(ArtistId, Artist)],
+    albums: &'static [(AlbumId, Album)],
+    tracks: &'static [(TrackId, Track)],
+    albums_by_artist: &'static [(ArtistId, AlbumId)],
+    album_artists: &'static [(AlbumId, ArtistCredit)],
+    appearances: &'static [(ArtistId, TrackId)],
+    track_mbids: &'static [(TrackId, TrackMbids)],
+    album_mbids: &'static [(AlbumId, AlbumMbids)],
+    album_details: &'static [(AlbumId, AlbumDetails)],
+    album_genres: &'static [(AlbumId, StringRef)],
+    track_composers: &'static [(TrackId, ComposerId)],
+    composers: &'static [(ComposerId, Composer)],
+    works: &'static [(WorkId, Work)],
+    track_works: &'static [(TrackId, TrackWork)],
+    tracks_by_composer: &'static [(ComposerId, TrackId)],
+    disc_subtitles: &'static [(TrackId, StringRef)],
+
+    artist_bookmarks: &'static [u32; 257],
+    album_bookmarks: &'static [u32; 257],
+    track_bookmarks: &'static [u32; 257],
+    albums_by_artist_bookmarks: &'static [u32; 257],
+    album_artists_bookmarks: &'static [u32; 257],
+    appearances_bookmarks: &'static [u32; 257],
+    track_mbids_bookmarks: &'static [u32; 257],
+    album_mbids_bookmarks: &'static [u32; 257],
+    album_details_bookmarks: &'static [u32; 257],
+    album_genres_bookmarks: &'static [u32; 257],
+    track_composers_bookmarks: &'static [u32; 257],
+    composers_bookmarks: &'static [u32; 257],
+    works_bookmarks: &'static [u32; 257],
+    track_works_bookmarks: &'static [u32; 257],
+    tracks_by_composer_bookmarks: &'static [u32; 257],
+    disc_subtitles_bookmarks: &'static [u32; 257],
+
+    string_offsets: &'static [u32],
+    string_data: &'static str,
+    filename_offsets: &'static [u32],
+    filename_data: &'static str,
+
+    words_artist: WordIndexRef<'static, ArtistId>,
+    words_album: WordIndexRef<'static, AlbumId>,
+    words_track: WordIndexRef<'static, TrackId>,
+    words_composer: WordIndexRef<'static, ComposerId>,
+
+    files: &'static [u8],
+    issues: &'static [u8],
+    fallback_ids: bool,
+
+    mmap: Mmap,
+}
+
+impl MmapMetaIndex {
+    /// Map an index file written by `write`.
+    ///
+    /// This validates that all sections and slices are in bounds, so lookups
+    /// can not go out of bounds later, but it does not decode anything.
+    pub fn open(path: &Path) -> Result<MmapMetaIndex> {
+        let mmap = Mmap::open(path)?;
+        let r = SectionReader::new(mmap.bytes())?;
+
+        r.canary_pair(section::CANARY_ARTIST, canary_artist())?;
+        r.canary_pair(section::CANARY_ALBUM, canary_album())?;
+        r.canary_pair(section::CANARY_TRACK, canary_track())?;
+        r.canary_pair(section::CANARY_ALBUM_BY_ARTIST, canary_album_by_artist())?;
+        r.canary(section::CANARY_KEY, canary_key())?;
+        r.canary(section::CANARY_VALUES, canary_values())?;
+        r.canary_pair(section::CANARY_ALBUM_ARTIST, canary_album_artist())?;
+        r.canary_pair(section::CANARY_APPEARANCE, canary_appearance())?;
+        r.canary_pair(section::CANARY_TRACK_MBIDS, canary_track_mbids())?;
+        r.canary_pair(section::CANARY_ALBUM_MBIDS, canary_album_mbids())?;
+        r.canary_pair(section::CANARY_ALBUM_DETAILS, canary_album_details())?;
+        r.canary_pair(section::CANARY_ALBUM_GENRE, canary_album_genre())?;
+        r.canary_pair(section::CANARY_TRACK_COMPOSER, canary_track_composer())?;
+        r.canary_pair(section::CANARY_COMPOSER, canary_composer())?;
+        r.canary_pair(section::CANARY_WORK, canary_work())?;
+        r.canary_pair(section::CANARY_TRACK_WORK, canary_track_work())?;
+        r.canary_pair(section::CANARY_TRACK_BY_COMPOSER, canary_track_by_composer())?;
+        r.canary_pair(section::CANARY_DISC_SUBTITLE, canary_disc_subtitle())?;
+
+        let artists: &[(ArtistId, Artist)] = r.pairs(section::ARTISTS)?;
+        let albums: &[(AlbumId, Album)] = r.pairs(section::ALBUMS)?;
+        let tracks: &[(TrackId, Track)] = r.pairs(section::TRACKS)?;
+        let albums_by_artist: &[(ArtistId, AlbumId)] = r.pairs(section::ALBUMS_BY_ARTIST)?;
+        let album_artists: &[(AlbumId, ArtistCredit)] = r.pairs(section::ALBUM_ARTISTS)?;
+        let appearances: &[(ArtistId, TrackId)] = r.pairs(section::APPEARANCES)?;
+        let track_mbids: &[(TrackId, TrackMbids)] = r.pairs(section::TRACK_MBIDS)?;
+        let album_mbids: &[(AlbumId, AlbumMbids)] = r.pairs(section::ALBUM_MBIDS)?;
+        let album_details: &[(AlbumId, AlbumDetails)] = r.pairs(section::ALBUM_DETAILS)?;
+        let album_genres: &[(AlbumId, StringRef)] = r.pairs(section::ALBUM_GENRES)?;
+        let track_composers: &[(TrackId, ComposerId)] = r.pairs(section::TRACK_COMPOSERS)?;
+        let composers: &[(ComposerId, Composer)] = r.pairs(section::COMPOSERS)?;
+        let works: &[(WorkId, Work)] = r.pairs(section::WORKS)?;
+        let track_works: &[(TrackId, TrackWork)] = r.pairs(section::TRACK_WORKS)?;
+        let tracks_by_composer: &[(ComposerId, TrackId)] = r.pairs(section::TRACKS_BY_COMPOSER)?;
+        let disc_subtitles: &[(TrackId, StringRef)] = r.pairs(section::DISC_SUBTITLES)?;
+        let (string_offsets, string_data) = r.arena(section::STRING_OFFSETS, section::STRING_DATA)?;
+        let (filename_offsets, filename_data) = r.arena(section::FILENAME_OFFSETS, section::FILENAME_DATA)?;
+
+        // The lookups binary search these, if they are not sorted, we would
+        // silently return wrong results, rather than rebuilding the index.
+        let is_sorted =
+            is_sorted_by_id(artists, true) &&
+            is_sorted_by_id(albums, true) &&
+            is_sorted_by_id(tracks, true) &&
+            is_sorted_by_id(albums_by_artist, false) &&
+            is_sorted_by_id(album_artists, false) &&
+            is_sorted_by_id(appearances, false) &&
+            is_sorted_by_id(track_mbids, true) &&
+            is_sorted_by_id(album_mbids, true) &&
+            is_sorted_by_id(album_details, true) &&
+            is_sorted_by_id(album_genres, false) &&
+            is_sorted_by_id(track_composers, false) &&
+            is_sorted_by_id(composers, true) &&
+            is_sorted_by_id(works, true) &&
+            is_sorted_by_id(track_works, true) &&
+            is_sorted_by_id(tracks_by_composer, false) &&
+            is_sorted_by_id(disc_subtitles, true);
+        if !is_sorted {
+            return Err(Error::Corrupt("ids are not sorted."))
+        }
+
+        let index = MmapMetaIndex {
+            artist_bookmarks: r.bookmarks(section::ARTIST_BOOKMARKS, artists.iter().map(|p| (p.0).0))?,
+            album_bookmarks: r.bookmarks(section::ALBUM_BOOKMARKS, albums.iter().map(|p| (p.0).0))?,
+            track_bookmarks: r.bookmarks(section::TRACK_BOOKMARKS, tracks.iter().map(|p| (p.0).0))?,
+            albums_by_artist_bookmarks: r.bookmarks(section::ALBUMS_BY_ARTIST_BOOKMARKS, albums_by_artist.iter().map(|p| (p.0).0))?,
+            album_artists_bookmarks: r.bookmarks(section::ALBUM_ARTISTS_BOOKMARKS, album_artists.iter().map(|p| (p.0).0))?,
+            appearances_bookmarks: r.bookmarks(section::APPEARANCES_BOOKMARKS, appearances.iter().map(|p| (p.0).0))?,
+            track_mbids_bookmarks: r.bookmarks(section::TRACK_MBIDS_BOOKMARKS, track_mbids.iter().map(|p| (p.0).0))?,
+            album_mbids_bookmarks: r.bookmarks(section::ALBUM_MBIDS_BOOKMARKS, album_mbids.iter().map(|p| (p.0).0))?,
+            album_details_bookmarks: r.bookmarks(section::ALBUM_DETAILS_BOOKMARKS, album_details.iter().map(|p| (p.0).0))?,
+            album_genres_bookmarks: r.bookmarks(section::ALBUM_GENRES_BOOKMARKS, album_genres.iter().map(|p| (p.0).0))?,
+            track_composers_bookmarks: r.bookmarks(section::TRACK_COMPOSERS_BOOKMARKS, track_composers.iter().map(|p| (p.0).0))?,
+            composers_bookmarks: r.bookmarks(section::COMPOSERS_BOOKMARKS, composers.iter().map(|p| (p.0).0))?,
+            works_bookmarks: r.bookmarks(section::WORKS_BOOKMARKS, works.iter().map(|p| (p.0).0))?,
+            track_works_bookmarks: r.bookmarks(section::TRACK_WORKS_BOOKMARKS, track_works.iter().map(|p| (p.0).0))?,
+            tracks_by_composer_bookmarks: r.bookmarks(section::TRACKS_BY_COMPOSER_BOOKMARKS, tracks_by_composer.iter().map(|p| (p.0).0))?,
+            disc_subtitles_bookmarks: r.bookmarks(section::DISC_SUBTITLES_BOOKMARKS, disc_subtitles.iter().map(|p| (p.0).0))?,
+            artists: artists,
+            albums: albums,
+            tracks: tracks,
+            albums_by_artist: albums_by_artist,
+            album_artists: album_artists,
+            appearances: appearances,
+            track_mbids: track_mbids,
+            album_mbids: album_mbids,
+            album_details: album_details,
+            album_genres: album_genres,
+            track_composers: track_composers,
+            composers: composers,
+            works: works,
+            track_works: track_works,
+            tracks_by_composer: tracks_by_composer,
+            disc_subtitles: disc_subtitles,
+            string_offsets: string_offsets,
+            string_data: string_data,
+            filename_offsets: filename_offsets,
+            filename_data: filename_data,
+            words_artist: r.word_index(section::WORDS_ARTIST)?,
+            words_album: r.word_index(section::WORDS_ALBUM)?,
+            words_track: r.word_index(section::WORDS_TRACK)?,
+            words_composer: r.word_index(section::WORDS_COMPOSER)?,
+            files: r.bytes(section::FILES),
+            issues: r.bytes(section::ISSUES),
+            fallback_ids: r.flags & FLAG_FALLBACK_IDS != 0,
+            mmap: mmap,
+        };
+
+        Ok(index)
+    }
+
+    /// Return the files that the index was built from, with their metadata and tags.
+    pub fn get_files(&self) -> Result<Vec<ScannedFile>> {
+        Vec::decode(&mut io::Cursor::new(self.files))
+    }
+
+    /// Return the issues that were found while building the index.
+    pub fn get_issues(&self) -> Result<Vec<Issue>> {
+        Vec::decode(&mut io::Cursor::new(self.issues))
+    }
+
+    /// Return whether the index was built with the `fallback_ids` setting enabled.
+    pub fn fallback_ids(&self) -> bool {
+        self.fallback_ids
+    }
+
+    /// Return the size of the mapped file in bytes.
+    pub fn size_bytes(&self) -> usize {
+        self.mmap.len
+    }
+
+    /// Return the sizes of the artist, album, and track word index.
+    pub fn word_index_sizes(&self) -> (WordIndexSize, WordIndexSize, WordIndexSize) {
+        (
+            self.words_artist.size(),
+            self.words_album.size(),
+            self.words_track.size(),
+        )
+    }
+}
+
+impl MetaIndex for MmapMetaIndex {
+    #[inline]
+    fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    #[inline]
+    fn get_string(&self, sr: StringRef) -> &str {
+        get_arena_str(self.string_offsets, self.string_data, sr.0)
+    }
+
+    #[inline]
+    fn get_filename(&self, sr: FilenameRef) -> &str {
+        get_arena_str(self.filename_offsets, self.filename_data, sr.0)
+    }
+
+    #[inline]
+    fn get_track(&self, id: TrackId) -> Option<&Track> {
+        find_by_id(self.track_bookmarks, self.tracks, id, id.0)
+    }
+
+    #[inline]
+    fn get_album(&self, id: AlbumId) -> Option<&Album> {
+        find_by_id(self.album_bookmarks, self.albums, id, id.0)
+    }
+
+    #[inline]
+    fn get_track_mbids(&self, id: TrackId) -> Option<&TrackMbids> {
+        find_by_id(self.track_mbids_bookmarks, self.track_mbids, id, id.0)
+    }
+
+    #[inline]
+    fn get_album_mbids(&self, id: AlbumId) -> Option<&AlbumMbids> {
+        find_by_id(self.album_mbids_bookmarks, self.album_mbids, id, id.0)
+    }
+
+    #[inline]
+    fn get_album_details(&self, id: AlbumId) -> Option<&AlbumDetails> {
+        find_by_id(self.album_details_bookmarks, self.album_details, id, id.0)
+    }
+
+    #[inline]
+    fn get_album_genres(&self, id: AlbumId) -> &[(AlbumId, StringRef)] {
+        find_all_by_id(self.album_genres_bookmarks, self.album_genres, id, id.0)
+    }
+
+    #[inline]
+    fn get_all_album_genres(&self) -> &[(AlbumId, StringRef)] {
+        self.album_genres
+    }
+
+    #[inline]
+    fn get_track_composers(&self, id: TrackId) -> &[(TrackId, ComposerId)] {
+        find_all_by_id(self.track_composers_bookmarks, self.track_composers, id, id.0)
+    }
+
+    #[inline]
+    fn get_composer(&self, id: ComposerId) -> Option<&Composer> {
+        find_by_id(self.composers_bookmarks, self.composers, id, id.0)
+    }
+
+    #[inline]
+    fn get_composers(&self) -> &[(ComposerId, Composer)] {
+        self.composers
+    }
+
+    #[inline]
+    fn get_work(&self, id: WorkId) -> Option<&Work> {
+        find_by_id(self.works_bookmarks, self.works, id, id.0)
+    }
+
+    #[inline]
+    fn get_track_work(&self, id: TrackId) -> Option<&TrackWork> {
+        find_by_id(self.track_works_bookmarks, self.track_works, id, id.0)
+    }
+
+    #[inline]
+    fn get_tracks_by_composer(&self, id: ComposerId) -> &[(ComposerId, TrackId)] {
+        find_all_by_id(self.tracks_by_composer_bookmarks, self.tracks_by_composer, id, id.0)
+    }
+
+    #[inline]
+    fn get_disc_subtitle(&self, id: AlbumId, disc_number: u8) -> Option<StringRef> {
+        let disc_id = get_track_id(id, disc_number, 0);
+        find_by_id(self.disc_subtitles_bookmarks, self.disc_subtitles, disc_id, disc_id.0).cloned()
+    }
+
+    #[inline]
+    fn get_album_tracks(&self, id: AlbumId) -> &[(TrackId, Track)] {
+        find_album_tracks(self.tracks, id)
+    }
+
+    #[inline]
+    fn get_tracks(&self) -> &[(TrackId, Track)] {
+        self.tracks
+    }
+
+    #[inline]
+    fn get_albums(&self) -> &[(AlbumId, Album)] {
+        self.albums
+    }
+
+    #[inline]
+    fn get_artists(&self) -> &[(ArtistId, Artist)] {
+        self.artists
+    }
+
+    #[inline]
+    fn get_artist(&self, id: ArtistId) -> Option<&Artist> {
+        find_by_id(self.artist_bookmarks, self.artists, id, id.0)
+    }
+
+    #[inline]
+    fn get_album_artists(&self, album_id: AlbumId) -> &[(AlbumId, ArtistCredit)] {
+        find_all_by_id(self.album_artists_bookmarks, self.album_artists, album_id, album_id.0)
+    }
+
+    #[inline]
+    fn get_albums_by_artist(&self, artist_id: ArtistId) -> &[(ArtistId, AlbumId)] {
+        find_all_by_id(self.albums_by_artist_bookmarks, self.albums_by_artist, artist_id, artist_id.0)
+    }
+
+    #[inline]
+    fn get_appearances(&self, artist_id: ArtistId) -> &[(ArtistId, TrackId)] {
+        find_all_by_id(self.appearances_bookmarks, self.appearances, artist_id, artist_id.0)
+    }
+
+    #[inline]
+    fn get_album_ids_ordered_by_artist(&self) -> &[(ArtistId, AlbumId)] {
+        self.albums_by_artist
+    }
+
+    fn search_artist(&self, words: &[String], into: &mut Vec<ArtistId>) {
+        search::search(&self.words_artist, words, into);
+    }
+
+    fn search_album(&self, words: &[String], into: &mut Vec<AlbumId>) {
+        search::search(&self.words_album, words, into);
+    }
+
+    fn search_track(&self, words: &[String], into: &mut Vec<TrackId>) {
+        search::search(&self.words_track, words, into);
+    }
+
+    fn search_composer(&self, words: &[String], into: &mut Vec<ComposerId>) {
+        search::search(&self.words_composer, words, into);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+    use std::process;
+
+    use crate::{MemoryMetaIndex, MetaIndex};
+    use crate::prim::{AlbumId, Album, AlbumDetails, ArtistCredit, ArtistId, Artist, ComposerId, Composer, Date, FilenameRef, Lufs, Mbid, OptionLufs, ReleaseKind, StringRef, Track, TrackMbids, TrackWork, WorkId, Work, get_track_id};
+    use crate::word_index::{MemoryWordIndex, WordMeta};
+    use crate::scan::{FileTags, Issue, IssueDetail};
+    use super::{Encode, FileMetadata, MmapMetaIndex, ScannedFile};
+
+    fn scanned_file(path: &str, size: u64) -> ScannedFile {
+        ScannedFile {
+            path: PathBuf::from(path),
+            metadata: FileMetadata { size: size, mtime_secs: 1_600_000_000, mtime_nanos: 7 },
+            tags: FileTags {
+                channels: 2,
+                bits_per_sample: 16,
+                sample_rate: 44_100,
+                samples: Some(size),
+                tags: vec![("TITLE".into(), "Fade Away".into()), ("ARTIST".into(), "Tycho".into())],
+            },
+        }
+    }
+
+    #[test]
+    fn flat_types_have_no_padding() {
+        use std::mem::size_of;
+        use crate::word_index::{Key, Values};
+        // When the size of a struct is the sum of the sizes of its fields,
+        // there is no room left for padding, which `Flat` relies on.
+        assert_eq!(size_of::<Track>(), 4 * 4 + 2 * 2 + size_of::<OptionLufs>() + 1 + 1);
+        assert_eq!(size_of::<Album>(), 8 + 4 + 4 + size_of::<Date>() + size_of::<OptionLufs>() + 1 + 1);
+        assert_eq!(size_of::<Date>(), 2 + 1 + 1);
+        assert_eq!(size_of::<Artist>(), 4 + 4);
+        assert_eq!(size_of::<ArtistCredit>(), 8 + 4 + 4);
+        assert_eq!(size_of::<TrackMbids>(), 2 * size_of::<Mbid>());
+        assert_eq!(size_of::<AlbumDetails>(), 5 * 4 + 1 + 3);
+        assert_eq!(size_of::<Composer>(), 4);
+        assert_eq!(size_of::<Work>(), 4);
+        assert_eq!(size_of::<TrackWork>(), 8 + 4 + 1 + 3);
+        assert_eq!(size_of::<Key>(), 4 + 4);
+        assert_eq!(size_of::<Values>(), 4 + 4);
+        assert_eq!(size_of::<WordMeta>(), 4);
+    }
+
+    #[test]
+    fn encode_roundtrips_scanned_files() {
+        let mut b = scanned_file("/music/b.flac", 0);
+        b.metadata.mtime_secs = -1;
+        b.tags.samples = None;
+        b.tags.tags.clear();
+        let files = vec![scanned_file("/music/a.flac", 42), b];
+
+        let mut buf = Vec::new();
+        files.encode(&mut buf).unwrap();
+        let decoded = Vec::<ScannedFile>::decode(&mut io::Cursor::new(&buf[..])).unwrap();
+        assert_eq!(decoded, files);
+
+        buf.pop();
+        assert!(Vec::<ScannedFile>::decode(&mut io::Cursor::new(&buf[..])).is_err());
+    }
+
+    #[test]
+    fn encode_roundtrips_issues() {
+        let album_id = AlbumId(0x2000_0000_0000_0000);
+        let details = vec![
+            IssueDetail::FieldMissingError("musicbrainz_albumid".into()),
+            IssueDetail::FieldMissingWarning("bs17704_track_loudness".into()),
+            IssueDetail::FieldParseFailedError("originaldate".into()),
+            IssueDetail::TrackTitleContainsFeat,
+            IssueDetail::AlbumTitleMismatch(album_id, "Dive".into(), "Dive (Remaster)".into()),
+            IssueDetail::AlbumReleaseDateMismatch(album_id, Date::new(2011, 11, 8), Date::new(2011, 0, 0)),
+            IssueDetail::AlbumArtistMismatch(album_id, "Tycho".into(), "Tycho & Benoît Pioulard".into()),
+            IssueDetail::AlbumLoudnessMismatch(album_id, Some(Lufs::new(-800)), None),
+            IssueDetail::ArtistNameMismatch(ArtistId(1), "Tycho".into(), "tycho".into()),
+            IssueDetail::ArtistSortNameMismatch(ArtistId(1), "Tycho".into(), "tycho".into()),
+            IssueDetail::NotStereo,
+            IssueDetail::UnsupportedBitDepth(8),
+            IssueDetail::FallbackIdUsed("musicbrainz_albumid".into()),
+            IssueDetail::DuplicateTrack(get_track_id(album_id, 1, 2), "/music/tycho/dive/02 (1).flac".into()),
+            IssueDetail::ReadFailed("failed to fill whole buffer".into()),
+            IssueDetail::FieldParseFailedWarning("musicbrainz_artistid".into()),
+        ];
+        for detail in &details {
+            assert!(IssueDetail::KINDS.contains(&detail.kind()), "Missing kind {}.", detail.kind());
+        }
+
+        let issues: Vec<Issue> = details
+            .into_iter()
+            .map(|detail| detail.for_file("/music/tycho/dive/02.flac".into()))
+            .collect();
+
+        let mut buf = Vec::new();
+        issues.encode(&mut buf).unwrap();
+        let decoded = Vec::<Issue>::decode(&mut io::Cursor::new(&buf[..])).unwrap();
+        assert_eq!(decoded, issues);
+    }
+
+    #[test]
+    fn mmap_index_roundtrips_memory_index() {
+        let artist_id = ArtistId(0x1000_0000_0000_0001);
+        let album_id = AlbumId(0x2000_0000_0000_0000);
+        let track_id = get_track_id(album_id, 1, 2);
+
+        let featured_artist_id = ArtistId(0x3000_0000_0000_0001);
+        let artist = Artist { name: StringRef(0), name_for_sort: StringRef(1) };
+        let album = Album {
+            artist_id: artist_id,
+            artist: StringRef(0),
+            title: StringRef(2),
+            original_release_date: Date::new(2012, 7, 0),
+            loudness: OptionLufs::new(Some(Lufs::new(-800))),
+            disc_total: 1,
+            _padding: 0,
+        };
+        let credit = ArtistCredit { artist_id: artist_id, join_phrase: StringRef(4), _padding: 0 };
+        let track_mbids = TrackMbids {
+            recording: Mbid::parse("1070cbb2-ad74-44ce-90a4-7fa1dfd8164e").unwrap(),
+            track: Mbid::NIL,
+        };
+        let track = Track {
+            title: StringRef(3),
+            artist: StringRef(0),
+            filename: FilenameRef(0),
+            duration_seconds: 255,
+            disc_number: 1,
+            track_number: 2,
+            track_total: 9,
+            loudness: OptionLufs::NONE,
+            _padding: 0,
+        };
+        let details = AlbumDetails {
+            label: StringRef(5),
+            catalogue_number: StringRef(6),
+            release_country: StringRef(4),
+            release_type: StringRef(4),
+            release_status: StringRef(4),
+            release_kind: ReleaseKind::Album as u8,
+            _padding: [0; 3],
+        };
+
+        let composer_id = ComposerId(0x3a47e52c5b1a2e4d);
+        let composer = Composer { name: StringRef(8) };
+        let work_id = WorkId(0x4c0a8f3b7e2d9a15);
+        let work = Work { title: StringRef(9) };
+        let track_work = TrackWork {
+            work_id: work_id,
+            movement_name: StringRef(10),
+            movement_number: 2,
+            _padding: [0; 3],
+        };
+
+        let mut words_track = BTreeSet::new();
+        words_track.insert(("fade".to_string(), track_id, WordMeta::new(4, 13, 0, 2)));
+        words_track.insert(("away".to_string(), track_id, WordMeta::new(4, 13, 1, 2)));
+        let mut words_composer = BTreeSet::new();
+        words_composer.insert(("hansen".to_string(), composer_id, WordMeta::new(6, 12, 1, 2)));
+
+        let index = MemoryMetaIndex::from_parts(
+            vec![(artist_id, artist.clone()), (featured_artist_id, artist.clone())],
+            vec![(album_id, album.clone())],
+            vec![(album_id, credit.clone())],
+            vec![(track_id, track.clone())],
+            // The album artist is credited on the track too, that is not an appearance.
+            vec![(track_id, artist_id), (track_id, featured_artist_id)],
+            vec![(track_id, track_mbids.clone())],
+            Vec::new(),
+            vec![(album_id, details.clone())],
+            vec![(album_id, StringRef(7))],
+            vec![(track_id, composer_id)],
+            vec![(composer_id, composer.clone())],
+            vec![(work_id, work.clone())],
+            vec![(track_id, track_work.clone())],
+            vec![(get_track_id(album_id, 1, 0), StringRef(2))],
+            vec![
+                "Tycho".into(), "tycho".into(), "Dive".into(), "Fade Away".into(), "".into(),
+                "Ghostly International".into(), "GHOST123".into(), "Electronic".into(), "Scott Hansen".into(),
+                "Dive Suite".into(), "Fade Away".into(),
+            ],
+            vec!["/music/tycho/dive/02.flac".into()],
+            MemoryWordIndex::new(&BTreeSet::new()),
+            MemoryWordIndex::new(&BTreeSet::new()),
+            MemoryWordIndex::new(&words_track),
+            MemoryWordIndex::new(&words_composer),
+        );
+
+        let path = env::temp_dir().join(format!("musium-test-{}.index", process::id()));
+        let files = vec![scanned_file("/music/tycho/dive/02.flac", 1234)];
+        let issues = vec![IssueDetail::TrackTitleContainsFeat.for_file("/music/tycho/dive/02.flac".into())];
+        super::write(&path, &files, &issues, &index, true).unwrap();
+        let mmap_index = MmapMetaIndex::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mmap_index.fallback_ids(), true);
+        assert_eq!(mmap_index.get_files().unwrap(), files);
+        assert_eq!(mmap_index.get_issues().unwrap(), issues);
+        assert_eq!(mmap_index.get_artist(artist_id), Some(&artist));
+        assert_eq!(mmap_index.get_album(album_id), Some(&album));
+        assert_eq!(mmap_index.get_track(track_id), Some(&track));
+        assert_eq!(mmap_index.get_track_mbids(track_id), Some(&track_mbids));
+        assert_eq!(mmap_index.get_album_mbids(album_id), None);
+        assert_eq!(mmap_index.get_album_details(album_id), Some(&details));
+        assert_eq!(mmap_index.get_album_genres(album_id), &[(album_id, StringRef(7))]);
+        assert_eq!(mmap_index.get_track_composers(track_id), &[(track_id, composer_id)]);
+        assert_eq!(mmap_index.get_composer(composer_id), Some(&composer));
+        assert_eq!(mmap_index.get_work(work_id), Some(&work));
+        assert_eq!(mmap_index.get_track_work(track_id), Some(&track_work));
+        assert_eq!(mmap_index.get_tracks_by_composer(composer_id), &[(composer_id, track_id)]);
+        assert_eq!(mmap_index.get_disc_subtitle(album_id, 1), Some(StringRef(2)));
+        assert_eq!(mmap_index.get_disc_subtitle(album_id, 2), None);
+        assert_eq!(mmap_index.get_string(StringRef(5)), "Ghostly International");
+        assert_eq!(mmap_index.get_album_tracks(album_id), &[(track_id, track)]);
+        assert_eq!(mmap_index.get_album_artists(album_id), &[(album_id, credit)]);
+        assert_eq!(mmap_index.get_albums_by_artist(artist_id), &[(artist_id, album_id)]);
+        assert_eq!(mmap_index.get_appearances(artist_id), &[]);
+        assert_eq!(mmap_index.get_appearances(featured_artist_id), &[(featured_artist_id, track_id)]);
+        assert_eq!(mmap_index.get_string(StringRef(3)), "Fade Away");
+        assert_eq!(mmap_index.get_string(StringRef(4)), "");
+        assert_eq!(mmap_index.get_filename(FilenameRef(0)), "/music/tycho/dive/02.flac");
+
+        let mut results = Vec::new();
+        mmap_index.search_track(&["fad".to_string()], &mut results);
+        assert_eq!(results, vec![track_id]);
+
+        let mut results = Vec::new();
+        mmap_index.search_composer(&["hans".to_string()], &mut results);
+        assert_eq!(results, vec![composer_id]);
+    }
+
+    #[test]
+    fn mmap_index_rejects_unsorted_ids() {
+        let album_id = AlbumId(0x2000_0000_0000_0000);
+        let track = Track {
+            title: StringRef(0),
+            artist: StringRef(0),
+            filename: FilenameRef(0),
+            duration_seconds: 1,
+            disc_number: 1,
+            track_number: 1,
+            track_total: 0,
+            loudness: OptionLufs::NONE,
+            _padding: 0,
+        };
+        let index = MemoryMetaIndex::from_parts(
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            vec![(get_track_id(album_id, 1, 2), track.clone()), (get_track_id(album_id, 1, 1), track)],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            vec!["".into()],
+            vec!["".into()],
+            MemoryWordIndex::new(&BTreeSet::new()),
+            MemoryWordIndex::new(&BTreeSet::new()),
+            MemoryWordIndex::new(&BTreeSet::new()),
+            MemoryWordIndex::new(&BTreeSet::new()),
+        );
+
+        let path = env::temp_dir().join(format!("musium-test-unsorted-{}.index", process::id()));
+        super::write(&path, &[], &[], &index, false).unwrap();
+        let result = MmapMetaIndex::open(&path);
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(super::Error::Corrupt(..)) => {}
+            _ => panic!("Expected the unsorted index to be rejected as corrupt."),
+        }
+    }
+}
@@ -18,32 +18,44 @@ mod scan;
 mod search;
 mod word_index;
 
+pub mod browse;
 pub mod config;
+pub mod cover;
 pub mod error;
 pub mod history;
+pub mod index_file;
 pub mod net;
 pub mod playback;
 pub mod player;
 pub mod prim;
 pub mod serialization;
 pub mod string_utils;
+pub mod swap;
+pub mod systemd;
 pub mod thumb_cache;
+pub mod watcher;
 
 use std::collections::btree_map;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::io::Write;
 use std::io;
 use std::mem;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::sync_channel;
 use std::u32;
 use std::u64;
 
-use crate::prim::{ArtistId, Artist, AlbumId, Album, TrackId, Track, Lufs, StringRef, FilenameRef, get_track_id};
+pub use crate::index_file::MmapMetaIndex;
+pub use crate::scan::{Issue, IssueDetail, ScanStage, ScanStatus};
+
+use crate::prim::{ArtistId, Artist, ArtistCredit, AlbumId, Album, AlbumDetails, AlbumMbids, ComposerId, Composer, TrackId, Track, TrackMbids, TrackWork, WorkId, Work, Lufs, StringRef, FilenameRef, get_track_id};
 use crate::word_index::{MemoryWordIndex};
 use crate::string_utils::StringDeduper;
-use crate::scan::{BuildMetaIndex, Issue, Progress, artists_different, albums_different};
+use crate::index_file::{FileMetadata, ScannedFile};
+use crate::scan::{BuildMetaIndex, FileTags, Progress, album_artists_different, artists_different, albums_different, prefer_track_file};
 
 pub trait MetaIndex {
     /// Return the number of tracks in the index.
@@ -66,6 +78,50 @@ pub trait MetaIndex {
     /// Return album metadata.
     fn get_album(&self, id: AlbumId) -> Option<&Album>;
 
+    /// Return the full MusicBrainz ids of the track, if it has any.
+    fn get_track_mbids(&self, id: TrackId) -> Option<&TrackMbids>;
+
+    /// Return the full MusicBrainz ids of the album, if it has any.
+    fn get_album_mbids(&self, id: AlbumId) -> Option<&AlbumMbids>;
+
+    /// Return the label, catalogue number, etc. of the album, if it has any.
+    fn get_album_details(&self, id: AlbumId) -> Option<&AlbumDetails>;
+
+    /// Return the genres of the album.
+    ///
+    /// Includes the album too, because the genres are stored as a flat array
+    /// of (album id, genre) pairs.
+    fn get_album_genres(&self, id: AlbumId) -> &[(AlbumId, StringRef)];
+
+    /// Return all (album id, genre) pairs, ordered by album id.
+    fn get_all_album_genres(&self) -> &[(AlbumId, StringRef)];
+
+    /// Return the composers of the track, in the order they were tagged.
+    ///
+    /// Includes the track too, like `get_album_genres`.
+    fn get_track_composers(&self, id: TrackId) -> &[(TrackId, ComposerId)];
+
+    /// Look up a composer by id.
+    fn get_composer(&self, id: ComposerId) -> Option<&Composer>;
+
+    /// Return all composers, ordered by id.
+    fn get_composers(&self) -> &[(ComposerId, Composer)];
+
+    /// Look up a work by id.
+    fn get_work(&self, id: WorkId) -> Option<&Work>;
+
+    /// Return the work that the track is a part of, if it is tagged with one.
+    fn get_track_work(&self, id: TrackId) -> Option<&TrackWork>;
+
+    /// Return the subtitle of a disc of the album, if it has one.
+    fn get_disc_subtitle(&self, id: AlbumId, disc_number: u8) -> Option<StringRef>;
+
+    /// Return all tracks by the composer, across albums.
+    ///
+    /// The tracks are sorted by ascending release date of the album, and then
+    /// by track id. Includes the composer too, like `get_appearances`.
+    fn get_tracks_by_composer(&self, id: ComposerId) -> &[(ComposerId, TrackId)];
+
     /// Return all tracks that are part of the album.
     fn get_album_tracks(&self, id: AlbumId) -> &[(TrackId, Track)];
 
@@ -81,18 +137,33 @@ pub trait MetaIndex {
     /// Look up an artist by id.
     fn get_artist(&self, _: ArtistId) -> Option<&Artist>;
 
+    /// Return the artists credited for the album, in order.
+    ///
+    /// Includes the album too, because the credits are stored as a flat array
+    /// of (album id, credit) pairs.
+    fn get_album_artists(&self, _: AlbumId) -> &[(AlbumId, ArtistCredit)];
+
     /// Return all albums by the given artist.
     ///
-    /// The albums are sorted by ascending release date.
+    /// The albums are sorted by ascending release date. This includes albums
+    /// where the artist is not the first credited artist.
     ///
     /// Includes the artist too, because the associations are stored as a flat
     /// array of (artist id, album id) pairs.
     fn get_albums_by_artist(&self, _: ArtistId) -> &[(ArtistId, AlbumId)];
 
+    /// Return the tracks that the artist appears on, on albums by other artists.
+    ///
+    /// These are e.g. feat. credits and tracks on compilations. The tracks are
+    /// sorted by ascending release date of the album, and then by track id.
+    /// Includes the artist too, like `get_albums_by_artist`.
+    fn get_appearances(&self, _: ArtistId) -> &[(ArtistId, TrackId)];
+
     /// Return all (artist id, album id) pairs.
     ///
     /// The resulting index is sorted by artist id first, and then by ascending
-    /// release date of the album.
+    /// release date of the album. Albums with multiple artists occur once for
+    /// every artist.
     fn get_album_ids_ordered_by_artist(&self) -> &[(ArtistId, AlbumId)];
 
     /// Search for artists where the word occurs in the name.
@@ -108,6 +179,9 @@ pub trait MetaIndex {
     /// tracks by an artist, only those for which `search_album` would not
     /// already find the entire album.
     fn search_track(&self, words: &[String], into: &mut Vec<TrackId>);
+
+    /// Search for composers where the word occurs in the name.
+    fn search_composer(&self, words: &[String], into: &mut Vec<ComposerId>);
 }
 
 #[derive(Debug)]
@@ -134,6 +208,15 @@ impl From<claxon::Error> for Error {
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IoError(ref err) => write!(f, "{}", err),
+            Error::FormatError(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
 /// Indices into a sorted array based on the most significant byte of an id.
@@ -179,56 +262,186 @@ impl Bookmarks {
             bookmarks: Box::new(bookmarks)
         }
     }
+}
 
-    /// Return the subslice of `xs` that contains the given id.
-    pub fn range<'a, T>(&self, xs: &'a [T], id: u64) -> &'a [T] {
-        let b = (id >> 56) as usize;
-        let begin = self.bookmarks[b] as usize;
-        let end = self.bookmarks[b + 1] as usize;
-        &xs[begin..end]
-    }
+/// Return the subslice of `xs` that contains the given id, see `Bookmarks`.
+fn bookmark_range<'a, T>(bookmarks: &[u32; 257], xs: &'a [T], id: u64) -> &'a [T] {
+    let b = (id >> 56) as usize;
+    let begin = bookmarks[b] as usize;
+    let end = bookmarks[b + 1] as usize;
+    &xs[begin..end]
+}
+
+/// Look up the value for `id` in `xs`, which must be sorted on id.
+///
+/// The ids `I` are newtypes around `u64`, `raw_id` is the `u64` of `id`.
+fn find_by_id<'a, I: Copy + Ord, T>(
+    bookmarks: &[u32; 257],
+    xs: &'a [(I, T)],
+    id: I,
+    raw_id: u64,
+) -> Option<&'a T> {
+    let slice = bookmark_range(bookmarks, xs, raw_id);
+    slice
+        .binary_search_by_key(&id, |pair| pair.0)
+        .ok()
+        // TODO: Remove bounds check.
+        .map(|idx| &slice[idx].1)
 }
 
+/// Return all tracks that are part of the album, `tracks` must be sorted on id.
+fn find_album_tracks(tracks: &[(TrackId, Track)], id: AlbumId) -> &[(TrackId, Track)] {
+    // Look for track 0 of disc 0. This is the first track of the album,
+    // if it exists. Otherwise binary search would find the first track
+    // after it.
+    let tid = get_track_id(id, 0, 0);
+    // TODO: Use bookmarks for this.
+    let begin = match tracks.binary_search_by_key(&tid, |pair| pair.0) {
+        Ok(i) => i,
+        Err(i) => i,
+    };
+    // Then do a linear scan over the tracks to find the first track that
+    // does not belong to the album any more. We could do another binary
+    // search to find the end instead, but a binary search would take about
+    // 13 random memory accesses for 12k tracks, whereas most albums have
+    // less tracks than that, and the linear scan has a very regular memory
+    // access pattern.
+    let end = begin + tracks[begin..]
+        .iter()
+        .position(|&(tid, _)| tid.album_id() != id)
+        .unwrap_or(tracks.len() - begin);
+
+    &tracks[begin..end]
+}
+
+/// Return all elements with the given id in `xs`, which must be sorted on id.
+///
+/// This is used for `MetaIndex::get_albums_by_artist` and
+/// `MetaIndex::get_album_artists`. The ids `I` are newtypes around `u64`,
+/// `raw_id` is the `u64` of `id`.
+fn find_all_by_id<'a, I: Copy + Eq, T>(
+    bookmarks: &[u32; 257],
+    xs: &'a [(I, T)],
+    id: I,
+    raw_id: u64,
+) -> &'a [(I, T)] {
+    // Use the bookmarks to narrow down the range of ids that we need to
+    // look though.
+    let mut candidates = bookmark_range(bookmarks, xs, raw_id);
+
+    // Within that slice, we do a linear search for the start of the id.
+    // For a library with ~400 artists like mine, there will only be one or
+    // two artists in the slice anyway, and most artists have few (no more
+    // than a dozen) albums. We could use a binary search for better
+    // complexity, but the one in `slice` is not suitable for this (it does
+    // not return the *first* index with the key, only *a* index), so I'll
+    // go with the easy thing for now.
+    let begin = candidates
+        .iter()
+        .position(|&(ref elem_id, _)| *elem_id == id)
+        .unwrap_or(candidates.len());
+    candidates = &candidates[begin..];
+
+    // Then do a linear scan to find the first element that does not have
+    // the id any more. We could do another binary search to locate the end,
+    // but typically there are few elements per id, so we go with a
+    // predictible memory access pattern here.
+    let end = candidates
+        .iter()
+        .position(|&(ref elem_id, _)| *elem_id != id)
+        .unwrap_or(candidates.len());
+
+    &candidates[..end]
+}
+
+/// An index that owns its data, built by scanning the library.
+///
+/// See `index_file::MmapMetaIndex` for the variant that serves from a file.
 pub struct MemoryMetaIndex {
-    // TODO: Use an mmappable data structure. For now this will suffice.
     artists: Vec<(ArtistId, Artist)>,
     albums: Vec<(AlbumId, Album)>,
     tracks: Vec<(TrackId, Track)>,
+    // Per album, the credited artists, in credit order.
+    album_artists: Vec<(AlbumId, ArtistCredit)>,
     // Per artist, all albums, ordered by ascending release date.
     albums_by_artist: Vec<(ArtistId, AlbumId)>,
+    // Per artist, tracks on albums by other artists, see `get_appearances`.
+    appearances: Vec<(ArtistId, TrackId)>,
+    // Full MusicBrainz ids, only for tracks and albums that have them.
+    track_mbids: Vec<(TrackId, TrackMbids)>,
+    album_mbids: Vec<(AlbumId, AlbumMbids)>,
+    // Release details, only for albums that have them.
+    album_details: Vec<(AlbumId, AlbumDetails)>,
+    album_genres: Vec<(AlbumId, StringRef)>,
+    track_composers: Vec<(TrackId, ComposerId)>,
+    composers: Vec<(ComposerId, Composer)>,
+    works: Vec<(WorkId, Work)>,
+    // Only for tracks that are part of a work.
+    track_works: Vec<(TrackId, TrackWork)>,
+    // Per composer, all tracks, see `get_tracks_by_composer`.
+    tracks_by_composer: Vec<(ComposerId, TrackId)>,
+    // Keyed by the id of track 0 on the disc, only for discs that have one.
+    disc_subtitles: Vec<(TrackId, StringRef)>,
 
     // Bookmarks for quick indexing into the above arrays.
     artist_bookmarks: Bookmarks,
     album_bookmarks: Bookmarks,
     track_bookmarks: Bookmarks,
+    album_artists_bookmarks: Bookmarks,
     albums_by_artist_bookmarks: Bookmarks,
+    appearances_bookmarks: Bookmarks,
+    track_mbids_bookmarks: Bookmarks,
+    album_mbids_bookmarks: Bookmarks,
+    album_details_bookmarks: Bookmarks,
+    album_genres_bookmarks: Bookmarks,
+    track_composers_bookmarks: Bookmarks,
+    composers_bookmarks: Bookmarks,
+    works_bookmarks: Bookmarks,
+    track_works_bookmarks: Bookmarks,
+    tracks_by_composer_bookmarks: Bookmarks,
+    disc_subtitles_bookmarks: Bookmarks,
 
     strings: Vec<String>,
     filenames: Vec<String>,
 
-    // TODO: Don't make these pub, this is just for debug printing stats.
-    pub words_artist: MemoryWordIndex<ArtistId>,
-    pub words_album: MemoryWordIndex<AlbumId>,
-    pub words_track: MemoryWordIndex<TrackId>,
+    words_artist: MemoryWordIndex<ArtistId>,
+    words_album: MemoryWordIndex<AlbumId>,
+    words_track: MemoryWordIndex<TrackId>,
+    words_composer: MemoryWordIndex<ComposerId>,
 }
 
 /// Build the sorted mapping of artist id to album id.
 ///
 /// Entries are sorted by artist id first, so we can use bookmarks and do a
 /// binary search. Albums for a single artist are ordered by ascending release
-/// date.
-fn build_albums_by_artist_index(albums: &[(AlbumId, Album)]) -> Vec<(ArtistId, AlbumId)> {
-    let mut entries_with_date = Vec::with_capacity(albums.len());
-    let mut entries = Vec::with_capacity(albums.len());
-
-    for &(album_id, ref album) in albums {
-        entries_with_date.push((album.artist_id, album_id, album.original_release_date));
+/// date. An album is listed under every artist in its artist credit.
+fn build_albums_by_artist_index(
+    albums: &[(AlbumId, Album)],
+    album_artists: &[(AlbumId, ArtistCredit)],
+) -> Vec<(ArtistId, AlbumId)> {
+    let mut entries_with_date = Vec::with_capacity(album_artists.len());
+    let mut entries = Vec::with_capacity(album_artists.len());
+
+    // Both arrays are sorted by album id, so we can walk them in lockstep.
+    let mut albums_iter = albums.iter().peekable();
+    for &(album_id, ref credit) in album_artists {
+        while albums_iter.peek().map_or(false, |&&(id, _)| id < album_id) {
+            albums_iter.next();
+        }
+        let release_date = match albums_iter.peek() {
+            Some(&&(id, ref album)) if id == album_id => album.original_release_date,
+            _ => panic!("Album artist credit refers to a nonexistent album."),
+        };
+        entries_with_date.push((credit.artist_id, album_id, release_date));
     }
 
     entries_with_date.sort_by_key(|&(artist_id, album_id, release_date)|
         (artist_id, release_date, album_id)
     );
 
+    // An artist could be credited twice on the same album, list it only once.
+    entries_with_date.dedup();
+
     for (artist_id, album_id, _release_date) in entries_with_date {
         entries.push((artist_id, album_id));
     }
@@ -236,6 +449,84 @@ fn build_albums_by_artist_index(albums: &[(AlbumId, Album)]) -> Vec<(ArtistId, A
     entries
 }
 
+/// Build a list of (artist id, track id) pairs for tracks on albums by other artists.
+///
+/// Entries are sorted by artist id first, then by ascending release date of
+/// the album, and then by track id. Track artists that are also credited for
+/// the album are not included, those tracks are on the artist's own albums.
+fn build_appearances_index(
+    albums: &[(AlbumId, Album)],
+    album_artists: &[(AlbumId, ArtistCredit)],
+    tracks: &[(TrackId, Track)],
+    track_artists: &[(TrackId, ArtistId)],
+) -> Vec<(ArtistId, TrackId)> {
+    let credited: HashSet<(AlbumId, ArtistId)> = album_artists
+        .iter()
+        .map(|&(album_id, ref credit)| (album_id, credit.artist_id))
+        .collect();
+
+    let mut entries_with_date = Vec::new();
+    for &(track_id, artist_id) in track_artists {
+        debug_assert!(
+            tracks.binary_search_by_key(&track_id, |&(id, _)| id).is_ok(),
+            "Track artist credit refers to a nonexistent track."
+        );
+        let album_id = track_id.album_id();
+        if credited.contains(&(album_id, artist_id)) {
+            continue
+        }
+        let release_date = match albums.binary_search_by_key(&album_id, |&(id, _)| id) {
+            Ok(i) => albums[i].1.original_release_date,
+            Err(_) => panic!("Track refers to a nonexistent album."),
+        };
+        entries_with_date.push((artist_id, release_date, track_id));
+    }
+
+    entries_with_date.sort();
+
+    // An artist could be credited twice on the same track, list it only once.
+    entries_with_date.dedup();
+
+    entries_with_date
+        .into_iter()
+        .map(|(artist_id, _release_date, track_id)| (artist_id, track_id))
+        .collect()
+}
+
+/// Build a list of (composer id, track id) pairs.
+///
+/// Entries are sorted by composer id first, then by ascending release date of
+/// the album, and then by track id, like for `build_appearances_index`.
+fn build_tracks_by_composer_index(
+    albums: &[(AlbumId, Album)],
+    tracks: &[(TrackId, Track)],
+    track_composers: &[(TrackId, ComposerId)],
+) -> Vec<(ComposerId, TrackId)> {
+    let mut entries_with_date = Vec::with_capacity(track_composers.len());
+    for &(track_id, composer_id) in track_composers {
+        debug_assert!(
+            tracks.binary_search_by_key(&track_id, |&(id, _)| id).is_ok(),
+            "Track composer refers to a nonexistent track."
+        );
+        let album_id = track_id.album_id();
+        let release_date = match albums.binary_search_by_key(&album_id, |&(id, _)| id) {
+            Ok(i) => albums[i].1.original_release_date,
+            Err(_) => panic!("Track refers to a nonexistent album."),
+        };
+        entries_with_date.push((composer_id, release_date, track_id));
+    }
+
+    entries_with_date.sort();
+
+    // A composer could be tagged twice on the same track, list it only once.
+    entries_with_date.dedup();
+
+    entries_with_date
+        .into_iter()
+        .map(|(composer_id, _release_date, track_id)| (composer_id, track_id))
+        .collect()
+}
+
 /// Invokes `process` for all elements in the builder, in sorted order.
 ///
 /// The arguments passed to process are `(i, id, value)`, where `i` is the
@@ -290,14 +581,43 @@ impl MemoryMetaIndex {
         assert!(builders.len() > 0);
         let mut artists: Vec<(ArtistId, Artist)> = Vec::new();
         let mut albums: Vec<(AlbumId, Album)> = Vec::new();
+        let mut album_artists: Vec<(AlbumId, ArtistCredit)> = Vec::new();
         let mut tracks: Vec<(TrackId, Track)> = Vec::new();
         let mut strings = StringDeduper::new();
-        let mut filenames = Vec::new();
+        let mut filenames: Vec<String> = Vec::new();
         let mut words_artist = BTreeSet::new();
         let mut words_album = BTreeSet::new();
         let mut words_track = BTreeSet::new();
+        let mut words_composer = BTreeSet::new();
 
+        // The same track can occur in multiple builders. Like within a builder,
+        // we keep one of the files and report the other one. For those tracks,
+        // we remember which builder we kept, to take the other data from there.
+        let mut track_sources: HashMap<TrackId, usize> = HashMap::new();
+        let mut prev_source = 0;
         for_each_sorted(builders, |b| b.tracks.iter(), |i, id, mut track| {
+            let filename = &builders[i].filenames[track.filename.0 as usize];
+
+            if let Some(&(prev_id, ref prev)) = tracks.last() {
+                if prev_id == id {
+                    let prev_filename = &filenames[prev.filename.0 as usize];
+                    let prev_bits = builders[prev_source].track_bits_per_sample[&id];
+                    let bits = builders[i].track_bits_per_sample[&id];
+                    if prefer_track_file(prev_bits, prev_filename, bits, filename) {
+                        let detail = IssueDetail::DuplicateTrack(id, prev_filename.clone());
+                        issues.push(detail.for_file(filename.clone()));
+                        track_sources.insert(id, prev_source);
+                        return // Like `continue`, returns from the closure.
+                    }
+                    let detail = IssueDetail::DuplicateTrack(id, filename.clone());
+                    issues.push(detail.for_file(prev_filename.clone()));
+                    track_sources.insert(id, i);
+                    // The filename of a track is always the last one we pushed.
+                    tracks.pop();
+                    filenames.pop();
+                }
+            }
+
             // Give the track the final stringrefs, into the merged arrays.
             track.title = StringRef(
                 strings.insert(builders[i].strings.get(track.title.0))
@@ -305,20 +625,20 @@ impl MemoryMetaIndex {
             track.artist = StringRef(
                 strings.insert(builders[i].strings.get(track.artist.0))
             );
-            filenames.push(builders[i].filenames[track.filename.0 as usize].clone());
+            filenames.push(filename.clone());
             track.filename = FilenameRef(filenames.len() as u32 - 1);
 
-            if let Some(&(prev_id, ref _prev)) = tracks.last() {
-                assert!(prev_id != id, "Duplicate track should not occur.");
-            }
-
             tracks.push((id, track));
+            prev_source = i;
         });
 
         for_each_sorted(builders, |b| b.albums.iter(), |i, id, mut album| {
             album.title = StringRef(
                 strings.insert(builders[i].strings.get(album.title.0))
             );
+            album.artist = StringRef(
+                strings.insert(builders[i].strings.get(album.artist.0))
+            );
 
             if let Some(&(prev_id, ref prev)) = albums.last() {
                 if prev_id == id {
@@ -336,6 +656,39 @@ impl MemoryMetaIndex {
             albums.push((id, album));
         });
 
+        // Credits of the same album are adjacent, because we go in order of
+        // album id. For albums that occur in multiple builders, we keep the
+        // credits from the first one, like for the album itself above.
+        let mut prev_credits: Option<(AlbumId, Vec<ArtistCredit>)> = None;
+        for_each_sorted(builders, |b| b.album_artists.iter(), |i, id, mut credits| {
+            for credit in credits.iter_mut() {
+                credit.join_phrase = StringRef(
+                    strings.insert(builders[i].strings.get(credit.join_phrase.0))
+                );
+            }
+
+            if let Some((prev_id, ref prev)) = prev_credits {
+                if prev_id == id {
+                    // The merged album holds the credit that we kept.
+                    let kept = match albums.binary_search_by_key(&id, |&(album_id, _)| album_id) {
+                        Ok(k) => strings.get(albums[k].1.artist.0),
+                        Err(_) => unreachable!("Credits should belong to an album."),
+                    };
+                    let alternative = builders[i].strings.get(builders[i].albums[&id].artist.0);
+                    if let Some(detail) = album_artists_different(id, kept, prev, alternative, &credits) {
+                        let fname_index = builders[i].album_sources[&id];
+                        let filename = builders[i].filenames[fname_index.0 as usize].clone();
+                        let issue = detail.for_file(filename);
+                        issues.push(issue);
+                    }
+                    return // Like `continue`, returns from the closure.
+                }
+            }
+
+            album_artists.extend(credits.iter().map(|credit| (id, credit.clone())));
+            prev_credits = Some((id, credits));
+        });
+
         for_each_sorted(builders, |b| b.artists.iter(), |i, id, mut artist| {
             artist.name = StringRef(
                 strings.insert(builders[i].strings.get(artist.name.0))
@@ -360,52 +713,273 @@ impl MemoryMetaIndex {
             artists.push((id, artist));
         });
 
+        // Artists that we have only seen as track artist get an entry too, but
+        // if any builder has them as album artist, that entry takes precedence.
+        // There is no point in reporting inconsistencies here: track credits
+        // often use a different name than the artist's own name.
+        let mut track_only_artists = BTreeMap::new();
         for builder in builders {
+            for (&id, artist) in builder.track_only_artists.iter() {
+                if artists.binary_search_by_key(&id, |&(artist_id, _)| artist_id).is_ok() {
+                    continue
+                }
+                track_only_artists.entry(id).or_insert_with(|| Artist {
+                    name: StringRef(strings.insert(builder.strings.get(artist.name.0))),
+                    name_for_sort: StringRef(strings.insert(builder.strings.get(artist.name_for_sort.0))),
+                });
+            }
+        }
+        artists.extend(track_only_artists);
+        artists.sort_by_key(|&(id, _)| id);
+
+        // For duplicate tracks, take the per-track data only from the builder
+        // whose file we kept.
+        let is_kept = |i: usize, id: &TrackId| track_sources.get(id).map_or(true, |&k| k == i);
+
+        let mut track_artists = Vec::new();
+        for (i, builder) in builders.iter().enumerate() {
+            for (&track_id, artist_ids) in builder.track_artists.iter() {
+                if !is_kept(i, &track_id) { continue }
+                track_artists.extend(artist_ids.iter().map(|&artist_id| (track_id, artist_id)));
+            }
+        }
+        track_artists.sort();
+
+        // Like for the albums themselves, if an album occurs in multiple
+        // builders, the first one wins.
+        let mut track_mbids = BTreeMap::new();
+        let mut album_mbids = BTreeMap::new();
+        for (i, builder) in builders.iter().enumerate() {
+            for (&id, mbids) in builder.track_mbids.iter() {
+                if !is_kept(i, &id) { continue }
+                track_mbids.entry(id).or_insert_with(|| mbids.clone());
+            }
+            for (&id, mbids) in builder.album_mbids.iter() {
+                album_mbids.entry(id).or_insert_with(|| mbids.clone());
+            }
+        }
+
+        // Album details follow the album, so the first builder wins. Genres
+        // are the union over all builders, like they are within a builder.
+        let mut album_details = BTreeMap::new();
+        let mut album_genres: BTreeMap<AlbumId, Vec<StringRef>> = BTreeMap::new();
+        let mut track_composers = Vec::new();
+        for (i, builder) in builders.iter().enumerate() {
+            for (&id, details) in builder.album_details.iter() {
+                if album_details.contains_key(&id) { continue }
+                let details = AlbumDetails {
+                    label: StringRef(strings.insert(builder.strings.get(details.label.0))),
+                    catalogue_number: StringRef(strings.insert(builder.strings.get(details.catalogue_number.0))),
+                    release_country: StringRef(strings.insert(builder.strings.get(details.release_country.0))),
+                    release_type: StringRef(strings.insert(builder.strings.get(details.release_type.0))),
+                    release_status: StringRef(strings.insert(builder.strings.get(details.release_status.0))),
+                    release_kind: details.release_kind,
+                    _padding: [0; 3],
+                };
+                album_details.insert(id, details);
+            }
+            for (&id, genres) in builder.album_genres.iter() {
+                let merged = album_genres.entry(id).or_insert_with(Vec::new);
+                for genre in genres {
+                    let genre_ref = StringRef(strings.insert(builder.strings.get(genre.0)));
+                    if !merged.contains(&genre_ref) {
+                        merged.push(genre_ref);
+                    }
+                }
+            }
+            for (&id, composer_ids) in builder.track_composers.iter() {
+                if !is_kept(i, &id) { continue }
+                track_composers.extend(composer_ids.iter().map(|&composer_id| (id, composer_id)));
+            }
+        }
+        // The sort is stable, so composers of a track stay in tag order.
+        track_composers.sort_by_key(|&(id, _)| id);
+
+        // Composers and works are identified by name when untagged, so
+        // builders agree on them; the first one wins. Like other per-track
+        // data, the track's work comes from the builder whose file we kept.
+        let mut composers = BTreeMap::new();
+        let mut works = BTreeMap::new();
+        let mut track_works = Vec::new();
+        for (i, builder) in builders.iter().enumerate() {
+            for (&id, composer) in builder.composers.iter() {
+                if composers.contains_key(&id) { continue }
+                let name = StringRef(strings.insert(builder.strings.get(composer.name.0)));
+                composers.insert(id, Composer { name: name });
+            }
+            for (&id, work) in builder.works.iter() {
+                if works.contains_key(&id) { continue }
+                let title = StringRef(strings.insert(builder.strings.get(work.title.0)));
+                works.insert(id, Work { title: title });
+            }
+            for (&id, track_work) in builder.track_works.iter() {
+                if !is_kept(i, &id) { continue }
+                let track_work = TrackWork {
+                    work_id: track_work.work_id,
+                    movement_name: StringRef(strings.insert(builder.strings.get(track_work.movement_name.0))),
+                    movement_number: track_work.movement_number,
+                    _padding: [0; 3],
+                };
+                track_works.push((id, track_work));
+            }
+        }
+        track_works.sort_by_key(|&(id, _)| id);
+
+        // Like album details, disc subtitles follow the album.
+        let mut disc_subtitles = BTreeMap::new();
+        for builder in builders {
+            for (&id, subtitle) in builder.disc_subtitles.iter() {
+                if disc_subtitles.contains_key(&id) { continue }
+                disc_subtitles.insert(id, StringRef(strings.insert(builder.strings.get(subtitle.0))));
+            }
+        }
+        let album_genres = album_genres
+            .into_iter()
+            .flat_map(|(id, genres)| genres.into_iter().map(move |genre| (id, genre)))
+            .collect();
+
+        for (i, builder) in builders.iter().enumerate() {
             words_artist.extend(builder.words_artist.iter().cloned());
             words_album.extend(builder.words_album.iter().cloned());
-            words_track.extend(builder.words_track.iter().cloned());
+            words_track.extend(
+                builder.words_track.iter().filter(|w| is_kept(i, &w.1)).cloned()
+            );
+            words_composer.extend(builder.words_composer.iter().cloned());
         }
 
         strings.upgrade_quotes();
 
-        // Albums know their artist; build the reverse mapping so we can look up
-        // albums by a given artist. We could build it incrementally and merge
-        // it, but instead of doing that and having to worry about duplicates,
-        // we can just build it once at the end.
-        let albums_by_artist = build_albums_by_artist_index(&albums[..]);
+        MemoryMetaIndex::from_parts(
+            artists,
+            albums,
+            album_artists,
+            tracks,
+            track_artists,
+            track_mbids.into_iter().collect(),
+            album_mbids.into_iter().collect(),
+            album_details.into_iter().collect(),
+            album_genres,
+            track_composers,
+            composers.into_iter().collect(),
+            works.into_iter().collect(),
+            track_works,
+            disc_subtitles.into_iter().collect(),
+            strings.into_vec(),
+            filenames,
+            MemoryWordIndex::new(&words_artist),
+            MemoryWordIndex::new(&words_album),
+            MemoryWordIndex::new(&words_track),
+            MemoryWordIndex::new(&words_composer),
+        )
+    }
+
+    /// Assemble an index from sorted arrays, and build the derived lookups.
+    ///
+    /// This is used both after merging builders, and when loading an index
+    /// from disk.
+    fn from_parts(
+        artists: Vec<(ArtistId, Artist)>,
+        albums: Vec<(AlbumId, Album)>,
+        album_artists: Vec<(AlbumId, ArtistCredit)>,
+        tracks: Vec<(TrackId, Track)>,
+        track_artists: Vec<(TrackId, ArtistId)>,
+        track_mbids: Vec<(TrackId, TrackMbids)>,
+        album_mbids: Vec<(AlbumId, AlbumMbids)>,
+        album_details: Vec<(AlbumId, AlbumDetails)>,
+        album_genres: Vec<(AlbumId, StringRef)>,
+        track_composers: Vec<(TrackId, ComposerId)>,
+        composers: Vec<(ComposerId, Composer)>,
+        works: Vec<(WorkId, Work)>,
+        track_works: Vec<(TrackId, TrackWork)>,
+        disc_subtitles: Vec<(TrackId, StringRef)>,
+        strings: Vec<String>,
+        filenames: Vec<String>,
+        words_artist: MemoryWordIndex<ArtistId>,
+        words_album: MemoryWordIndex<AlbumId>,
+        words_track: MemoryWordIndex<TrackId>,
+        words_composer: MemoryWordIndex<ComposerId>,
+    ) -> MemoryMetaIndex {
+        // Albums know their artists; build the reverse mapping so we can look
+        // up albums by a given artist. We could build it incrementally and
+        // merge it, or store it, but instead of doing that and having to worry
+        // about duplicates, we can just build it once at the end.
+        let albums_by_artist = build_albums_by_artist_index(&albums[..], &album_artists[..]);
+        let appearances = build_appearances_index(&albums[..], &album_artists[..], &tracks[..], &track_artists[..]);
+        let tracks_by_composer = build_tracks_by_composer_index(&albums[..], &tracks[..], &track_composers[..]);
 
         MemoryMetaIndex {
             artist_bookmarks: Bookmarks::new(artists.iter().map(|p| (p.0).0)),
             album_bookmarks: Bookmarks::new(albums.iter().map(|p| (p.0).0)),
             track_bookmarks: Bookmarks::new(tracks.iter().map(|p| (p.0).0)),
+            album_artists_bookmarks: Bookmarks::new(album_artists.iter().map(|p| (p.0).0)),
             albums_by_artist_bookmarks: Bookmarks::new(albums_by_artist.iter().map(|p| (p.0).0)),
+            appearances_bookmarks: Bookmarks::new(appearances.iter().map(|p| (p.0).0)),
+            track_mbids_bookmarks: Bookmarks::new(track_mbids.iter().map(|p| (p.0).0)),
+            album_mbids_bookmarks: Bookmarks::new(album_mbids.iter().map(|p| (p.0).0)),
+            album_details_bookmarks: Bookmarks::new(album_details.iter().map(|p| (p.0).0)),
+            album_genres_bookmarks: Bookmarks::new(album_genres.iter().map(|p| (p.0).0)),
+            track_composers_bookmarks: Bookmarks::new(track_composers.iter().map(|p| (p.0).0)),
+            composers_bookmarks: Bookmarks::new(composers.iter().map(|p| (p.0).0)),
+            works_bookmarks: Bookmarks::new(works.iter().map(|p| (p.0).0)),
+            track_works_bookmarks: Bookmarks::new(track_works.iter().map(|p| (p.0).0)),
+            tracks_by_composer_bookmarks: Bookmarks::new(tracks_by_composer.iter().map(|p| (p.0).0)),
+            disc_subtitles_bookmarks: Bookmarks::new(disc_subtitles.iter().map(|p| (p.0).0)),
             artists: artists,
             albums: albums,
             tracks: tracks,
+            album_artists: album_artists,
             albums_by_artist: albums_by_artist,
-            strings: strings.into_vec(),
+            appearances: appearances,
+            track_mbids: track_mbids,
+            album_mbids: album_mbids,
+            album_details: album_details,
+            album_genres: album_genres,
+            track_composers: track_composers,
+            composers: composers,
+            works: works,
+            track_works: track_works,
+            tracks_by_composer: tracks_by_composer,
+            disc_subtitles: disc_subtitles,
+            strings: strings,
             filenames: filenames,
-            words_artist: MemoryWordIndex::new(&words_artist),
-            words_album: MemoryWordIndex::new(&words_album),
-            words_track: MemoryWordIndex::new(&words_track),
+            words_artist: words_artist,
+            words_album: words_album,
+            words_track: words_track,
+            words_composer: words_composer,
         }
     }
 
-    fn process(paths: &[PathBuf], counter: &AtomicUsize, builder: &mut BuildMetaIndex) {
+    /// Insert files into the builder, until all files have been claimed.
+    ///
+    /// Files with `Some` tags were scanned before and are unchanged, we insert
+    /// those tags. Files with `None` are new or changed, we read their tags,
+    /// and return them together with the index of the file.
+    fn process(
+        files: &[(&Path, Option<&FileTags>)],
+        counter: &AtomicUsize,
+        builder: &mut BuildMetaIndex,
+    ) -> Vec<(usize, FileTags)> {
         let mut progress_unreported = 0;
+        let mut read_tags = Vec::new();
         loop {
             let i = counter.fetch_add(1, Ordering::SeqCst);
-            if i >= paths.len() {
+            if i >= files.len() {
                 break;
             }
-            let path = &paths[i];
-            let opts = claxon::FlacReaderOptions {
-                metadata_only: true,
-                read_picture: claxon::ReadPicture::Skip,
-                read_vorbis_comment: true,
-            };
-            let reader = claxon::FlacReader::open_ext(path, opts).unwrap();
-            builder.insert(path.to_str().expect("TODO"), &reader.streaminfo(), &mut reader.tags());
+            let (path, tags) = files[i];
+            let filename = path.to_str().expect("TODO");
+            match tags {
+                Some(tags) => builder.insert(filename, tags),
+                None => match FileTags::read(path) {
+                    Ok(tags) => {
+                        builder.insert(filename, &tags);
+                        read_tags.push((i, tags));
+                    }
+                    // The file may have been deleted, or still be in the
+                    // middle of being copied. Report it, and skip it.
+                    Err(err) => builder.error_read_failed(filename.to_string(), err),
+                }
+            }
             progress_unreported += 1;
 
             // Don't report every track individually, to avoid synchronisation
@@ -421,13 +995,65 @@ impl MemoryMetaIndex {
         }
 
         builder.progress = None;
+        read_tags
     }
 
-    /// Index the given files.
+    /// Index the given files, reusing the tags of files that did not change.
     ///
-    /// Reports progress to `out`, which can be `std::io::stdout().lock()`.
-    pub fn from_paths<W>(paths: &[PathBuf], mut out: W) -> Result<MemoryMetaIndex>
+    /// `files` and `previous` must be sorted by path. Only files that are not
+    /// in `previous`, or whose metadata differs, are read. Returns the index,
+    /// the scanned files to pass as `previous` next time, and the issues found.
+    ///
+    /// When `fallback_ids` is set, files without MusicBrainz ids are indexed
+    /// under ids derived from their tags, rather than rejected.
+    ///
+    /// Reports progress to `out`, which can be `std::io::stdout().lock()`, and
+    /// also updates `status`, so it can be observed from other threads.
+    pub fn from_files<W>(
+        files: &[(PathBuf, FileMetadata)],
+        previous: Vec<ScannedFile>,
+        fallback_ids: bool,
+        status: &Mutex<ScanStatus>,
+        mut out: W,
+    ) -> Result<(MemoryMetaIndex, Vec<ScannedFile>, Vec<Issue>)>
     where W: Write {
+        // Walk the current and previous files in lockstep, to find which ones
+        // we need to read.
+        let mut added = 0;
+        let mut changed = 0;
+        let mut removed = 0;
+        let mut known_tags: Vec<Option<FileTags>> = Vec::with_capacity(files.len());
+        let mut previous = previous.into_iter().peekable();
+        for &(ref path, ref metadata) in files {
+            // Skip over files that no longer exist.
+            while previous.peek().map_or(false, |prev| prev.path < *path) {
+                previous.next();
+                removed += 1;
+            }
+            match previous.peek() {
+                Some(prev) if prev.path == *path => {
+                    let prev = previous.next().unwrap();
+                    if prev.metadata == *metadata {
+                        known_tags.push(Some(prev.tags));
+                    } else {
+                        known_tags.push(None);
+                        changed += 1;
+                    }
+                }
+                _ => {
+                    known_tags.push(None);
+                    added += 1;
+                }
+            }
+        }
+        removed += previous.count() as u32;
+
+        let jobs: Vec<(&Path, Option<&FileTags>)> = files
+            .iter()
+            .zip(known_tags.iter())
+            .map(|(&(ref path, _), tags)| (path.as_ref(), tags.as_ref()))
+            .collect();
+
         let (tx_progress, rx_progress) = sync_channel(8);
 
         // When we are IO bound, we need enough threads to keep the IO scheduler
@@ -439,19 +1065,27 @@ impl MemoryMetaIndex {
         // to ~70 seconds, which is totally worth it.
         let num_threads = 64;
         let mut builders: Vec<_> = (0..num_threads)
-            .map(|_| BuildMetaIndex::new(tx_progress.clone()))
+            .map(|_| BuildMetaIndex::new(tx_progress.clone(), fallback_ids))
             .collect();
 
+        // The channel has room for this message, the receiver prints it first.
+        tx_progress.send(Progress::Changes { added, changed, removed }).unwrap();
+
         // Drop the original sender to ensure the channel is closed when all
         // threads are done.
         mem::drop(tx_progress);
 
         let counter = std::sync::atomic::AtomicUsize::new(0);
 
+        let mut read_tags = Vec::with_capacity(num_threads);
+        let mut issues = Vec::new();
+
         crossbeam::scope(|scope| {
+            let jobs = &jobs[..];
+            let mut handles = Vec::with_capacity(num_threads);
             for builder in builders.iter_mut() {
                 let counter = &counter;
-                scope.spawn(move || MemoryMetaIndex::process(paths, counter, builder));
+                handles.push(scope.spawn(move || MemoryMetaIndex::process(jobs, counter, builder)));
             }
 
             // Print issues live as indexing happens.
@@ -459,12 +1093,27 @@ impl MemoryMetaIndex {
             let mut count = 0;
             for progress in rx_progress {
                 match progress {
+                    Progress::Changes { added, changed, removed } => {
+                        let mut status = status.lock().unwrap();
+                        status.stage = ScanStage::Indexing;
+                        status.files_added = added as u64;
+                        status.files_changed = changed as u64;
+                        status.files_removed = removed as u64;
+                        writeln!(
+                            out,
+                            "{} files added, {} changed, {} removed since the previous scan.",
+                            added, changed, removed,
+                        )?;
+                    }
                     Progress::Issue(issue) => {
+                        status.lock().unwrap().issues += 1;
                         if printed_count { write!(out, "\r")?; }
                         writeln!(out, "{}\n", issue)?;
                         printed_count = false;
+                        issues.push(issue);
                     }
                     Progress::Indexed(n) => {
+                        status.lock().unwrap().files_indexed += n as u64;
                         count += n;
                         if printed_count { write!(out, "\r")?; }
                         write!(out, "{} tracks indexed", count)?;
@@ -475,6 +1124,10 @@ impl MemoryMetaIndex {
             }
             if printed_count { writeln!(out, "").unwrap(); }
 
+            for handle in handles {
+                read_tags.push(handle.join());
+            }
+
             // We return `Ok` here so the return type of the scope closure is
             // `io::Result`, which allows using `?` above; that's a bit nicer
             // than unwrapping everywhere. We do unwrap the result below though,
@@ -484,15 +1137,34 @@ impl MemoryMetaIndex {
             result
         }).unwrap();
 
-        let mut issues = Vec::new();
-        let memory_index = MemoryMetaIndex::new(&builders, &mut issues);
+        let mut merge_issues = Vec::new();
+        let memory_index = MemoryMetaIndex::new(&builders, &mut merge_issues);
 
         // Report issues that resulted from merging.
-        for issue in &issues {
+        for issue in &merge_issues {
             writeln!(out, "{}\n", issue).unwrap();
         }
+        status.lock().unwrap().issues += merge_issues.len() as u64;
+        issues.extend(merge_issues);
 
-        Ok(memory_index)
+        // Fill in the tags we read, to complete the list of scanned files.
+        mem::drop(jobs);
+        for (i, tags) in read_tags.into_iter().flatten() {
+            known_tags[i] = Some(tags);
+        }
+        // Files that we failed to read have no tags. We leave them out, so the
+        // next scan sees them as added, and tries to read them again.
+        let scanned_files = files
+            .iter()
+            .zip(known_tags)
+            .filter_map(|(&(ref path, metadata), tags)| tags.map(|tags| ScannedFile {
+                path: path.clone(),
+                metadata: metadata,
+                tags: tags,
+            }))
+            .collect();
+
+        Ok((memory_index, scanned_files, issues))
     }
 }
 
@@ -514,47 +1186,72 @@ impl MetaIndex for MemoryMetaIndex {
 
     #[inline]
     fn get_track(&self, id: TrackId) -> Option<&Track> {
-        let slice = self.track_bookmarks.range(&self.tracks[..], id.0);
-        slice
-            .binary_search_by_key(&id, |pair| pair.0)
-            .ok()
-            // TODO: Remove bounds check.
-            .map(|idx| &slice[idx].1)
+        find_by_id(&self.track_bookmarks.bookmarks, &self.tracks, id, id.0)
     }
 
     #[inline]
     fn get_album(&self, id: AlbumId) -> Option<&Album> {
-        let slice = self.album_bookmarks.range(&self.albums[..], id.0);
-        slice
-            .binary_search_by_key(&id, |pair| pair.0)
-            .ok()
-            // TODO: Remove bounds check.
-            .map(|idx| &slice[idx].1)
+        find_by_id(&self.album_bookmarks.bookmarks, &self.albums, id, id.0)
     }
 
     #[inline]
-    fn get_album_tracks(&self, id: AlbumId) -> &[(TrackId, Track)] {
-        // Look for track 0 of disc 0. This is the first track of the album,
-        // if it exists. Otherwise binary search would find the first track
-        // after it.
-        let tid = get_track_id(id, 0, 0);
-        // TODO: Use bookmarks for this.
-        let begin = match self.tracks.binary_search_by_key(&tid, |pair| pair.0) {
-            Ok(i) => i,
-            Err(i) => i,
-        };
-        // Then do a linear scan over the tracks to find the first track that
-        // does not belong to the album any more. We could do another binary
-        // search to find the end instead, but a binary search would take about
-        // 13 random memory accesses for 12k tracks, whereas most albums have
-        // less tracks than that, and the linear scan has a very regular memory
-        // access pattern.
-        let end = begin + self.tracks[begin..]
-            .iter()
-            .position(|&(_tid, ref track)| track.album_id != id)
-            .unwrap_or(self.tracks.len() - begin);
+    fn get_track_mbids(&self, id: TrackId) -> Option<&TrackMbids> {
+        find_by_id(&self.track_mbids_bookmarks.bookmarks, &self.track_mbids, id, id.0)
+    }
+
+    #[inline]
+    fn get_album_mbids(&self, id: AlbumId) -> Option<&AlbumMbids> {
+        find_by_id(&self.album_mbids_bookmarks.bookmarks, &self.album_mbids, id, id.0)
+    }
+
+    #[inline]
+    fn get_album_details(&self, id: AlbumId) -> Option<&AlbumDetails> {
+        find_by_id(&self.album_details_bookmarks.bookmarks, &self.album_details, id, id.0)
+    }
+
+    #[inline]
+    fn get_album_genres(&self, id: AlbumId) -> &[(AlbumId, StringRef)] {
+        find_all_by_id(&self.album_genres_bookmarks.bookmarks, &self.album_genres, id, id.0)
+    }
+
+    #[inline]
+    fn get_all_album_genres(&self) -> &[(AlbumId, StringRef)] {
+        &self.album_genres
+    }
+
+    #[inline]
+    fn get_track_composers(&self, id: TrackId) -> &[(TrackId, ComposerId)] {
+        find_all_by_id(&self.track_composers_bookmarks.bookmarks, &self.track_composers, id, id.0)
+    }
 
-        &self.tracks[begin..end]
+    fn get_composer(&self, id: ComposerId) -> Option<&Composer> {
+        find_by_id(&self.composers_bookmarks.bookmarks, &self.composers, id, id.0)
+    }
+
+    fn get_composers(&self) -> &[(ComposerId, Composer)] {
+        &self.composers
+    }
+
+    fn get_work(&self, id: WorkId) -> Option<&Work> {
+        find_by_id(&self.works_bookmarks.bookmarks, &self.works, id, id.0)
+    }
+
+    fn get_track_work(&self, id: TrackId) -> Option<&TrackWork> {
+        find_by_id(&self.track_works_bookmarks.bookmarks, &self.track_works, id, id.0)
+    }
+
+    fn get_tracks_by_composer(&self, id: ComposerId) -> &[(ComposerId, TrackId)] {
+        find_all_by_id(&self.tracks_by_composer_bookmarks.bookmarks, &self.tracks_by_composer, id, id.0)
+    }
+
+    fn get_disc_subtitle(&self, id: AlbumId, disc_number: u8) -> Option<StringRef> {
+        let disc_id = get_track_id(id, disc_number, 0);
+        find_by_id(&self.disc_subtitles_bookmarks.bookmarks, &self.disc_subtitles, disc_id, disc_id.0).cloned()
+    }
+
+    #[inline]
+    fn get_album_tracks(&self, id: AlbumId) -> &[(TrackId, Track)] {
+        find_album_tracks(&self.tracks, id)
     }
 
     #[inline]
@@ -574,48 +1271,37 @@ impl MetaIndex for MemoryMetaIndex {
 
     #[inline]
     fn get_artist(&self, id: ArtistId) -> Option<&Artist> {
-        let slice = self.artist_bookmarks.range(&self.artists[..], id.0);
-        slice
-            .binary_search_by_key(&id, |pair| pair.0)
-            .ok()
-            // TODO: Remove bounds check.
-            .map(|idx| &slice[idx].1)
+        find_by_id(&self.artist_bookmarks.bookmarks, &self.artists, id, id.0)
+    }
+
+    #[inline]
+    fn get_album_artists(&self, album_id: AlbumId) -> &[(AlbumId, ArtistCredit)] {
+        find_all_by_id(
+            &self.album_artists_bookmarks.bookmarks,
+            &self.album_artists,
+            album_id,
+            album_id.0,
+        )
     }
 
     #[inline]
     fn get_albums_by_artist(&self, artist_id: ArtistId) -> &[(ArtistId, AlbumId)] {
-        // Use the bookmarks to narrow down the range of artists that we need to
-        // look though.
-        let mut candidates = self
-            .albums_by_artist_bookmarks
-            .range(&self.albums_by_artist[..], artist_id.0);
-
-        // Within that slice, we do a linear search for the start of the artist.
-        // For a library with ~400 artists like mine, there will only be one or
-        // two artists in the slice anyway, and most artists have few (no more
-        // than a dozen) albums. We could use a binary search for better
-        // complexity, but the one in `slice` is not suitable for this (it does
-        // not return the *first* index with the key, only *a* index), so I'll
-        // go with the easy thing for now.
-        let begin = candidates
-            .iter()
-            .position(|&(elem_artist_id, _album_id)| elem_artist_id == artist_id)
-            .unwrap_or(candidates.len());
-        candidates = &candidates[begin..];
-
-        // Then do a linear scan over the albums to find the first albums that
-        // does not belong to the artist any more. We could do another binary
-        // search to locate the end, but typically artists have few albums, so
-        // we go with a predictible memory access pattern here.
-        let end = candidates
-            .iter()
-            .position(|&(elem_artist_id, _album_id)| elem_artist_id != artist_id)
-            .unwrap_or(candidates.len());
-        candidates = &candidates[..end];
+        find_all_by_id(
+            &self.albums_by_artist_bookmarks.bookmarks,
+            &self.albums_by_artist,
+            artist_id,
+            artist_id.0,
+        )
+    }
 
-        // Only the albums for the desired artist are in this slice, and they
-        // are already sorted on ascending release date.
-        &candidates[..end]
+    #[inline]
+    fn get_appearances(&self, artist_id: ArtistId) -> &[(ArtistId, TrackId)] {
+        find_all_by_id(
+            &self.appearances_bookmarks.bookmarks,
+            &self.appearances,
+            artist_id,
+            artist_id.0,
+        )
     }
 
     #[inline]
@@ -634,5 +1320,9 @@ impl MetaIndex for MemoryMetaIndex {
     fn search_track(&self, words: &[String], into: &mut Vec<TrackId>) {
         search::search(&self.words_track, words, into);
     }
+
+    fn search_composer(&self, words: &[String], into: &mut Vec<ComposerId>) {
+        search::search(&self.words_composer, words, into);
+    }
 }
 
@@ -7,32 +7,46 @@
 
 extern crate claxon;
 extern crate musium;
+extern crate nix;
 extern crate serde_json;
 extern crate tiny_http;
 extern crate url;
 extern crate walkdir;
 
+use std::collections::HashMap;
 use std::env;
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
 use std::io::{BufRead, Write};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::sys::signal::{SigSet, Signal};
 
 use tiny_http::{Header, Request, Response, ResponseBox, Server};
 use tiny_http::Method::{Get, Post, Put};
 
+use musium::browse::{self, AlbumFilter};
 use musium::config::Config;
+use musium::cover::{self, AlbumPicture};
 use musium::error;
+use musium::index_file::{self, FileMetadata};
 use musium::player::{Millibel, Player};
-use musium::prim::{ArtistId, AlbumId, TrackId};
+use musium::prim::{ArtistId, AlbumId, ComposerId, TrackId};
 use musium::serialization;
 use musium::string_utils::normalize_words;
+use musium::swap::Swap;
+use musium::systemd;
 use musium::thumb_cache::ThumbCache;
-use musium::{MetaIndex, MemoryMetaIndex};
+use musium::watcher::Watcher;
+use musium::{Issue, IssueDetail, MetaIndex, MmapMetaIndex, ScanStage, ScanStatus};
 
 fn header_content_type(content_type: &str) -> Header {
     Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
@@ -48,22 +62,123 @@ fn header_expires_seconds(age_seconds: i64) -> Header {
         .expect("Failed to create content-type header, value is not ascii.")
 }
 
-struct MetaServer {
-    index: Arc<MemoryMetaIndex>,
+/// Why we could not produce a new index and library.
+#[derive(Debug)]
+enum LoadError {
+    /// Scanning the library failed.
+    Scan(musium::Error),
+    /// Writing the index, or reading it back, failed.
+    Index(index_file::Error),
+    /// Loading the cover art thumbnails failed.
+    Thumbs(io::Error),
+}
+
+impl From<musium::Error> for LoadError {
+    fn from(err: musium::Error) -> LoadError {
+        LoadError::Scan(err)
+    }
+}
+
+impl From<index_file::Error> for LoadError {
+    fn from(err: index_file::Error) -> LoadError {
+        LoadError::Index(err)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Scan(ref err) => write!(f, "Failed to scan library: {}", err),
+            LoadError::Index(ref err) => write!(f, "Failed to load index: {}", err),
+            LoadError::Thumbs(ref err) => write!(f, "Failed to load cover art thumbnails: {}", err),
+        }
+    }
+}
+
+/// The index and the thumbnails that belong to it, replaced together on reindex.
+struct Library {
+    index: Arc<MmapMetaIndex>,
     thumb_cache: ThumbCache,
+    issues: Vec<Issue>,
+    /// Pictures of the albums that were requested so far, see `get_album_pictures`.
+    album_pictures: Mutex<HashMap<AlbumId, Arc<Vec<AlbumPicture>>>>,
+}
+
+impl Library {
+    fn load(config: &Config, index: MmapMetaIndex) -> Result<Library, LoadError> {
+        println!("Loading cover art thumbnails ...");
+        let thumb_cache = ThumbCache::new(
+            index.get_album_ids_ordered_by_artist(),
+            &config.covers_path,
+        ).map_err(LoadError::Thumbs)?;
+        println!("Thumb cache size: {}", thumb_cache.size());
+
+        let issues = index.get_issues()?;
+
+        let library = Library {
+            index: Arc::new(index),
+            thumb_cache: thumb_cache,
+            issues: issues,
+            album_pictures: Mutex::new(HashMap::new()),
+        };
+        Ok(library)
+    }
+
+    /// Return the pictures of the album, listing them on first use.
+    ///
+    /// Listing reads every picture, so we keep the listing. A reindex replaces
+    /// the library, so it drops the listings, which keeps them from going
+    /// stale when the files change.
+    fn get_album_pictures(&self, album_id: AlbumId) -> Arc<Vec<AlbumPicture>> {
+        if let Some(pictures) = self.album_pictures.lock().unwrap().get(&album_id) {
+            return pictures.clone()
+        }
+
+        // We don't hold the lock while we read the files, that can take a
+        // while. If two requests race, they both list the pictures, that's ok.
+        let fnames: Vec<&str> = self.index
+            .get_album_tracks(album_id)
+            .iter()
+            .map(|&(_, ref track)| self.index.get_filename(track.filename))
+            .collect();
+        let pictures = Arc::new(cover::list_album_pictures(&fnames));
+        self.album_pictures.lock().unwrap().insert(album_id, pictures.clone());
+        pictures
+    }
+}
+
+/// Clears the reindexing flag when dropped, also when the reindex returns early.
+struct ReindexGuard<'a>(&'a AtomicBool);
+
+impl<'a> Drop for ReindexGuard<'a> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+struct MetaServer {
+    config: Config,
+    library: Swap<Library>,
     player: Player,
+    is_reindexing: AtomicBool,
+    reindex_thread: Mutex<Option<thread::JoinHandle<()>>>,
+    scan_status: Mutex<ScanStatus>,
 }
 
 impl MetaServer {
     fn new(
-        index: Arc<MemoryMetaIndex>,
-        thumb_cache: ThumbCache,
+        config: Config,
+        library: Library,
         player: Player,
+        scan_status: Mutex<ScanStatus>,
     ) -> MetaServer {
         MetaServer {
-            index: index,
-            thumb_cache: thumb_cache,
+            config: config,
+            library: Swap::new(Arc::new(library)),
             player: player,
+            is_reindexing: AtomicBool::new(false),
+            reindex_thread: Mutex::new(None),
+            scan_status: scan_status,
         }
     }
 
@@ -95,47 +210,75 @@ impl MetaServer {
             .boxed()
     }
 
-    fn handle_album_cover(&self, id: &str) -> ResponseBox {
-        let album_id = match AlbumId::parse(id) {
+    /// Serve `/cover/:album_id`, `/cover/:album_id/front`, or `/cover/:album_id/:n`.
+    ///
+    /// The first lists the pictures of the album as json, the second serves
+    /// the preferred front cover, and the last serves picture `n` of the list.
+    fn handle_album_cover(&self, path: &str) -> ResponseBox {
+        let library = self.library.get();
+        let mut parts = path.splitn(2, '/');
+        let album_id = match parts.next().and_then(AlbumId::parse) {
             Some(aid) => aid,
             None => return self.handle_bad_request("Invalid album id."),
         };
 
-        let tracks = self.index.get_album_tracks(album_id);
-        let (_track_id, track) = tracks.first().expect("Albums have at least one track.");
-        let fname = self.index.get_filename(track.filename);
-
-        let opts = claxon::FlacReaderOptions {
-            metadata_only: true,
-            read_picture: claxon::ReadPicture::CoverAsVec,
-            read_vorbis_comment: false,
+        let tracks = library.index.get_album_tracks(album_id);
+        // Albums have at least one track, so if there is none, the album does
+        // not exist. Clients may still refer to it after a reindex removed it.
+        let (_track_id, track) = match tracks.first() {
+            Some(t) => t,
+            None => return self.handle_not_found(),
         };
-        let reader = match claxon::FlacReader::open_ext(fname, opts) {
-            Ok(r) => r,
-            Err(..) => return self.handle_error("Failed to open flac file."),
+
+        let n = parts.next();
+        if n == Some("front") {
+            let fname = library.index.get_filename(track.filename);
+            return match cover::read_cover(fname) {
+                Ok(Some(cover)) => Response::from_data(cover.data)
+                    .with_header(header_content_type(&cover.mime_type))
+                    .with_header(header_expires_seconds(3600 * 24 * 30))
+                    .boxed(),
+                // The album has no cover art, embedded or in its directory.
+                Ok(None) => self.handle_not_found(),
+                Err(..) => self.handle_error("Failed to read cover art."),
+            }
+        }
+
+        let pictures = library.get_album_pictures(album_id);
+
+        let picture = match n.map(|n| n.parse::<usize>()) {
+            Some(Ok(i)) if i < pictures.len() => &pictures[i],
+            Some(Ok(..)) => return self.handle_not_found(),
+            Some(Err(..)) => return self.handle_bad_request("Invalid picture index."),
+            None => {
+                let buffer = Vec::new();
+                let mut w = io::Cursor::new(buffer);
+                serialization::write_album_pictures_json(&mut w, album_id, &pictures).unwrap();
+                return Response::from_data(w.into_inner())
+                    .with_header(header_content_type("application/json"))
+                    .boxed()
+            }
         };
 
-        if let Some(cover) = reader.into_pictures().pop() {
-            let content_type = header_content_type(&cover.mime_type);
-            let data = cover.into_vec();
-            Response::from_data(data)
-                .with_header(content_type)
-                .with_header(header_expires_seconds(3600 * 24 * 30))
-                .boxed()
-        } else {
-            // The file has no embedded front cover.
-            self.handle_not_found()
+        match picture.read_data() {
+            // No expiry header here: after the library changes, the same `n`
+            // can refer to a different picture.
+            Ok(data) => Response::from_data(data)
+                .with_header(header_content_type(&picture.mime_type))
+                .boxed(),
+            Err(..) => self.handle_error("Failed to read cover art."),
         }
     }
 
     fn handle_thumb(&self, id: &str) -> ResponseBox {
+        let library = self.library.get();
         // TODO: DRY this track id parsing and loadong part.
         let album_id = match AlbumId::parse(id) {
             Some(aid) => aid,
             None => return self.handle_bad_request("Invalid album id."),
         };
 
-        let img = match self.thumb_cache.get(album_id) {
+        let img = match library.thumb_cache.get(album_id) {
             // TODO: Generate thumbs lazily?
             None => return self.handle_not_found(),
             Some(bytes) => bytes,
@@ -148,6 +291,7 @@ impl MetaServer {
     }
 
     fn handle_track(&self, path: &str) -> ResponseBox {
+        let library = self.library.get();
         // Track urls are of the form `/track/f7c153f2b16dc101.flac`.
         if !path.ends_with(".flac") {
             return self.handle_bad_request("Expected a path ending in .flac.")
@@ -159,12 +303,12 @@ impl MetaServer {
             None => return self.handle_bad_request("Invalid track id."),
         };
 
-        let track = match self.index.get_track(track_id) {
+        let track = match library.index.get_track(track_id) {
             Some(t) => t,
             None => return self.handle_not_found(),
         };
 
-        let fname = self.index.get_filename(track.filename);
+        let fname = library.index.get_filename(track.filename);
 
         // TODO: Rather than reading the file into memory in userspace,
         // use sendfile.
@@ -180,19 +324,20 @@ impl MetaServer {
     }
 
     fn handle_album(&self, id: &str) -> ResponseBox {
+        let library = self.library.get();
         let album_id = match AlbumId::parse(id) {
             Some(aid) => aid,
             None => return self.handle_bad_request("Invalid album id."),
         };
 
-        let album = match self.index.get_album(album_id) {
+        let album = match library.index.get_album(album_id) {
             Some(a) => a,
             None => return self.handle_not_found(),
         };
 
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
-        serialization::write_album_json(&*self.index, &mut w, album_id, album).unwrap();
+        serialization::write_album_json(&*library.index, &mut w, album_id, album).unwrap();
 
         Response::from_data(w.into_inner())
             .with_header(header_content_type("application/json"))
@@ -200,31 +345,97 @@ impl MetaServer {
     }
 
     fn handle_artist(&self, id: &str) -> ResponseBox {
+        let library = self.library.get();
         let artist_id = match ArtistId::parse(id) {
             Some(aid) => aid,
             None => return self.handle_bad_request("Invalid artist id."),
         };
 
-        let artist = match self.index.get_artist(artist_id) {
+        let artist = match library.index.get_artist(artist_id) {
             Some(a) => a,
             None => return self.handle_not_found(),
         };
 
-        let albums = self.index.get_albums_by_artist(artist_id);
+        let albums = library.index.get_albums_by_artist(artist_id);
+        let appearances = library.index.get_appearances(artist_id);
 
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
-        serialization::write_artist_json(&*self.index, &mut w, artist, albums).unwrap();
+        serialization::write_artist_json(&*library.index, &mut w, artist, albums, appearances).unwrap();
 
         Response::from_data(w.into_inner())
             .with_header(header_content_type("application/json"))
             .boxed()
     }
 
-    fn handle_albums(&self) -> ResponseBox {
+    fn handle_composer(&self, id: &str) -> ResponseBox {
+        let library = self.library.get();
+        let composer_id = match ComposerId::parse(id) {
+            Some(cid) => cid,
+            None => return self.handle_bad_request("Invalid composer id."),
+        };
+
+        let composer = match library.index.get_composer(composer_id) {
+            Some(c) => c,
+            None => return self.handle_not_found(),
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_composer_json(&*library.index, &mut w, composer_id, composer).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_composers(&self) -> ResponseBox {
+        let library = self.library.get();
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
-        serialization::write_albums_json(&*self.index, &mut w).unwrap();
+        serialization::write_composers_json(&*library.index, &mut w).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_albums(&self, raw_query: &str) -> ResponseBox {
+        let mut filter = AlbumFilter::new();
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if !filter.set(k.as_ref(), v.as_ref()) {
+                return self.handle_bad_request("Unknown album filter.");
+            }
+        }
+
+        let library = self.library.get();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_albums_json(&*library.index, &mut w, &filter).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_genres(&self) -> ResponseBox {
+        let library = self.library.get();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let genres = browse::count_genres(&*library.index);
+        serialization::write_album_counts_json(&mut w, &genres[..]).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_labels(&self) -> ResponseBox {
+        let library = self.library.get();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let labels = browse::count_labels(&*library.index);
+        serialization::write_album_counts_json(&mut w, &labels[..]).unwrap();
 
         Response::from_data(w.into_inner())
             .with_header(header_content_type("application/json"))
@@ -232,11 +443,12 @@ impl MetaServer {
     }
 
     fn handle_queue(&self) -> ResponseBox {
+        let library = self.library.get();
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
         let queue = self.player.get_queue();
         serialization::write_queue_json(
-            &*self.index,
+            &*library.index,
             &mut w,
             &queue.tracks[..],
         ).unwrap();
@@ -251,13 +463,10 @@ impl MetaServer {
             None => return self.handle_bad_request("Invalid track id."),
         };
 
-        // Confirm that the track exists before we enqueue it.
-        let _track = match self.index.get_track(track_id) {
-            Some(t) => t,
+        let queue_id = match self.player.enqueue(track_id) {
+            Some(qid) => qid,
             None => return self.handle_not_found(),
         };
-
-        let queue_id = self.player.enqueue(track_id);
         let queue_id_json = format!(r#""{}""#, queue_id);
 
         Response::from_string(queue_id_json)
@@ -286,7 +495,18 @@ impl MetaServer {
             .boxed()
     }
 
+    fn handle_status(&self) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let device_status = self.player.get_device_status();
+        serialization::write_status_json(&mut w, &device_status).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
     fn handle_search(&self, raw_query: &str) -> ResponseBox {
+        let library = self.library.get();
         let mut opt_query = None;
         for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
             if k == "q" {
@@ -304,10 +524,12 @@ impl MetaServer {
         let mut artists = Vec::new();
         let mut albums = Vec::new();
         let mut tracks = Vec::new();
+        let mut composers = Vec::new();
 
-        self.index.search_artist(&words[..], &mut artists);
-        self.index.search_album(&words[..], &mut albums);
-        self.index.search_track(&words[..], &mut tracks);
+        library.index.search_artist(&words[..], &mut artists);
+        library.index.search_album(&words[..], &mut albums);
+        library.index.search_track(&words[..], &mut tracks);
+        library.index.search_composer(&words[..], &mut composers);
 
         // Cap the number of search results we serve. We can easily produce many
         // many results (especially when searching for "t", a prefix of "the",
@@ -317,15 +539,17 @@ impl MetaServer {
         let n_artists = artists.len().min(250);
         let n_albums = albums.len().min(250);
         let n_tracks = tracks.len().min(250);
+        let n_composers = composers.len().min(250);
 
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
         serialization::write_search_results_json(
-            &*self.index,
+            &*library.index,
             &mut w,
             &artists[..n_artists],
             &albums[..n_albums],
             &tracks[..n_tracks],
+            &composers[..n_composers],
         ).unwrap();
 
         Response::from_data(w.into_inner())
@@ -334,7 +558,101 @@ impl MetaServer {
             .boxed()
     }
 
-    fn handle_request(&self, request: Request) {
+    fn handle_reindex(self: &Arc<Self>) -> ResponseBox {
+        if !self.start_reindex() {
+            return Response::from_string("A reindex is already in progress.")
+                .with_status_code(409) // "409 Conflict"
+                .boxed()
+        }
+
+        Response::from_string("Reindex started.")
+            .with_status_code(202) // "202 Accepted"
+            .boxed()
+    }
+
+    /// Start a reindex on a background thread, unless one is in progress.
+    ///
+    /// Returns whether a reindex was started.
+    fn start_reindex(self: &Arc<Self>) -> bool {
+        if self.is_reindexing.swap(true, Ordering::SeqCst) {
+            return false
+        }
+
+        // The previous reindex thread, if any, has finished, because it clears
+        // the flag as the last thing it does. Join it before we replace it.
+        let mut reindex_thread = self.reindex_thread.lock().unwrap();
+        if let Some(prev_thread) = reindex_thread.take() {
+            prev_thread.join().unwrap();
+        }
+
+        let self_for_reindex = self.clone();
+        let builder = thread::Builder::new().name("reindex".into());
+        let join_handle = builder.spawn(move || {
+            self_for_reindex.reindex();
+        }).unwrap();
+        *reindex_thread = Some(join_handle);
+
+        true
+    }
+
+    /// Rescan the library, and swap in the new index when done.
+    ///
+    /// Requests that are being served while we swap continue to use the old
+    /// index; it is unmapped when the last of them completes.
+    fn reindex(&self) {
+        let _guard = ReindexGuard(&self.is_reindexing);
+        *self.scan_status.lock().unwrap() = ScanStatus::new();
+
+        let library = match make_index(&self.config, false, &self.scan_status)
+            .and_then(|index| Library::load(&self.config, index))
+        {
+            Ok(library) => Arc::new(library),
+            Err(err) => {
+                // Keep serving the old library, and report why in the status.
+                println!("Reindex failed: {}", err);
+                let mut status = self.scan_status.lock().unwrap();
+                status.stage = ScanStage::Failed;
+                status.error = Some(err.to_string());
+                return
+            }
+        };
+
+        // Swap the player first. It drops queued tracks that no longer exist,
+        // so by the time handlers see the new index, the queue matches it.
+        self.player.set_index(library.index.clone());
+        self.library.set(library);
+        self.scan_status.lock().unwrap().stage = ScanStage::Done;
+        println!("Reindex complete.");
+    }
+
+    fn handle_reindex_status(&self) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let status = self.scan_status.lock().unwrap().clone();
+        serialization::write_scan_status_json(&mut w, &status).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_issues(&self) -> ResponseBox {
+        let library = self.library.get();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_issues_json(&mut w, &library.issues[..]).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Wait for a reindex that is in progress, if any.
+    fn join_reindex(&self) {
+        if let Some(join_handle) = self.reindex_thread.lock().unwrap().take() {
+            join_handle.join().unwrap();
+        }
+    }
+
+    fn handle_request(self: &Arc<Self>, request: Request) {
         // Break url into the part before the ? and the part after. The part
         // before we split on slashes.
         let mut url_iter = request.url().splitn(2, '?');
@@ -359,10 +677,18 @@ impl MetaServer {
             (&Get, Some("track"),  Some(t)) => self.handle_track(t),
             (&Get, Some("album"),  Some(a)) => self.handle_album(a),
             (&Get, Some("artist"), Some(a)) => self.handle_artist(a),
-            (&Get, Some("albums"), None)    => self.handle_albums(),
+            (&Get, Some("albums"), None)    => self.handle_albums(query),
+            (&Get, Some("composer"), Some(c)) => self.handle_composer(c),
+            (&Get, Some("composers"), None) => self.handle_composers(),
+            (&Get, Some("genres"), None)    => self.handle_genres(),
+            (&Get, Some("labels"), None)    => self.handle_labels(),
             (&Get, Some("search"), None)    => self.handle_search(query),
             (&Get, Some("queue"),  None)    => self.handle_queue(),
             (&Put, Some("queue"),  Some(t)) => self.handle_enqueue(t),
+            (&Get, Some("status"), None)    => self.handle_status(),
+            (&Get, Some("issues"), None)    => self.handle_issues(),
+            (&Post, Some("reindex"), None)  => self.handle_reindex(),
+            (&Get, Some("reindex"), Some("status")) => self.handle_reindex_status(),
 
             // Volume control, volume up/down change the volume by 1 dB.
             (&Get,  Some("volume"), None)         => self.handle_get_volume(),
@@ -382,7 +708,7 @@ impl MetaServer {
             }
             // Fallback.
             (&Get, _, _) => self.handle_not_found(),
-            _ => self.handle_bad_request("Expected a GET request."),
+            _ => self.handle_bad_request("Unsupported method for this endpoint."),
         };
 
         match request.respond(response) {
@@ -392,9 +718,20 @@ impl MetaServer {
     }
 }
 
-fn serve(bind: &str, service: Arc<MetaServer>) {
-    let server = Server::http(bind).expect("TODO: Failed to start server.");
-    let server = Arc::new(server);
+/// Serve requests until we receive one of the signals, then shut down.
+///
+/// The signals must be blocked in all threads, so that `signals.wait()` can
+/// receive them.
+fn serve(bind: &str, service: Arc<MetaServer>, signals: SigSet) {
+    // When systemd passes us a socket, use it instead of binding our own.
+    let server = match systemd::take_listener() {
+        Some(listener) => {
+            println!("Using socket passed by systemd instead of {}.", bind);
+            Server::from_listener(listener, None)
+        }
+        None => Server::http(bind),
+    };
+    let server = Arc::new(server.expect("TODO: Failed to start server."));
 
     // Browsers do not make more than 8 requests in parallel, so having more
     // handler threads is not useful; I expect only a single user to be
@@ -402,15 +739,22 @@ fn serve(bind: &str, service: Arc<MetaServer>) {
     let n_threads = 8;
     let mut threads = Vec::with_capacity(n_threads);
 
+    // The handler threads poll this flag between requests, so they exit
+    // within one poll interval after we start shutting down.
+    let is_stopping = Arc::new(AtomicBool::new(false));
+    let poll_interval = Duration::from_millis(500);
+
     for i in 0..n_threads {
         let server_i = server.clone();
         let service_i = service.clone();
+        let is_stopping_i = is_stopping.clone();
         let name = format!("http_server_{}", i);
         let builder = thread::Builder::new().name(name);
         let join_handle = builder.spawn(move || {
-            loop {
-                let request = match server_i.recv() {
-                    Ok(rq) => rq,
+            while !is_stopping_i.load(Ordering::SeqCst) {
+                let request = match server_i.recv_timeout(poll_interval) {
+                    Ok(Some(rq)) => rq,
+                    Ok(None) => continue,
                     Err(e) => {
                         println!("Error: {:?}", e);
                         break;
@@ -422,61 +766,271 @@ fn serve(bind: &str, service: Arc<MetaServer>) {
         threads.push(join_handle);
     }
 
-    // Block until all threads have stopped, which only happens in case of an
-    // error on all of them.
+    // If systemd enabled the watchdog, ping it as long as the player is
+    // healthy. If it is not, systemd will restart us.
+    let watchdog_thread = systemd::watchdog_timeout().map(|timeout| {
+        let service_w = service.clone();
+        let is_stopping_w = is_stopping.clone();
+        let builder = thread::Builder::new().name("watchdog".into());
+        builder.spawn(move || {
+            while !is_stopping_w.load(Ordering::SeqCst) {
+                if service_w.player.is_healthy() {
+                    systemd::notify_watchdog();
+                } else {
+                    println!("Player threads are unhealthy, not pinging watchdog.");
+                }
+                thread::park_timeout(timeout / 2);
+            }
+        }).unwrap()
+    });
+
+    // Watch the library, and reindex when it changes. Taggers and rippers
+    // write many files in quick succession, so we wait until the library has
+    // been quiet for a while before we start.
+    let watcher_thread = {
+        let service_w = service.clone();
+        let is_stopping_w = is_stopping.clone();
+        let debounce = Duration::from_secs(5);
+        let builder = thread::Builder::new().name("watcher".into());
+        builder.spawn(move || {
+            let mut watcher = match Watcher::new(&service_w.config.library_path) {
+                Ok(w) => w,
+                Err(nix::Error::Sys(Errno::ENOSPC)) => {
+                    println!("Not watching the library for changes: inotify watch limit reached.");
+                    println!("Increase fs.inotify.max_user_watches to enable watching.");
+                    return
+                }
+                Err(err) => {
+                    println!("Not watching the library for changes: {}", err);
+                    return
+                }
+            };
+            println!("Watching {} directories for changes.", watcher.len());
+
+            let mut last_change: Option<Instant> = None;
+            while !is_stopping_w.load(Ordering::SeqCst) {
+                match watcher.poll(poll_interval) {
+                    Ok(true) => last_change = Some(Instant::now()),
+                    Ok(false) => {}
+                    Err(err) => {
+                        println!("Failed to watch the library, no longer watching for changes: {}", err);
+                        return
+                    }
+                }
+                // If a reindex is in progress, we try again at the next poll,
+                // because it may have missed the change.
+                let is_quiet = last_change.map_or(false, |t| t.elapsed() >= debounce);
+                if is_quiet && service_w.start_reindex() {
+                    println!("Library changed, reindexing ...");
+                    last_change = None;
+                }
+            }
+        }).unwrap()
+    };
+
+    systemd::notify_ready();
+
+    let signal = signals.wait().expect("Failed to wait for signal.");
+    println!("Received {:?}, shutting down ...", signal);
+    systemd::notify_stopping();
+
+    // Let the handler threads finish the requests they are serving, and stop.
+    is_stopping.store(true, Ordering::SeqCst);
+    if let Some(watchdog_thread) = watchdog_thread {
+        watchdog_thread.thread().unpark();
+        watchdog_thread.join().unwrap();
+    }
     for thread in threads.drain(..) {
         thread.join().unwrap();
     }
+    watcher_thread.join().unwrap();
+    println!("Server stopped.");
+
+    // A reindex thread holds a reference to the service too. It can't be
+    // interrupted, so if one is in progress, we have to wait for it.
+    service.join_reindex();
+
+    // With the handler and reindex threads gone, we hold the only reference to
+    // the service.
+    let service = match Arc::try_unwrap(service) {
+        Ok(service) => service,
+        Err(..) => panic!("Service is still referenced after handlers stopped."),
+    };
+    service.player.shutdown();
+    service.player.join();
+    println!("Shutdown complete.");
 }
 
-fn make_index(dir: &Path) -> MemoryMetaIndex {
+/// Enumerate all flac files in the library, with their size and mtime.
+///
+/// The result is sorted by path.
+fn enumerate_files(dir: &Path, status: &Mutex<ScanStatus>) -> Vec<(PathBuf, FileMetadata)> {
     let wd = walkdir::WalkDir::new(dir)
         .follow_links(true)
         .max_open(128);
 
     let flac_ext = OsStr::new("flac");
 
-    let index;
-    {
-        let stdout = std::io::stdout();
-        let mut lock = stdout.lock();
-
-        // First enumerate all flac files, before indexing them. It turns out
-        // that this is faster than indexing them on the go (and not first
-        // collecting into a vector). See also performance.md in the root of the
-        // repository.
-        let mut k = 0;
-        let mut paths = Vec::new();
-        let paths_iter = wd
-            .into_iter()
-            .filter_map(|e| match e {
-                Ok(entry) => Some(entry),
-                // TODO: Add a nicer way to report errors.
-                Err(err) => { eprintln!("{}", err); None }
-            })
-            .filter(|e| e.file_type().is_file())
-            .map(|e| e.into_path())
-            .filter(|p| p.extension() == Some(flac_ext));
-
-        for p in paths_iter {
-            // Print progress updates on the number of files discovered.
-            // Enumerating the filesystem can take a long time when the OS
-            // caches are cold. When the caches are warm it is pretty much
-            // instant, but indexing tends to happen with cold caches.
-            k += 1;
-            if k % 64 == 0 {
-                write!(&mut lock, "\r{} files discovered", k).unwrap();
-                lock.flush().unwrap();
+    let stderr = std::io::stderr();
+    let mut lock = stderr.lock();
+
+    // First enumerate all flac files, before indexing them. It turns out
+    // that this is faster than indexing them on the go (and not first
+    // collecting into a vector). See also performance.md in the root of the
+    // repository.
+    let mut k = 0;
+    let mut files = Vec::new();
+    let files_iter = wd
+        .into_iter()
+        .filter_map(|e| match e {
+            Ok(entry) => Some(entry),
+            // TODO: Add a nicer way to report errors.
+            Err(err) => { eprintln!("{}", err); None }
+        })
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| e.path().extension() == Some(flac_ext))
+        .filter_map(|e| match e.metadata() {
+            Ok(meta) => Some((e.into_path(), FileMetadata::from_metadata(&meta))),
+            Err(err) => { eprintln!("{}", err); None }
+        });
+
+    for file in files_iter {
+        // Print progress updates on the number of files discovered.
+        // Enumerating the filesystem can take a long time when the OS
+        // caches are cold. When the caches are warm it is pretty much
+        // instant, but indexing tends to happen with cold caches.
+        k += 1;
+        if k % 64 == 0 {
+            write!(&mut lock, "\r{} files discovered", k).unwrap();
+            lock.flush().unwrap();
+            status.lock().unwrap().files_discovered = k;
+        }
+        files.push(file);
+    }
+    writeln!(&mut lock, "\r{} files discovered", k).unwrap();
+    status.lock().unwrap().files_discovered = k;
+
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    files
+}
+
+/// Load the index from the data path, or rescan the library if needed.
+///
+/// The index on disk is only used if the files in the library have the same
+/// size and mtime as when it was written. Otherwise we rescan, but we only
+/// read the files that were added or changed since then, for the others we
+/// reuse the tags stored in the index. When `force_rescan` is set, read all
+/// files. After a scan, the new index is written to disk, and then we serve
+/// from the file, so the index does not need to stay in memory.
+///
+/// Progress is printed to stderr, so that commands can write their output to
+/// stdout. It is also reported in `status`. Setting the stage to `Done` is up
+/// to the caller, because the caller may have more work to do before the index
+/// is used.
+///
+/// When the library cannot be scanned or the new index cannot be written, we
+/// return the error rather than panicking, so a failed reindex does not take
+/// the server down with it.
+fn make_index(
+    config: &Config,
+    force_rescan: bool,
+    status: &Mutex<ScanStatus>,
+) -> Result<MmapMetaIndex, LoadError> {
+    let files = enumerate_files(&config.library_path, status);
+    let index_path = config.data_path.join("index.bin");
+
+    let mut previous_files = Vec::new();
+
+    if !force_rescan {
+        match MmapMetaIndex::open(&index_path).and_then(|index| Ok((index.get_files()?, index))) {
+            Ok((indexed_files, index)) => {
+                let is_unchanged = indexed_files.len() == files.len() && indexed_files
+                    .iter()
+                    .zip(files.iter())
+                    .all(|(prev, &(ref path, ref metadata))| prev.path == *path && prev.metadata == *metadata);
+                let is_same_config = index.fallback_ids() == config.fallback_ids;
+                if is_unchanged && is_same_config {
+                    eprintln!("Loaded index from {}.", index_path.to_string_lossy());
+                    print_index_stats(&index);
+                    return Ok(index)
+                }
+                if is_unchanged {
+                    // The tags of all files are in the index, so we can rebuild
+                    // it with the new setting without reading any file.
+                    eprintln!("The fallback_ids setting changed, rebuilding index ...");
+                } else {
+                    eprintln!("Library changed since the index was written, rescanning ...");
+                }
+                previous_files = indexed_files;
+            }
+            Err(index_file::Error::IoError(ref err)) if err.kind() == io::ErrorKind::NotFound => {
+                eprintln!("No index found at {}, scanning library ...", index_path.to_string_lossy());
+            }
+            Err(err) => {
+                eprintln!("Failed to load index from {}: {}", index_path.to_string_lossy(), err);
+                eprintln!("Rescanning library ...");
             }
-            paths.push(p);
         }
-        writeln!(&mut lock, "\r{} files discovered", k).unwrap();
+    }
 
-        index = musium::MemoryMetaIndex::from_paths(&paths[..], &mut lock);
+    let result = {
+        let stderr = std::io::stderr();
+        let mut lock = stderr.lock();
+        musium::MemoryMetaIndex::from_files(&files[..], previous_files, config.fallback_ids, status, &mut lock)
     };
+    let (index, scanned_files, issues) = result?;
 
-    let index = index.expect("Failed to build index.");
-    println!(
+    status.lock().unwrap().stage = ScanStage::Writing;
+    if let Err(err) = index_file::write(&index_path, &scanned_files[..], &issues[..], &index, config.fallback_ids) {
+        eprintln!("Failed to write index to {}: {}", index_path.to_string_lossy(), err);
+        return Err(LoadError::Index(err.into()))
+    }
+    eprintln!("Wrote index to {}.", index_path.to_string_lossy());
+
+    // Drop the in-memory index before we map the file, so we don't hold both.
+    std::mem::drop(index);
+    let index = MmapMetaIndex::open(&index_path)?;
+
+    print_index_stats(&index);
+    Ok(index)
+}
+
+/// Scan the library and return the issues, without writing an index.
+///
+/// Tags of files that did not change are taken from the existing index, if
+/// there is one, so only new and changed files are read.
+fn scan_issues(config: &Config) -> Result<Vec<Issue>, LoadError> {
+    let status = Mutex::new(ScanStatus::new());
+    let files = enumerate_files(&config.library_path, &status);
+    let index_path = config.data_path.join("index.bin");
+    let previous_files = MmapMetaIndex::open(&index_path)
+        .and_then(|index| index.get_files())
+        .unwrap_or_default();
+
+    let stderr = io::stderr();
+    let (_index, _scanned_files, issues) = musium::MemoryMetaIndex::from_files(
+        &files[..],
+        previous_files,
+        config.fallback_ids,
+        &status,
+        stderr.lock(),
+    )?;
+    Ok(issues)
+}
+
+/// Call `make_index`, for commands where we have no use for a failed index.
+fn make_index_or_exit(config: &Config, force_rescan: bool, status: &Mutex<ScanStatus>) -> MmapMetaIndex {
+    match make_index(config, force_rescan, status) {
+        Ok(index) => index,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_index_stats(index: &MmapMetaIndex) {
+    eprintln!(
         "Index has {} artists, {} albums, and {} tracks.",
         index.get_artists().len(),
         index.get_albums().len(),
@@ -485,65 +1039,73 @@ fn make_index(dir: &Path) -> MemoryMetaIndex {
 
     let mut track_louds = Vec::new();
     for &(track_id, ref track) in index.get_tracks() {
-        if let Some(lufs) = track.loudness {
+        if let Some(lufs) = track.loudness.get() {
             track_louds.push((lufs, track_id));
         }
     }
     track_louds.sort();
-    let track_loud_min = track_louds[0];
-    let track_loud_max = track_louds[track_louds.len() - 1];
-    println!(
-        "\nSoftest track: {} by {} at {}.",
-        index.get_string(index.get_track(track_loud_min.1).unwrap().title),
-        index.get_string(index.get_track(track_loud_min.1).unwrap().artist),
-        track_loud_min.0,
-    );
-    println!(
-        "Loudest track: {} by {} at {}.",
-        index.get_string(index.get_track(track_loud_max.1).unwrap().title),
-        index.get_string(index.get_track(track_loud_max.1).unwrap().artist),
-        track_loud_max.0,
-    );
-    println!(
-        "Track loudness p5, p50, p95: {}, {}, {}",
-        track_louds[ 5 * track_louds.len() / 100].0,
-        track_louds[50 * track_louds.len() / 100].0,
-        track_louds[95 * track_louds.len() / 100].0,
-    );
+
+    // In an empty library, or before loudness analysis ran, there is nothing
+    // to report.
+    if !track_louds.is_empty() {
+        let track_loud_min = track_louds[0];
+        let track_loud_max = track_louds[track_louds.len() - 1];
+        eprintln!(
+            "\nSoftest track: {} by {} at {}.",
+            index.get_string(index.get_track(track_loud_min.1).unwrap().title),
+            index.get_string(index.get_track(track_loud_min.1).unwrap().artist),
+            track_loud_min.0,
+        );
+        eprintln!(
+            "Loudest track: {} by {} at {}.",
+            index.get_string(index.get_track(track_loud_max.1).unwrap().title),
+            index.get_string(index.get_track(track_loud_max.1).unwrap().artist),
+            track_loud_max.0,
+        );
+        eprintln!(
+            "Track loudness p5, p50, p95: {}, {}, {}",
+            track_louds[ 5 * track_louds.len() / 100].0,
+            track_louds[50 * track_louds.len() / 100].0,
+            track_louds[95 * track_louds.len() / 100].0,
+        );
+    }
 
     let mut album_louds = Vec::new();
     for &(album_id, ref album) in index.get_albums() {
-        if let Some(lufs) = album.loudness {
+        if let Some(lufs) = album.loudness.get() {
             album_louds.push((lufs, album_id));
         }
     }
     album_louds.sort();
-    let album_loud_min = album_louds[0];
-    let album_loud_max = album_louds[album_louds.len() - 1];
-    println!(
-        "\nSoftest album: {} by {} at {}.",
-        index.get_string(index.get_album(album_loud_min.1).unwrap().title),
-        index.get_string(index.get_artist(index.get_album(album_loud_min.1).unwrap().artist_id).unwrap().name),
-        album_loud_min.0,
-    );
-    println!(
-        "Loudest album: {} by {} at {}.",
-        index.get_string(index.get_album(album_loud_max.1).unwrap().title),
-        index.get_string(index.get_artist(index.get_album(album_loud_max.1).unwrap().artist_id).unwrap().name),
-        album_loud_max.0,
-    );
-    println!(
-        "Album loudness p5, p50, p95: {}, {}, {}\n",
-        album_louds[ 5 * album_louds.len() / 100].0,
-        album_louds[50 * album_louds.len() / 100].0,
-        album_louds[95 * album_louds.len() / 100].0,
-    );
 
-    println!("Artist word index: {}", index.words_artist.size());
-    println!("Album word index:  {}", index.words_album.size());
-    println!("Track word index:  {}", index.words_track.size());
+    if !album_louds.is_empty() {
+        let album_loud_min = album_louds[0];
+        let album_loud_max = album_louds[album_louds.len() - 1];
+        eprintln!(
+            "\nSoftest album: {} by {} at {}.",
+            index.get_string(index.get_album(album_loud_min.1).unwrap().title),
+            index.get_string(index.get_album(album_loud_min.1).unwrap().artist),
+            album_loud_min.0,
+        );
+        eprintln!(
+            "Loudest album: {} by {} at {}.",
+            index.get_string(index.get_album(album_loud_max.1).unwrap().title),
+            index.get_string(index.get_album(album_loud_max.1).unwrap().artist),
+            album_loud_max.0,
+        );
+        eprintln!(
+            "Album loudness p5, p50, p95: {}, {}, {}\n",
+            album_louds[ 5 * album_louds.len() / 100].0,
+            album_louds[50 * album_louds.len() / 100].0,
+            album_louds[95 * album_louds.len() / 100].0,
+        );
+    }
 
-    index
+    let (words_artist, words_album, words_track) = index.word_index_sizes();
+    eprintln!("Artist word index: {}", words_artist);
+    eprintln!("Album word index:  {}", words_album);
+    eprintln!("Track word index:  {}", words_track);
+    eprintln!("Index file size:   {} bytes", index.size_bytes());
 }
 
 enum GenThumb {
@@ -576,14 +1138,9 @@ impl GenThumb {
             return Ok(None)
         }
 
-        let opts = claxon::FlacReaderOptions {
-            metadata_only: true,
-            read_picture: claxon::ReadPicture::CoverAsVec,
-            read_vorbis_comment: false,
-        };
-        let reader = claxon::FlacReader::open_ext(filename, opts)?;
-
-        let cover = match reader.into_pictures().pop() {
+        // Use the same cover as the cover endpoint, so the full-resolution
+        // image matches the thumbnail.
+        let cover = match cover::read_cover(filename)? {
             Some(c) => c,
             None => return Ok(None),
         };
@@ -637,7 +1194,7 @@ impl GenThumb {
 
         {
             let stdin = convert.stdin.as_mut().expect("Failed to open stdin.");
-            stdin.write_all(cover.data()).unwrap();
+            stdin.write_all(&cover.data).unwrap();
         }
 
         let result = GenThumb::Resizing {
@@ -750,15 +1307,15 @@ impl<'a> GenThumbs<'a> {
 }
 
 
-fn generate_thumbnails(index: &MemoryMetaIndex, cache_dir: &Path) {
+fn generate_thumbnails(index: &dyn MetaIndex, cache_dir: &Path) {
     let max_parallelism = 32;
     let mut gen_thumbs = GenThumbs::new(cache_dir, max_parallelism);
     let mut prev_album_id = AlbumId(0);
-    for &(_tid, ref track) in index.get_tracks() {
-        if track.album_id != prev_album_id {
+    for &(tid, ref track) in index.get_tracks() {
+        if tid.album_id() != prev_album_id {
             let fname = index.get_filename(track.filename);
-            gen_thumbs.add(track.album_id, fname).expect("Failed to start thumbnail generation.");
-            prev_album_id = track.album_id;
+            gen_thumbs.add(tid.album_id(), fname).expect("Failed to start thumbnail generation.");
+            prev_album_id = tid.album_id();
         }
     }
     gen_thumbs.drain();
@@ -775,7 +1332,7 @@ fn equals_normalized(x1: &str, x2: &str) -> bool {
 }
 
 fn match_listens(
-    index: &MemoryMetaIndex,
+    index: &dyn MetaIndex,
     in_path: String,
     out_path: String,
 ) -> io::Result<()> {
@@ -812,7 +1369,7 @@ fn match_listens(
 
         for track_id in tracks {
             let track = index.get_track(track_id).expect("Search result should be in index.");
-            let album = index.get_album(track.album_id).expect("Track album should be in index.");
+            let album = index.get_album(track_id.album_id()).expect("Track album should be in index.");
             let track_ok = equals_normalized(index.get_string(track.title), track_title);
             let artist_ok = equals_normalized(index.get_string(track.artist), artist_name);
             let album_ok = equals_normalized(index.get_string(album.title), album_name);
@@ -848,15 +1405,103 @@ fn match_listens(
     Ok(())
 }
 
+/// Output format for `musium check`.
+enum ReportFormat {
+    Json,
+    Tsv,
+}
+
+/// Options for `musium check`, to select the issues to report.
+struct CheckOptions {
+    format: ReportFormat,
+    /// `Some(true)` to report only errors, `Some(false)` for only warnings.
+    severity: Option<bool>,
+    kinds: Vec<String>,
+}
+
+impl CheckOptions {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<CheckOptions, String> {
+        let mut options = CheckOptions {
+            format: ReportFormat::Tsv,
+            severity: None,
+            kinds: Vec::new(),
+        };
+        while let Some(arg) = args.next() {
+            let value = match args.next() {
+                Some(v) => v,
+                None => return Err(format!("Option '{}' needs a value.", arg)),
+            };
+            match (&arg[..], &value[..]) {
+                ("--format", "json") => options.format = ReportFormat::Json,
+                ("--format", "tsv") => options.format = ReportFormat::Tsv,
+                ("--severity", "error") => options.severity = Some(true),
+                ("--severity", "warning") => options.severity = Some(false),
+                ("--kind", _) if IssueDetail::KINDS.contains(&&value[..]) => options.kinds.push(value),
+                ("--format", _) | ("--severity", _) | ("--kind", _) => {
+                    return Err(format!("Invalid value '{}' for option '{}'.", value, arg))
+                }
+                _ => return Err(format!("Unknown option '{}'.", arg)),
+            }
+        }
+        Ok(options)
+    }
+
+    fn matches(&self, issue: &Issue) -> bool {
+        let severity_ok = self.severity.map_or(true, |is_error| issue.detail.is_error() == is_error);
+        let kind_ok = self.kinds.is_empty() || self.kinds.iter().any(|k| k == issue.detail.kind());
+        severity_ok && kind_ok
+    }
+}
+
+/// Replace tabs and newlines, so the value fits in a single tsv field.
+fn tsv_escape(value: &str) -> String {
+    value.replace(|ch: char| ch == '\t' || ch == '\n' || ch == '\r', " ")
+}
+
+/// Write the selected issues to `out`, one per line.
+///
+/// Returns whether any of the reported issues is an error.
+fn check_library<W: Write>(issues: &[Issue], options: &CheckOptions, mut out: W) -> io::Result<bool> {
+    let mut has_errors = false;
+    if let ReportFormat::Tsv = options.format {
+        writeln!(out, "file\tseverity\tkind\tfield\tdetails")?;
+    }
+    for issue in issues.iter().filter(|issue| options.matches(issue)) {
+        has_errors = has_errors || issue.detail.is_error();
+        match options.format {
+            ReportFormat::Json => serialization::write_issue_json(&mut out, issue)?,
+            ReportFormat::Tsv => write!(
+                out,
+                "{}\t{}\t{}\t{}\t{}",
+                tsv_escape(&issue.filename),
+                if issue.detail.is_error() { "error" } else { "warning" },
+                issue.detail.kind(),
+                issue.detail.field().unwrap_or(""),
+                tsv_escape(&issue.detail.to_string()),
+            )?,
+        }
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(has_errors)
+}
+
 fn print_usage() {
     println!("Usage:\n");
     println!("  musium serve musium.conf");
+    println!("  musium scan musium.conf");
     println!("  musium cache musium.conf");
+    println!("  musium check musium.conf [--format json|tsv] [--severity error|warning] [--kind KIND]...");
     println!("  musium match musium.conf listenbrainz.tsv matched.tsv");
+    println!("  musium devices");
     println!("
-serve -- Start the server.
-cache -- Generate album art thumbnails.
-match -- Match listens (see process_listens.py) to tracks.");
+serve   -- Start the server.
+scan    -- Rescan the library and rewrite the index.
+cache   -- Generate album art thumbnails.
+check   -- Scan the library and report issues. Exits with 1 if any are errors.
+           Does not write the index, run 'scan' for that.
+match   -- Match listens (see process_listens.py) to tracks.
+devices -- List audio devices and volume controls.");
 }
 
 fn load_config(config_fname: &str) -> error::Result<Config> {
@@ -867,6 +1512,16 @@ fn load_config(config_fname: &str) -> error::Result<Config> {
 }
 
 fn main() {
+    // Listing devices does not need a config file, so handle it before we
+    // load one.
+    if env::args().nth(1).as_ref().map(|cmd| &cmd[..]) == Some("devices") {
+        if let Err(err) = musium::playback::print_devices() {
+            println!("Failed to list devices: {}", err);
+            process::exit(1);
+        }
+        return
+    }
+
     if env::args().len() < 3 {
         print_usage();
         process::exit(1);
@@ -875,42 +1530,78 @@ fn main() {
     let cmd = env::args().nth(1).unwrap();
     let config_path = env::args().nth(2).unwrap();
     let config = load_config(&config_path).unwrap();
-    println!("Configuration:\n{}\n", config);
+    eprintln!("Configuration:\n{}\n", config);
 
     match &cmd[..] {
         "serve" => {
-            let index = make_index(&config.library_path);
-            let arc_index = std::sync::Arc::new(index);
+            let scan_status = Mutex::new(ScanStatus::new());
+            let index = make_index_or_exit(&config, false, &scan_status);
             println!("Indexing complete.");
-            println!("Loading cover art thumbnails ...");
-
-            let thumb_cache = ThumbCache::new(
-                arc_index.get_album_ids_ordered_by_artist(),
-                &config.covers_path,
-            ).expect("Failed to load cover art thumbnails.");
-            println!("Thumb cache size: {}", thumb_cache.size());
+            let library = match Library::load(&config, index) {
+                Ok(library) => library,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            };
+            scan_status.lock().unwrap().stage = ScanStage::Done;
 
             println!("Starting server on {}.", config.listen);
 
+            // Block the termination signals before we spawn any threads, so
+            // they inherit the signal mask, and the signals are only delivered
+            // when we wait for them in `serve`.
+            let mut signals = SigSet::empty();
+            signals.add(Signal::SIGINT);
+            signals.add(Signal::SIGTERM);
+            signals.thread_block().expect("Failed to block signals.");
+
             let mut db_path = config.data_path.clone();
             db_path.push("musium.sqlite3");
             let player = musium::player::Player::new(
-                arc_index.clone(),
-                config.audio_device,
-                config.audio_volume_control,
+                library.index.clone(),
+                config.audio_device.clone(),
+                config.audio_volume_control.clone(),
                 db_path,
             );
-            let service = MetaServer::new(arc_index.clone(), thumb_cache, player);
-            serve(&config.listen, Arc::new(service));
+            let listen = config.listen.clone();
+            let service = MetaServer::new(config, library, player, scan_status);
+            serve(&listen, Arc::new(service), signals);
+        }
+        "scan" => {
+            make_index_or_exit(&config, true, &Mutex::new(ScanStatus::new()));
         }
         "cache" => {
-            let index = make_index(&config.library_path);
+            let index = make_index_or_exit(&config, false, &Mutex::new(ScanStatus::new()));
             generate_thumbnails(&index, &config.covers_path);
         }
+        "check" => {
+            let options = match CheckOptions::parse(env::args().skip(3)) {
+                Ok(options) => options,
+                Err(msg) => {
+                    eprintln!("{}", msg);
+                    print_usage();
+                    process::exit(1);
+                }
+            };
+            let issues = match scan_issues(&config) {
+                Ok(issues) => issues,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            };
+            let stdout = io::stdout();
+            let has_errors = check_library(&issues[..], &options, stdout.lock())
+                .expect("Failed to write report.");
+            if has_errors {
+                process::exit(1);
+            }
+        }
         "match" => {
             let in_path = env::args().nth(3).unwrap();
             let out_path = env::args().nth(4).unwrap();
-            let index = make_index(&config.library_path);
+            let index = make_index_or_exit(&config, false, &Mutex::new(ScanStatus::new()));
             match_listens(&index, in_path, out_path).unwrap();
         }
         _ => {
@@ -919,3 +1610,58 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use musium::IssueDetail;
+    use super::{CheckOptions, ReportFormat, check_library};
+
+    fn parse(args: &[&str]) -> Result<CheckOptions, String> {
+        CheckOptions::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn check_options_parse_accepts_known_values() {
+        let options = parse(&["--format", "json", "--severity", "error", "--kind", "not_stereo"]).unwrap();
+        assert!(match options.format { ReportFormat::Json => true, _ => false });
+        assert_eq!(options.severity, Some(true));
+        assert_eq!(options.kinds, vec!["not_stereo".to_string()]);
+    }
+
+    #[test]
+    fn check_options_parse_rejects_invalid_values() {
+        assert!(parse(&["--format", "xml"]).is_err());
+        assert!(parse(&["--severity", "fatal"]).is_err());
+        assert!(parse(&["--kind", "not-stereo"]).is_err());
+        assert!(parse(&["--kind"]).is_err());
+        assert!(parse(&["--verbose", "true"]).is_err());
+    }
+
+    #[test]
+    fn check_library_reports_selected_issues() {
+        let issues = vec![
+            IssueDetail::TrackTitleContainsFeat.for_file("/music/a.flac".into()),
+            IssueDetail::NotStereo.for_file("/music/b\tc.flac".into()),
+        ];
+
+        let options = parse(&["--severity", "warning"]).unwrap();
+        let mut out = Vec::new();
+        let has_errors = check_library(&issues, &options, &mut out).unwrap();
+        assert_eq!(has_errors, false);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "file\tseverity\tkind\tfield\tdetails\n\
+             /music/a.flac\twarning\ttrack_title_contains_feat\ttitle\ttrack title contains '(feat. '.\n",
+        );
+
+        let options = parse(&["--kind", "not_stereo"]).unwrap();
+        let mut out = Vec::new();
+        let has_errors = check_library(&issues, &options, &mut out).unwrap();
+        assert_eq!(has_errors, true);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "file\tseverity\tkind\tfield\tdetails\n\
+             /music/b c.flac\terror\tnot_stereo\t\tthe file is not stereo\n",
+        );
+    }
+}
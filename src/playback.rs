@@ -7,20 +7,88 @@
 
 //! Logic for playing back audio using Alsa.
 
+use std::fmt;
+use std::fs;
+use std::io::Write;
 use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::result;
 use std::sync::Mutex;
 use std::thread::Thread;
 use std::thread;
+use std::time::Duration;
 use std::ffi::CString;
 
 use alsa;
 use alsa::PollDescriptors;
 use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::unistd;
 
-use crate::player::{Format, Millibel, PlayerState};
+use crate::player::{DeviceStatus, Format, Millibel, PlayerState};
 
-type Result<T> = result::Result<T, alsa::Error>;
+#[derive(Debug)]
+pub enum Error {
+    /// There is no card with the given name, it may be unplugged.
+    CardNotFound(String),
+
+    /// No card was configured, and none of the available ones can be used.
+    NoCardAvailable,
+
+    /// The card has no simple mixer control with the given name.
+    VolumeControlNotFound(String),
+
+    /// The device disappeared while we were playing.
+    Disconnected,
+
+    /// An Alsa call failed.
+    Alsa(alsa::Error),
+}
+
+impl From<alsa::Error> for Error {
+    fn from(err: alsa::Error) -> Error {
+        Error::Alsa(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::CardNotFound(ref name) =>
+                write!(f, "Could not find a card with name '{}'.", name),
+            Error::NoCardAvailable =>
+                write!(f, "Could not find a card that has the volume control."),
+            Error::VolumeControlNotFound(ref name) =>
+                write!(f, "Could not find a volume control with name '{}'.", name),
+            Error::Disconnected =>
+                write!(f, "The audio device was disconnected."),
+            Error::Alsa(ref err) =>
+                write!(f, "Alsa error: {}", err),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+fn print_card_info(card: &alsa::Card) -> Result<()> {
+    println!("Name:       {}", card.get_name()?);
+    println!("Long name:  {}", card.get_longname()?);
+
+    let non_block = false;
+    let ctl = alsa::ctl::Ctl::from_card(card, non_block)?;
+    let info = ctl.card_info()?;
+    println!("Card id:    {}", info.get_id()?);
+    println!("Driver:     {}", info.get_driver()?);
+    println!("Components: {}", info.get_components()?);
+    println!("Mixer name: {}", info.get_mixername()?);
+
+    Ok(())
+}
+
+fn print_no_cards_found() {
+    println!("No cards found.");
+    println!("You may need to be a member of the 'audio' group.");
+}
 
 fn print_available_cards() -> Result<()> {
     let cards = alsa::card::Iter::new();
@@ -32,49 +100,196 @@ fn print_available_cards() -> Result<()> {
         }
 
         let card = res_card?;
-        println!("Name:       {}", card.get_name()?);
-        println!("Long name:  {}", card.get_longname()?);
+        print_card_info(&card)?;
+
+        found_any = true;
+    }
+
+    if !found_any {
+        print_no_cards_found();
+    }
+
+    Ok(())
+}
+
+/// Print the playback PCM devices of the card, as reported by Alsa's hints.
+fn print_card_pcms(card: &alsa::Card) -> Result<()> {
+    println!("PCM devices:");
+    for hint in alsa::device_name::HintIter::new_str(Some(card), "pcm")? {
+        match hint.direction {
+            Some(alsa::Direction::Capture) => continue,
+            _ => {}
+        }
+        if let Some(name) = hint.name {
+            println!("  {}", name);
+        }
+    }
+    Ok(())
+}
+
+/// Print the sample rates and formats that the hardware supports natively.
+///
+/// We query the "hw" device rather than the "plug" device that we play on,
+/// because the plug device supports everything through conversion.
+fn print_card_formats(card: &alsa::Card) -> Result<()> {
+    let device = format!("hw:{}", card.get_index());
+    let non_block = true;
+    let pcm = match alsa::PCM::new(&device, alsa::Direction::Playback, non_block) {
+        Ok(pcm) => pcm,
+        Err(err) => {
+            println!("Formats:    unknown, could not open {}: {}", device, err);
+            return Ok(())
+        }
+    };
+    let hwp = alsa::pcm::HwParams::any(&pcm)?;
+
+    let rates = [44_100, 48_000, 88_200, 96_000, 176_400, 192_000];
+    let supported_rates: Vec<String> = rates
+        .iter()
+        .filter(|&&hz| hwp.test_rate(hz).is_ok())
+        .map(|hz| hz.to_string())
+        .collect();
+
+    // Musium plays 16-bit and 24-bit (in 3 bytes) samples, the other formats
+    // are listed because they can be a reason to prefer one card over another.
+    let formats = [
+        (alsa::pcm::Format::S16LE, "S16_LE"),
+        (alsa::pcm::Format::S243LE, "S24_3LE"),
+        (alsa::pcm::Format::S24LE, "S24_LE"),
+        (alsa::pcm::Format::S32LE, "S32_LE"),
+    ];
+    let supported_formats: Vec<&str> = formats
+        .iter()
+        .filter(|&&(format, _)| hwp.test_format(format).is_ok())
+        .map(|&(_, name)| name)
+        .collect();
+
+    println!("Channels:   {} to {}", hwp.get_channels_min()?, hwp.get_channels_max()?);
+    println!("Rates:      {}", supported_rates.join(", "));
+    println!("Formats:    {}", supported_formats.join(", "));
+
+    Ok(())
+}
+
+/// Print the mixer controls that can be used as `audio_volume_control`.
+fn print_card_volume_controls(card: &alsa::Card) -> Result<()> {
+    let device = format!("hw:{}", card.get_index());
+    let non_block = false;
+    let mixer = match alsa::Mixer::new(&device, non_block) {
+        Ok(mixer) => mixer,
+        Err(err) => {
+            println!("Volume controls: unknown, could not open {}: {}", device, err);
+            return Ok(())
+        }
+    };
+
+    println!("Volume controls:");
+    for elem in mixer.iter() {
+        let selem = match alsa::mixer::Selem::new(elem) {
+            Some(s) => s,
+            None => continue,
+        };
+        if !selem.has_playback_volume() {
+            continue
+        }
+        let (min, max) = selem.get_playback_db_range();
+        println!(
+            "  {} ({:.1} dB to {:.1} dB)",
+            selem.get_id().get_name()?,
+            min.0 as f32 * 0.01,
+            max.0 as f32 * 0.01,
+        );
+    }
+
+    Ok(())
+}
+
+fn print_card(card: &alsa::Card) -> Result<()> {
+    print_card_info(card)?;
+    print_card_formats(card)?;
+    print_card_pcms(card)?;
+    print_card_volume_controls(card)
+}
+
+/// Print all cards, with their devices, supported formats, and mixer controls.
+///
+/// This is used by the `devices` subcommand, to help fill out the
+/// `audio_device` and `audio_volume_control` configuration keys.
+pub fn print_devices() -> Result<()> {
+    let cards = alsa::card::Iter::new();
+    let mut found_any = false;
 
-        let non_block = false;
-        let ctl = alsa::ctl::Ctl::from_card(&card, non_block)?;
-        let info = ctl.card_info()?;
-        println!("Card id:    {}", info.get_id()?);
-        println!("Driver:     {}", info.get_driver()?);
-        println!("Components: {}", info.get_components()?);
-        println!("Mixer name: {}", info.get_mixername()?);
+    for res_card in cards {
+        if found_any {
+            println!();
+        }
 
         found_any = true;
+
+        // One broken card should not hide the others, so print the error and
+        // move on to the next card.
+        let card = match res_card {
+            Ok(card) => card,
+            Err(err) => {
+                println!("Failed to open card: {}", err);
+                continue
+            }
+        };
+        if let Err(err) = print_card(&card) {
+            println!("Failed to query card {}: {}", card.get_index(), err);
+        }
     }
 
     if !found_any {
-        println!("No cards found.");
-        println!("You may need to be a member of the 'audio' group.");
+        print_no_cards_found();
     }
 
     Ok(())
 }
 
-fn open_device(card_name: &str) -> Result<(alsa::PCM, alsa::Mixer)> {
+fn find_card_index(card_name: &str) -> Result<i32> {
     let cards = alsa::card::Iter::new();
-    let mut opt_card_index = None;
 
     for res_card in cards {
         let card = res_card?;
         if card.get_name()? == card_name {
-            opt_card_index = Some(card.get_index());
+            return Ok(card.get_index())
         }
     }
 
-    let card_index = match opt_card_index {
-        Some(i) => i,
-        None => {
-            println!("Could not find a card with name '{}'.", card_name);
-            println!("Valid options:\n");
-            print_available_cards()?;
-            panic!("TODO: Add a better error handler.");
+    Err(Error::CardNotFound(card_name.to_string()))
+}
+
+/// Open the first card that can be opened and has the volume control.
+fn open_first_card(volume_name: &str) -> Result<(alsa::PCM, alsa::Mixer)> {
+    let cards = alsa::card::Iter::new();
+
+    for res_card in cards {
+        let card = res_card?;
+        match open_card(card.get_index()) {
+            Ok((pcm, mixer)) => {
+                if get_volume_control(&mixer, volume_name).is_ok() {
+                    println!("Using card '{}'.", card.get_name()?);
+                    return Ok((pcm, mixer))
+                }
+            }
+            Err(err) => println!("Skipping card {}: {}", card.get_index(), err),
         }
-    };
+    }
+
+    Err(Error::NoCardAvailable)
+}
+
+/// Open the configured card, or the first usable one if none is configured.
+fn open_device(card_name: Option<&str>, volume_name: &str) -> Result<(alsa::PCM, alsa::Mixer)> {
+    match card_name {
+        Some(name) => open_card(find_card_index(name)?),
+        None => open_first_card(volume_name),
+    }
+}
 
+/// Open the PCM and mixer of the card with the given index.
+fn open_card(card_index: i32) -> Result<(alsa::PCM, alsa::Mixer)> {
     // Select the card by index (":{}") to get direct access to the hardware,
     // play back stereo on the front two speakers. Adding "plug:" in front makes
     // Alsa take care of conversions where needed. This is bad on the one hand,
@@ -91,9 +306,9 @@ fn open_device(card_name: &str) -> Result<(alsa::PCM, alsa::Mixer)> {
         Ok(pcm) => pcm,
         Err(error) if error.errno() == Some(Errno::EBUSY) => {
             println!("Could not open audio interface for exclusive access, it is already use.");
-            return Err(error);
+            return Err(Error::Alsa(error));
         }
-        Err(error) => return Err(error),
+        Err(error) => return Err(Error::Alsa(error)),
     };
 
     let device = format!("hw:{}", card_index);
@@ -103,12 +318,13 @@ fn open_device(card_name: &str) -> Result<(alsa::PCM, alsa::Mixer)> {
     Ok((pcm, mixer))
 }
 
-fn get_volume_control<'a>(mixer: &'a alsa::Mixer, name: &str) -> Option<alsa::mixer::Selem<'a>> {
+fn get_volume_control<'a>(mixer: &'a alsa::Mixer, name: &str) -> Result<alsa::mixer::Selem<'a>> {
     let mut selem_id = alsa::mixer::SelemId::empty();
     selem_id.set_name(&CString::new(name).expect("Invalid volume control name."));
-    let selem = mixer.find_selem(&selem_id)?;
-    assert!(selem.has_playback_volume());
-    Some(selem)
+    match mixer.find_selem(&selem_id) {
+        Some(selem) if selem.has_playback_volume() => Ok(selem),
+        _ => Err(Error::VolumeControlNotFound(name.to_string())),
+    }
 }
 
 fn set_format(pcm: &alsa::PCM, format: Format) -> Result<()> {
@@ -189,6 +405,13 @@ fn write_samples(
     } as usize;
 
     if n_available > 0 {
+        // Query how many frames the device still has to play before anything
+        // that we write now becomes audible. This is what makes the playback
+        // position and event times match what comes out of the speakers. When
+        // the device is not running (e.g. in the setup state, before the first
+        // write), the query can fail, but then nothing is pending either.
+        let delay_frames = pcm.delay().unwrap_or(0).max(0) as u64;
+
         n_consumed = match player.peek_mut() {
             Some(ref block) if current_format != block.format() => {
                 // Next block has a different sample rate or bit depth, finish
@@ -215,7 +438,8 @@ fn write_samples(
         };
 
         if n_consumed > 0 {
-            player.consume(n_consumed);
+            let num_channels = 2;
+            player.consume(n_consumed, delay_frames * num_channels);
         } else if player.is_queue_empty() {
             // The queue is empty, play what is still there, then stop.
             pcm.drain()?;
@@ -241,6 +465,7 @@ fn write_samples(
         State::Prepared => return Ok(WriteResult::Yield),
         State::XRun => pcm.prepare()?,
         State::Suspended => pcm.resume()?,
+        State::Disconnected => return Err(Error::Disconnected),
         unexpected => panic!("Unexpected PCM state: {:?}", unexpected),
     };
     Ok(WriteResult::NeedMore)
@@ -257,18 +482,71 @@ fn ensure_buffers_full(
     format: Format,
     io: &mut alsa::pcm::IO<u8>,
     player: &mut PlayerState,
-) -> FillResult {
+) -> Result<FillResult> {
+    // Most write errors are transient (an underrun for example), and we can
+    // resume after them. But when the device is gone, every write fails, so
+    // after a number of consecutive failures we give up, and let the caller
+    // reopen the device.
+    let max_errors = 10;
+    let mut n_errors = 0;
+
     loop {
         match write_samples(device, format, io, player) {
-            Err(err) => {
-                println!("Error while writing samples: {:?}", err);
+            Err(Error::Disconnected) => return Err(Error::Disconnected),
+            Err(err) if n_errors < max_errors => {
+                println!("Error while writing samples: {}", err);
                 println!("Resuming ...");
+                n_errors += 1;
                 continue
             }
-            Ok(WriteResult::NeedMore) => continue,
-            Ok(WriteResult::ChangeFormat(new_format)) => return FillResult::ChangeFormat(new_format),
-            Ok(WriteResult::Yield) => return FillResult::Yield,
-            Ok(WriteResult::QueueEmpty) => return FillResult::QueueEmpty,
+            Err(err) => return Err(err),
+            Ok(WriteResult::NeedMore) => {
+                n_errors = 0;
+                continue
+            }
+            Ok(WriteResult::ChangeFormat(new_format)) => return Ok(FillResult::ChangeFormat(new_format)),
+            Ok(WriteResult::Yield) => return Ok(FillResult::Yield),
+            Ok(WriteResult::QueueEmpty) => return Ok(FillResult::QueueEmpty),
+        }
+    }
+}
+
+/// A pipe to wake the playback thread while it waits for the audio device.
+///
+/// The playback thread polls the read end together with the Alsa descriptors,
+/// so when we write to the pipe on shutdown, it notices immediately, and we can
+/// still sleep long between buffer refills the rest of the time.
+pub struct Waker {
+    read: fs::File,
+    write: fs::File,
+}
+
+impl Waker {
+    pub fn new() -> nix::Result<Waker> {
+        let (read_fd, write_fd) = unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+        // This is safe, because we just created the fds, and nothing else owns
+        // them. The files close them when dropped.
+        let waker = unsafe {
+            Waker {
+                read: fs::File::from_raw_fd(read_fd),
+                write: fs::File::from_raw_fd(write_fd),
+            }
+        };
+        Ok(waker)
+    }
+
+    /// Make the poll in the playback thread return.
+    pub fn wake(&self) {
+        // We never read from the pipe, so after the first wake it stays
+        // readable. If it is full, the poll returns anyway, so ignore errors.
+        let _ = (&self.write).write(&[1]);
+    }
+
+    fn pollfd(&self) -> alsa::poll::pollfd {
+        alsa::poll::pollfd {
+            fd: self.read.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
         }
     }
 }
@@ -277,23 +555,29 @@ fn ensure_buffers_full(
 ///
 /// When the queue becomes empty, this function returns, and the Alsa device is
 /// released. An outer loop can call it again once there is new content in the
-/// queue.
+/// queue. When the device fails, this function returns the error. Anything that
+/// is still in the queue stays there, so playback can resume later.
 fn play_queue(
-    card_name: &str,
+    card_name: Option<&str>,
     volume_name: &str,
     state_mutex: &Mutex<PlayerState>,
     decode_thread: &Thread,
-) {
-    let (device, mixer) = open_device(card_name).expect("TODO: Failed to open device.");
-    let vc = get_volume_control(&mixer, volume_name).expect("TODO: Failed to get volume control.");
-    let mut fds = device.get().expect("TODO: Failed to get fds from device.");
+    waker: &Waker,
+) -> Result<()> {
+    let (device, mixer) = open_device(card_name, volume_name)?;
+    let vc = get_volume_control(&mixer, volume_name)?;
+    let mut fds = device.get()?;
+    let n_device_fds = fds.len();
+    fds.push(waker.pollfd());
 
     let mut volume = None;
     let mut format = Format {
         sample_rate_hz: 44_100,
         bits_per_sample: 16,
     };
-    set_format(&device, format).expect("TODO: Failed to set format.");
+    set_format(&device, format)?;
+
+    state_mutex.lock().unwrap().set_device_status(DeviceStatus::Open);
 
     // There is also "direct mode" that works with mmaps, but it is not
     // supported by the kernel on ARM, and I want to run this on a Raspberry Pi,
@@ -301,7 +585,7 @@ fn play_queue(
     let mut io = device.io();
 
     loop {
-        let (result, target_volume, needs_decode, pending_ms) = {
+        let (result, target_volume, needs_decode, pending_ms, is_stopping) = {
             let mut state = state_mutex.lock().unwrap();
             let result = ensure_buffers_full(
                 &device,
@@ -315,6 +599,7 @@ fn play_queue(
                 state.target_volume_full_scale(),
                 state.needs_decode(),
                 state.pending_duration_ms(),
+                state.is_stopping(),
             )
         };
 
@@ -325,21 +610,26 @@ fn play_queue(
         if volume != target_volume {
             if let Some(Millibel(v)) = target_volume {
                 println!("Changing volume to {:.1} dB", v as f32 * 0.01);
-                vc.set_playback_db_all(alsa::mixer::MilliBel(v as i64), alsa::Round::Floor)
-                    .expect("Failed to set volume. TODO: Make fn return Alsa error?");
+                vc.set_playback_db_all(alsa::mixer::MilliBel(v as i64), alsa::Round::Floor)?;
                 volume = target_volume;
             }
         }
 
-        match result {
-            FillResult::QueueEmpty => return,
+        if is_stopping {
+            let device_fds = &mut fds[..n_device_fds];
+            return fade_out(&device, format, &mut io, &vc, volume, state_mutex, device_fds);
+        }
+
+        match result? {
+            FillResult::QueueEmpty => return Ok(()),
             FillResult::Yield => {
-                let max_sleep_ms = 5_000.min(pending_ms as i32 / 2);
-                alsa::poll::poll(&mut fds, max_sleep_ms).expect("TODO: Failed to wait for events.");
+                // On shutdown, the waker makes the poll return early.
+                let max_sleep_ms = 5000.min(pending_ms as i32 / 2);
+                alsa::poll::poll(&mut fds, max_sleep_ms)?;
             }
             FillResult::ChangeFormat(new_format) => {
                 mem::drop(io);
-                set_format(&device, new_format).expect("TODO: Failed to set format.");
+                set_format(&device, new_format)?;
                 println!("Changed format to {:?}", new_format);
                 format = new_format;
                 io = device.io();
@@ -348,28 +638,113 @@ fn play_queue(
     }
 }
 
+/// Ramp down the volume while playing for a bit, then stop the device.
+///
+/// Cutting off playback abruptly can cause an audible click, so when we shut
+/// down, I prefer to fade out. The samples in the device buffer that are not
+/// played by the end of the fade are dropped. The listen of the track that was
+/// playing ends when the fade ends.
+fn fade_out(
+    device: &alsa::PCM,
+    format: Format,
+    io: &mut alsa::pcm::IO<u8>,
+    vc: &alsa::mixer::Selem,
+    volume: Option<Millibel>,
+    state_mutex: &Mutex<PlayerState>,
+    fds: &mut [alsa::poll::pollfd],
+) -> Result<()> {
+    // Fade out by 30 dB over 10 steps of 25 ms.
+    let n_steps = 10;
+    let fade_mb = 3_000;
+    let step_ms = 25;
+
+    if let Some(Millibel(v)) = volume {
+        for i in 1..=n_steps {
+            {
+                let mut state = state_mutex.lock().unwrap();
+                if let FillResult::QueueEmpty = ensure_buffers_full(device, format, io, &mut state)? {
+                    break
+                }
+            }
+            let v_step = v as i64 - fade_mb * i / n_steps;
+            vc.set_playback_db_all(alsa::mixer::MilliBel(v_step), alsa::Round::Floor)?;
+            alsa::poll::poll(fds, step_ms)?;
+        }
+    }
+
+    device.drop()?;
+    state_mutex.lock().unwrap().end_current_listen();
+    println!("Playback stopped.");
+    Ok(())
+}
+
 /// Play audio from the queue, then park the thread.
 ///
 /// When the thread that runs this is unparked, check if there is anything in
 /// the queue to play, and if so, open the Alsa device and start playing. When
 /// the queue is empty, the device is released, and the thread parks itself
 /// again.
+///
+/// When the device is unavailable, for example because it was unplugged, we
+/// retry with exponential backoff, until the device reappears and we can
+/// resume playing the queue.
+///
+/// When the player is stopping, playback fades out, and this function returns.
 pub fn main(
-    card_name: &str,
+    card_name: Option<&str>,
     volume_name: &str,
     state_mutex: &Mutex<PlayerState>,
     decode_thread: &Thread,
+    waker: &Waker,
 ) {
+    let min_backoff = Duration::from_secs(1);
+    let max_backoff = Duration::from_secs(30);
+    let mut backoff = min_backoff;
+
     // TODO: Set thread priority to high.
     loop {
-        let has_audio = {
+        let (has_audio, is_stopping) = {
             let state = state_mutex.lock().unwrap();
-            !state.is_queue_empty()
+            (!state.is_queue_empty(), state.is_stopping())
         };
+        if is_stopping {
+            return
+        }
         if has_audio {
             println!("Starting playback ...");
-            play_queue(card_name, volume_name, state_mutex, decode_thread);
-            println!("Playback done, sleeping ...");
+            match play_queue(card_name, volume_name, state_mutex, decode_thread, waker) {
+                Ok(()) => {
+                    state_mutex.lock().unwrap().set_device_status(DeviceStatus::Idle);
+                    backoff = min_backoff;
+                    println!("Playback done, sleeping ...");
+                }
+                Err(err) => {
+                    println!("Audio device unavailable: {}", err);
+                    // List the alternatives, but only on the first failure,
+                    // to not flood the log while the device is unplugged.
+                    let is_first_failure = backoff == min_backoff;
+                    match err {
+                        Error::CardNotFound(..) | Error::NoCardAvailable if is_first_failure => {
+                            println!("Valid options:\n");
+                            if let Err(err) = print_available_cards() {
+                                println!("Failed to list cards: {}", err);
+                            }
+                        }
+                        _ => {}
+                    }
+                    let status = DeviceStatus::Unavailable(err.to_string());
+                    state_mutex.lock().unwrap().set_device_status(status);
+                    println!("Retrying in {} seconds ...", backoff.as_secs());
+                    thread::park_timeout(backoff);
+                    backoff = (backoff * 2).min(max_backoff);
+                    continue
+                }
+            }
+        } else if backoff != min_backoff {
+            // The queue emptied while we were waiting for the device to come
+            // back, there is nothing to play, so the device is no longer in use.
+            state_mutex.lock().unwrap().set_device_status(DeviceStatus::Idle);
+            backoff = min_backoff;
         }
         thread::park();
     }
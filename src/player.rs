@@ -13,9 +13,11 @@ use std::mem;
 use std::path::PathBuf;
 use std::sync::mpsc::SyncSender;
 use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::thread;
+use std::time::Instant;
 
 use claxon;
 use claxon::metadata::StreamInfo;
@@ -23,10 +25,14 @@ use claxon::metadata::StreamInfo;
 use crate::history::PlaybackEvent;
 use crate::history;
 use crate::playback;
+use crate::swap::Swap;
 use crate::{Lufs, MetaIndex, TrackId};
 
 type FlacReader = claxon::FlacReader<fs::File>;
 
+/// The index shared by the player threads, it can be replaced on reindex.
+type SharedIndex = Swap<dyn MetaIndex + Send + Sync>;
+
 /// A unique identifier for a queued track.
 ///
 /// This identifier is used to track the queued track through its lifetimes
@@ -56,6 +62,20 @@ impl fmt::Display for Millibel {
     }
 }
 
+/// Whether the playback thread can use the audio device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DeviceStatus {
+    /// The device is not in use, because there is nothing to play.
+    Idle,
+
+    /// The device is open and we are playing.
+    Open,
+
+    /// Opening or writing to the device failed, we will retry periodically.
+    /// Contains a description of the failure.
+    Unavailable(String),
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Format {
     pub sample_rate_hz: u32,
@@ -191,8 +211,14 @@ impl QueuedTrack {
         self.blocks.iter().map(|b| b.duration_ms()).sum()
     }
 
-    /// Return the duration of the consumed samples in milliseconds.
-    pub fn position_ms(&self) -> u64 {
+    /// Return the duration of the audible consumed samples in milliseconds.
+    ///
+    /// Samples that were sent to the audio device are not audible immediately,
+    /// the device first plays what is still in its buffer. `unheard_samples` is
+    /// the number of samples that the device has yet to play; those are not
+    /// counted towards the position.
+    pub fn position_ms(&self, unheard_samples: u64) -> u64 {
+        let samples_heard = self.samples_played.saturating_sub(unheard_samples);
         match self.sample_rate_hz {
             // Multiply by 1000 to go from seconds to milliseconds, divide by 2
             // because there are 2 channels. We need to work with u64 here, because
@@ -200,7 +226,7 @@ impl QueuedTrack {
             // overflows a u32 (and usize can be 32 bits). We can't move the 500
             // into the denominator, because the common sample rate of 44.1 kHz
             // is not a multiple of 500.
-            Some(hz) => self.samples_played * 500 / (hz as u64),
+            Some(hz) => samples_heard * 500 / (hz as u64),
             // When the sample rate is not known, we definitely have not started
             // playback.
             None => 0
@@ -430,12 +456,59 @@ pub struct PlayerState {
     /// meantime, so we need to track the index of where to restore later.
     current_decode: Option<usize>,
 
+    /// The samples sent to the audio device that it had not played yet.
+    ///
+    /// This is updated by the playback thread whenever it writes samples, and
+    /// it is used to correct the playback position for the device latency.
+    device_delay: DeviceDelay,
+
+    /// Whether the audio device is available, as last observed by the playback thread.
+    device_status: DeviceStatus,
+
+    /// Whether the player is shutting down.
+    ///
+    /// When this is set, the decode and playback threads should exit.
+    is_stopping: bool,
+
     /// Sender for playback events.
     ///
     /// These events get consumed by the history thread, who logs them.
     events: SyncSender<PlaybackEvent>,
 }
 
+/// The samples pending in the audio device, as measured after a write.
+#[derive(Copy, Clone, Debug)]
+struct DeviceDelay {
+    /// Number of samples that the device had yet to play.
+    samples: u64,
+
+    /// The sample rate that the device plays those samples at.
+    sample_rate_hz: u32,
+
+    /// When we measured the delay.
+    measured_at: Instant,
+}
+
+impl DeviceDelay {
+    /// Estimate how many of the samples the device has yet to play at `now`.
+    ///
+    /// The device keeps playing after we measure, so by the time someone asks
+    /// for the playback position, it played part of the pending samples.
+    fn pending_samples_at(&self, now: Instant) -> u64 {
+        if self.sample_rate_hz == 0 { return 0 }
+        let elapsed_ms = now.saturating_duration_since(self.measured_at).as_millis() as u64;
+        // See `QueuedTrack::position_ms` for why we divide by 500.
+        let samples_played = elapsed_ms * self.sample_rate_hz as u64 / 500;
+        self.samples.saturating_sub(samples_played)
+    }
+}
+
+/// Return the time it takes to play `samples` stereo samples at the given rate.
+fn samples_duration(samples: u64, sample_rate_hz: u32) -> chrono::Duration {
+    // See `QueuedTrack::position_ms` for why we multiply by 500.
+    chrono::Duration::milliseconds((samples * 500 / sample_rate_hz as u64) as i64)
+}
+
 
 impl PlayerState {
     pub fn new(events: SyncSender<PlaybackEvent>) -> PlayerState {
@@ -445,6 +518,13 @@ impl PlayerState {
             target_loudness: Lufs::new(-2300),
             queue: Vec::new(),
             current_decode: None,
+            device_delay: DeviceDelay {
+                samples: 0,
+                sample_rate_hz: 0,
+                measured_at: Instant::now(),
+            },
+            device_status: DeviceStatus::Idle,
+            is_stopping: false,
             events: events,
         }
     }
@@ -487,6 +567,31 @@ impl PlayerState {
         self.queue.is_empty()
     }
 
+    /// Return whether the decode and playback threads should exit.
+    pub fn is_stopping(&self) -> bool {
+        self.is_stopping
+    }
+
+    /// End the listen of the track that is playing, if any, as of now.
+    ///
+    /// This is used on shutdown, after playback faded out. The track did not
+    /// finish, but we do record when it stopped, so the history does not keep
+    /// a listen without end time.
+    pub fn end_current_listen(&mut self) {
+        if let Some(queued_track) = self.queue.first() {
+            if queued_track.samples_played > 0 {
+                let now = chrono::Utc::now();
+                self.events.send(PlaybackEvent::Completed(queued_track.queue_id, queued_track.track_id, now))
+                    .expect("Failed to send completion event to history thread.");
+            }
+        }
+    }
+
+    /// Record whether the audio device is available.
+    pub fn set_device_status(&mut self, status: DeviceStatus) {
+        self.device_status = status;
+    }
+
     /// Return the desired playback volume relative to full scale.
     ///
     /// This applies loudness normalization on top of the player target volume,
@@ -503,17 +608,36 @@ impl PlayerState {
     }
 
     /// Consume n samples from the peeked block.
-    pub fn consume(&mut self, n: usize) {
+    ///
+    /// The `delay_samples` is the number of samples that were still pending in
+    /// the audio device at the time the n samples were written. We use it to
+    /// time the playback events to when the samples become audible, rather
+    /// than to when we hand them to the device.
+    pub fn consume(&mut self, n: usize, delay_samples: u64) {
         assert!(n > 0, "Must consume at least one sample.");
 
+        let now = chrono::Utc::now();
+        let sample_rate_hz = self.queue[0].blocks[0].format.sample_rate_hz;
+
+        // The pending samples are those of the previous write, which may belong
+        // to the previous track, so they play at the rate of that write. When
+        // the format changes, the playback thread drains the device first.
+        let delay_rate_hz = match self.device_delay.sample_rate_hz {
+            0 => sample_rate_hz,
+            hz => hz,
+        };
+        let delay = samples_duration(delay_samples, delay_rate_hz);
+
         let track_done = {
             let queued_track = &mut self.queue[0];
 
             // If this is the first time that we consume samples from this
-            // track, then that means it was just started.
+            // track, then that means it was just started. It becomes audible
+            // once the device has played everything that is ahead of it.
             if queued_track.samples_played == 0 {
+                let started_at = now + delay;
                 self.events.send(
-                    PlaybackEvent::Started(queued_track.queue_id, queued_track.track_id)
+                    PlaybackEvent::Started(queued_track.queue_id, queued_track.track_id, started_at)
                 ).expect("Failed to send completion event to history thread.");
             }
 
@@ -532,6 +656,14 @@ impl PlayerState {
                 _ => false,
             }
         };
+
+        // The samples we just wrote are now pending in the device too.
+        self.device_delay = DeviceDelay {
+            samples: delay_samples + n as u64,
+            sample_rate_hz: sample_rate_hz,
+            measured_at: Instant::now(),
+        };
+
         if track_done {
             let track = self.queue.remove(0);
             // If a decode is in progress, the index of the track it is decoding
@@ -540,11 +672,14 @@ impl PlayerState {
                 self.current_decode = Some(i - 1);
             }
 
-            self.events.send(PlaybackEvent::Completed(track.queue_id, track.track_id))
+            // The track is complete when its final sample has been played,
+            // which is after the device played everything that is pending.
+            let completed_at = now + delay + samples_duration(n as u64, sample_rate_hz);
+            self.events.send(PlaybackEvent::Completed(track.queue_id, track.track_id, completed_at))
                 .expect("Failed to send completion event to history thread.");
         }
 
-        #[cfg(debug)]
+        #[cfg(debug_assertions)]
         self.assert_invariants();
     }
 
@@ -653,10 +788,40 @@ impl PlayerState {
         };
         self.current_decode = None;
     }
+
+    /// Remove queued tracks that do not exist in the index.
+    ///
+    /// This is used after a reindex, when files may have been removed, or
+    /// retagged such that their track id changed. The track that is playing,
+    /// and the track that is being decoded, are kept: they no longer need the
+    /// index, they have an open reader already.
+    fn remove_missing_tracks(&mut self, index: &dyn MetaIndex) {
+        let mut i = 0;
+        while i < self.queue.len() {
+            let queued_track = &self.queue[i];
+            let is_playing = i == 0 && queued_track.samples_played > 0;
+            let is_decoding = self.current_decode == Some(i);
+            if is_playing || is_decoding || index.get_track(queued_track.track_id).is_some() {
+                i += 1;
+                continue
+            }
+
+            println!("Removing track {} from the queue, it is no longer in the index.", queued_track.track_id);
+            self.queue.remove(i);
+            if let Some(j) = self.current_decode {
+                if j > i {
+                    self.current_decode = Some(j - 1);
+                }
+            }
+        }
+
+        #[cfg(debug_assertions)]
+        self.assert_invariants();
+    }
 }
 
 /// Decode the queue until we reach a set memory limit.
-fn decode_burst(index: &dyn MetaIndex, state_mutex: &Mutex<PlayerState>) {
+fn decode_burst(index_var: &SharedIndex, state_mutex: &Mutex<PlayerState>) {
     // The decode thread is a trade-off between power consumption and memory
     // usage: decoding a lot in one go and then sleeping for a long time is more
     // efficient than decoding a bit all the time, because the CPU can be
@@ -675,13 +840,20 @@ fn decode_burst(index: &dyn MetaIndex, state_mutex: &Mutex<PlayerState>) {
         // Get the latest memory usage, and take the next task to execute. This
         // only holds the mutex briefly, so we can do the decode without holding
         // the mutex.
-        let (task, bytes_used, pending_duration_ms) = {
+        // We take the index snapshot while holding the lock too: the index is
+        // only replaced under the lock, after removing tracks from the queue
+        // that are not in the new index, so the track exists in this snapshot.
+        let (task, index, bytes_used, pending_duration_ms) = {
             let mut state = state_mutex.lock().unwrap();
 
             if let Some(result) = previous_result.take() {
                 state.return_decode_task(result);
             }
 
+            if state.is_stopping() {
+                return
+            }
+
             let bytes_used = state.pending_size_bytes();
             if bytes_used >= stop_after_bytes {
                 println!("Buffer full, stopping decode for now.");
@@ -693,7 +865,7 @@ fn decode_burst(index: &dyn MetaIndex, state_mutex: &Mutex<PlayerState>) {
                 Some(t) => t,
             };
 
-            (task, bytes_used, state.pending_duration_ms())
+            (task, index_var.get(), bytes_used, state.pending_duration_ms())
         };
 
         // If the buffer is running low, then our priority shouldn't be to
@@ -718,7 +890,7 @@ fn decode_burst(index: &dyn MetaIndex, state_mutex: &Mutex<PlayerState>) {
         // to decode as much, because most of the memory is taken up by
         // already-played samples in a large block where the playhead is at the
         // end of the block.
-        let result = task.run(index, bytes_left.min(10_000_000));
+        let result = task.run(&*index, bytes_left.min(10_000_000));
         println!("Decoded {:.3} MB.", result.block.size_bytes() as f32 * 1e-6);
         previous_result = Some(result);
     }
@@ -729,15 +901,20 @@ fn decode_burst(index: &dyn MetaIndex, state_mutex: &Mutex<PlayerState>) {
 /// Decodes until the in-memory buffer is full, then parks itself. When
 /// unparked, if the buffer is running low, it starts a new burst of decode and
 /// then parks itself again, etc.
-fn decode_main(index: &dyn MetaIndex, state_mutex: &Mutex<PlayerState>) {
+fn decode_main(index_var: &SharedIndex, state_mutex: &Mutex<PlayerState>) {
     loop {
-        let should_decode = {
+        let (should_decode, is_stopping) = {
             let state = state_mutex.lock().unwrap();
-            state.needs_decode()
+            (state.needs_decode(), state.is_stopping())
         };
 
+        if is_stopping {
+            println!("Decoder stopped.");
+            return
+        }
+
         if should_decode {
-            decode_burst(index, state_mutex);
+            decode_burst(index_var, state_mutex);
         }
 
         println!("Decoder going to sleep.");
@@ -746,12 +923,24 @@ fn decode_main(index: &dyn MetaIndex, state_mutex: &Mutex<PlayerState>) {
     }
 }
 
+/// Clears the flag when dropped, to signal that the thread that owns it exited.
+struct AliveGuard(Arc<AtomicBool>);
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
 pub struct Player {
     state: Arc<Mutex<PlayerState>>,
-    index: Arc<dyn MetaIndex + Send + Sync>,
+    index: Arc<SharedIndex>,
     decode_thread: JoinHandle<()>,
     playback_thread: JoinHandle<()>,
     history_thread: JoinHandle<()>,
+    is_decode_alive: Arc<AtomicBool>,
+    is_playback_alive: Arc<AtomicBool>,
+    waker: Arc<playback::Waker>,
 }
 
 pub struct TrackSnapshot {
@@ -784,7 +973,7 @@ pub struct QueueSnapshot {
 impl Player {
     pub fn new(
         index: Arc<dyn MetaIndex + Send + Sync>,
-        card_name: String,
+        card_name: Option<String>,
         volume_name: String,
         db_path: PathBuf,
     ) -> Player {
@@ -794,30 +983,40 @@ impl Player {
         let (sender, receiver) = mpsc::sync_channel(5);
 
         let state = Arc::new(Mutex::new(PlayerState::new(sender)));
+        let index = Arc::new(Swap::new(index));
 
         // Start the decode thread. It runs indefinitely, but we do need to
         // periodically unpark it when there is new stuff to decode.
         let state_mutex_for_decode = state.clone();
         let index_for_decode = index.clone();
+        let is_decode_alive = Arc::new(AtomicBool::new(true));
+        let alive_for_decode = AliveGuard(is_decode_alive.clone());
         let builder = std::thread::Builder::new();
         let decode_join_handle = builder
             .name("decoder".into())
             .spawn(move || {
+                let _alive = alive_for_decode;
                 decode_main(&*index_for_decode, &*state_mutex_for_decode);
             }).unwrap();
 
         let state_mutex_for_playback = state.clone();
         let decode_thread_for_playback = decode_join_handle.thread().clone();
+        let is_playback_alive = Arc::new(AtomicBool::new(true));
+        let alive_for_playback = AliveGuard(is_playback_alive.clone());
+        let waker = Arc::new(playback::Waker::new().expect("Failed to create wake pipe."));
+        let waker_for_playback = waker.clone();
 
         let builder = std::thread::Builder::new();
         let playback_join_handle = builder
             .name("playback".into())
             .spawn(move || {
+                let _alive = alive_for_playback;
                 playback::main(
-                    &card_name,
+                    card_name.as_ref().map(|name| &name[..]),
                     &volume_name,
                     &*state_mutex_for_playback,
                     &decode_thread_for_playback,
+                    &*waker_for_playback,
                 );
             }).unwrap();
 
@@ -840,28 +1039,82 @@ impl Player {
             decode_thread: decode_join_handle,
             playback_thread: playback_join_handle,
             history_thread: history_join_handle,
+            is_decode_alive: is_decode_alive,
+            is_playback_alive: is_playback_alive,
+            waker: waker,
         }
     }
 
-    /// Wait for the playback and decode thread to finish.
+    /// Return whether the decode and playback threads are alive.
+    ///
+    /// This acquires the state lock, so when a thread deadlocks while holding
+    /// it, this blocks too. This is used to ping the systemd watchdog, and not
+    /// pinging at all is the right response in that case.
+    pub fn is_healthy(&self) -> bool {
+        // The decode and playback threads each own a guard that clears their
+        // flag when they exit, also when they unwind from a panic.
+        let is_alive =
+            self.is_decode_alive.load(Ordering::SeqCst) &&
+            self.is_playback_alive.load(Ordering::SeqCst);
+        let is_locked_ok = self.state.lock().is_ok();
+        is_alive && is_locked_ok
+    }
+
+    /// Signal the decode and playback threads to stop.
+    ///
+    /// Playback fades out, and then the audio device is released. Use `join`
+    /// to wait for this to complete.
+    pub fn shutdown(&self) {
+        self.state.lock().unwrap().is_stopping = true;
+        self.decode_thread.thread().unpark();
+        self.playback_thread.thread().unpark();
+        self.waker.wake();
+    }
+
+    /// Wait for the playback, decode, and history threads to finish.
+    ///
+    /// This blocks indefinitely unless `shutdown` was called first. The history
+    /// thread exits after it wrote all pending events to the database,
+    /// including the end of the listen that playback interrupted.
     pub fn join(self) {
-        // Note: currently there is no way to to signal these threads to stop,
-        // so this will block indefinitely.
         self.playback_thread.join().unwrap();
         self.decode_thread.join().unwrap();
+
+        // The state holds the sender of the playback events. After the other
+        // threads exited, we hold the last reference, so dropping it closes the
+        // channel, and the history thread stops when it has drained it.
+        mem::drop(self.state);
+        self.history_thread.join().unwrap();
     }
 
-    /// Enqueue the track for playback at the end of the queue.
-    pub fn enqueue(&self, track_id: TrackId) -> QueueId {
-        let track = self.index.get_track(track_id).expect("Can only enqueue existing tracks.");
-        let album = self.index.get_album(track.album_id).expect("Track must belong to album.");
-        let track_loudness = track.loudness.unwrap_or(Lufs::default());
-        let album_loudness = album.loudness.unwrap_or(Lufs::default());
+    /// Replace the index, after a reindex.
+    ///
+    /// Queued tracks that are not in the new index are removed from the queue.
+    pub fn set_index(&self, index: Arc<dyn MetaIndex + Send + Sync>) {
+        // Hold the state lock while we swap, so the decoder never observes a
+        // queue that refers to tracks that are not in its index.
+        let mut state = self.state.lock().unwrap();
+        state.remove_missing_tracks(&*index);
+        self.index.set(index);
+    }
 
+    /// Enqueue the track for playback at the end of the queue.
+    ///
+    /// Returns `None` if the track does not exist in the index.
+    pub fn enqueue(&self, track_id: TrackId) -> Option<QueueId> {
         // If the queue is empty, then the playback thread may be parked,
         // so we may need to wake it after enqueuing something.
         let (queue_id, needs_wake) = {
             let mut state = self.state.lock().unwrap();
+
+            // Look up the track while holding the lock, so the index can't be
+            // replaced with one that does not contain it in the meantime.
+            let index = self.index.get();
+            let track = index.get_track(track_id)?;
+            let album = index.get_album(track_id.album_id()).expect("Track must belong to album.");
+            let track_loudness = track.loudness.get().unwrap_or(Lufs::default());
+            let album_loudness = album.loudness.get().unwrap_or(Lufs::default());
+
             let needs_wake = state.is_queue_empty();
             let id = state.next_unused_id;
             state.next_unused_id = QueueId(id.0 + 1);
@@ -874,19 +1127,26 @@ impl Player {
             self.playback_thread.thread().unpark();
         }
 
-        queue_id
+        Some(queue_id)
     }
 
     /// Return a snapshot of the queue.
     pub fn get_queue(&self) -> QueueSnapshot {
         let state = self.state.lock().unwrap();
 
+        // The delay was measured at the last write, the device played part of
+        // it since then.
+        let unheard_samples = state.device_delay.pending_samples_at(Instant::now());
+
         let mut tracks = Vec::with_capacity(state.queue.len());
         for queued_track in state.queue.iter() {
             let t = TrackSnapshot {
                 queue_id: queued_track.queue_id,
                 track_id: queued_track.track_id,
-                position_ms: queued_track.position_ms(),
+                // Only the track at the front of the queue has samples in the
+                // device buffer, for the others nothing has been played yet,
+                // so the correction for the device latency is a no-op there.
+                position_ms: queued_track.position_ms(unheard_samples),
                 buffered_ms: queued_track.duration_ms(),
                 is_buffering: match queued_track.decode {
                     Decode::Running => true,
@@ -901,6 +1161,12 @@ impl Player {
         }
     }
 
+    /// Return whether the audio device is available.
+    pub fn get_device_status(&self) -> DeviceStatus {
+        let state = self.state.lock().unwrap();
+        state.device_status.clone()
+    }
+
     /// Return the current playback volume.
     pub fn get_volume(&self) -> Millibel {
         let state = self.state.lock().unwrap();
@@ -924,3 +1190,103 @@ impl Player {
         state.volume
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    use crate::prim::{AlbumId, Album, ArtistCredit, ArtistId, Artist, Date, FilenameRef, OptionLufs, StringRef, Track, get_track_id};
+    use crate::word_index::MemoryWordIndex;
+    use crate::{Lufs, MemoryMetaIndex, TrackId};
+    use super::{DeviceDelay, PlayerState, QueueId, QueuedTrack};
+
+    fn make_index(track_ids: &[TrackId], album_id: AlbumId) -> MemoryMetaIndex {
+        let artist_id = ArtistId(1);
+        let artist = Artist { name: StringRef(0), name_for_sort: StringRef(0) };
+        let album = Album {
+            artist_id: artist_id,
+            artist: StringRef(0),
+            title: StringRef(0),
+            original_release_date: Date::new(2012, 7, 0),
+            loudness: OptionLufs::NONE,
+            disc_total: 0,
+            _padding: 0,
+        };
+        let tracks = track_ids.iter().map(|&track_id| {
+            let track = Track {
+                title: StringRef(0),
+                artist: StringRef(0),
+                filename: FilenameRef(0),
+                duration_seconds: 1,
+                disc_number: 1,
+                track_number: 1,
+                track_total: 0,
+                loudness: OptionLufs::NONE,
+                _padding: 0,
+            };
+            (track_id, track)
+        }).collect();
+        MemoryMetaIndex::from_parts(
+            vec![(artist_id, artist)],
+            vec![(album_id, album)],
+            vec![(album_id, ArtistCredit { artist_id: artist_id, join_phrase: StringRef(0), _padding: 0 })],
+            tracks,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            vec!["x".into()],
+            vec!["x.flac".into()],
+            MemoryWordIndex::new(&BTreeSet::new()),
+            MemoryWordIndex::new(&BTreeSet::new()),
+            MemoryWordIndex::new(&BTreeSet::new()),
+            MemoryWordIndex::new(&BTreeSet::new()),
+        )
+    }
+
+    #[test]
+    fn device_delay_pending_samples_decrease_while_device_plays() {
+        let measured_at = Instant::now();
+        let delay = DeviceDelay {
+            samples: 8820,
+            sample_rate_hz: 44_100,
+            measured_at: measured_at,
+        };
+        // 8820 stereo samples at 44.1 kHz is 100 ms of audio.
+        assert_eq!(delay.pending_samples_at(measured_at), 8820);
+        assert_eq!(delay.pending_samples_at(measured_at + Duration::from_millis(50)), 4410);
+        assert_eq!(delay.pending_samples_at(measured_at + Duration::from_millis(150)), 0);
+    }
+
+    #[test]
+    fn remove_missing_tracks_keeps_playing_and_decoding_tracks() {
+        let album_id = AlbumId(0x2000_0000_0000_0000);
+        let t: Vec<TrackId> = (1..6).map(|i| get_track_id(album_id, 1, i)).collect();
+
+        let (sender, _receiver) = mpsc::sync_channel(5);
+        let mut state = PlayerState::new(sender);
+        for (i, &track_id) in t.iter().enumerate() {
+            let qt = QueuedTrack::new(QueueId(i as u64), track_id, Lufs::default(), Lufs::default());
+            state.queue.push(qt);
+        }
+        state.queue[0].samples_played = 1;
+        state.current_decode = Some(3);
+
+        // Only track 2 and 5 survive the reindex, but track 1 is playing, and
+        // track 4 is being decoded.
+        let index = make_index(&[t[1], t[4]], album_id);
+        state.remove_missing_tracks(&index);
+
+        let remaining: Vec<TrackId> = state.queue.iter().map(|qt| qt.track_id).collect();
+        assert_eq!(remaining, vec![t[0], t[1], t[3], t[4]]);
+        assert_eq!(state.current_decode, Some(2));
+    }
+}
@@ -56,6 +56,12 @@ pub struct AlbumId(pub u64);
 #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct ArtistId(pub u64);
 
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ComposerId(pub u64);
+
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct WorkId(pub u64);
+
 /// Index into a byte array that contains length-prefixed strings.
 #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct StringRef(pub u32);
@@ -69,6 +75,12 @@ impl TrackId {
     pub fn parse(src: &str) -> Option<TrackId> {
         u64::from_str_radix(src, 16).ok().map(TrackId)
     }
+
+    /// Return the album that the track belongs to, see `get_track_id`.
+    #[inline]
+    pub fn album_id(self) -> AlbumId {
+        AlbumId(self.0 & ALBUM_ID_MASK)
+    }
 }
 
 impl AlbumId {
@@ -85,6 +97,20 @@ impl ArtistId {
     }
 }
 
+impl ComposerId {
+    #[inline]
+    pub fn parse(src: &str) -> Option<ComposerId> {
+        u64::from_str_radix(src, 16).ok().map(ComposerId)
+    }
+}
+
+impl WorkId {
+    #[inline]
+    pub fn parse(src: &str) -> Option<WorkId> {
+        u64::from_str_radix(src, 16).ok().map(WorkId)
+    }
+}
+
 /// Loudness Units relative to Full Scale.
 ///
 /// The representation is millibel relative to full scale. In other words, this
@@ -142,28 +168,46 @@ impl FromStr for Lufs {
     }
 }
 
+/// An optional loudness, as stored in the index.
+///
+/// This is an `Option<Lufs>` where 0 means unknown, but unlike an `Option`, it
+/// is a plain `i16`, for which every bit pattern is valid. We need that to use
+/// the index file in place, see `index_file::Flat`.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct OptionLufs(i16);
+
+impl OptionLufs {
+    pub const NONE: OptionLufs = OptionLufs(0);
+
+    pub fn new(loudness: Option<Lufs>) -> OptionLufs {
+        OptionLufs(loudness.map_or(0, |lufs| lufs.0.get()))
+    }
+
+    pub fn get(self) -> Option<Lufs> {
+        std::num::NonZeroI16::new(self.0).map(Lufs)
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Track {
-    pub album_id: AlbumId,
+    // There is no album id, it is a prefix of the track id, see
+    // `TrackId::album_id`. That allows us to squeeze a `(TrackId, Track)` into
+    // half a cache line, so they never straddle cache line boundaries. And of
+    // course more of them fit in the cache.
     pub title: StringRef,
     pub artist: StringRef,
     pub filename: FilenameRef,
-    // Using u16 for duration gives us a little over 18 hours as maximum
-    // duration; using u8 for track number gives us at most 255 tracks. This is
-    // perhaps a bit limiting, but it does allow us to squeeze a `(TrackId,
-    // Track)` into half a cache line, so they never straddle cache line
-    // boundaries. And of course more of them fit in the cache. If range ever
-    // becomes a problem, we could use some of the disc number bits to extend
-    // the duration range or track number range.
-    pub duration_seconds: u16,
+    pub duration_seconds: u32,
+    /// At most `MAX_TRACK_NUMBER`, which is also the limit for the total.
+    pub track_number: u16,
+    /// Number of tracks on the disc, or 0 if unknown.
+    pub track_total: u16,
+    pub loudness: OptionLufs,
     pub disc_number: u8,
-    pub track_number: u8,
-
-    // TODO: Because of this field, the `Track` type becomes too big. But we can
-    // save this, because `album_id` could be removed if we make the album id a
-    // prefix of the track id.
-    pub loudness: Option<Lufs>,
+    /// Makes the padding explicit, we write the bytes of a track to the index.
+    pub _padding: u8,
 }
 
 #[repr(C)]
@@ -193,10 +237,214 @@ impl Date {
 #[repr(C)]
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Album {
+    /// The first artist in the artist credit, see `MetaIndex::get_album_artists`
+    /// for all of them. Albums sort under this artist.
     pub artist_id: ArtistId,
+    /// The full artist credit, as displayed, e.g. "Artist A & Artist B".
+    pub artist: StringRef,
     pub title: StringRef,
     pub original_release_date: Date,
-    pub loudness: Option<Lufs>,
+    pub loudness: OptionLufs,
+    /// Number of discs in the release, or 0 if unknown.
+    pub disc_total: u8,
+    /// Makes the padding explicit, we write the bytes of an album to the index.
+    pub _padding: u8,
+}
+
+/// One artist in the artist credit of an album.
+#[repr(C)]
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct ArtistCredit {
+    pub artist_id: ArtistId,
+    /// The text that follows the artist name in the credit, e.g. " & ".
+    /// For the last artist, this is usually empty.
+    pub join_phrase: StringRef,
+    /// Makes the padding explicit, we write the bytes of a credit to the index.
+    pub _padding: u32,
+}
+
+/// A full 16-byte MusicBrainz identifier.
+///
+/// Ids like `TrackId` use only 8 bytes of the MusicBrainz id, see the comment
+/// at the top of this file. To talk to external services we need the full id,
+/// but it is not needed for anything else, so we store it separately. The nil
+/// UUID indicates an absent id.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Mbid(pub [u8; 16]);
+
+impl Mbid {
+    pub const NIL: Mbid = Mbid([0; 16]);
+
+    /// Parse a UUID of the form `1070cbb2-ad74-44ce-90a4-7fa1dfd8164e`.
+    pub fn parse(uuid: &str) -> Option<Mbid> {
+        if uuid.len() != 36 { return None }
+        let mut bytes = [0_u8; 16];
+        let mut hex = uuid.bytes().enumerate().filter_map(|(i, ch)| match i {
+            8 | 13 | 18 | 23 => if ch == b'-' { None } else { Some(None) },
+            _ => Some((ch as char).to_digit(16)),
+        });
+        for byte in bytes.iter_mut() {
+            let high = hex.next()??;
+            let low = hex.next()??;
+            *byte = (high * 16 + low) as u8;
+        }
+        Some(Mbid(bytes))
+    }
+
+    /// Return the id, or `None` if it is the nil UUID.
+    pub fn get(self) -> Option<Mbid> {
+        if self == Mbid::NIL { None } else { Some(self) }
+    }
+}
+
+impl fmt::Display for Mbid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// The MusicBrainz ids of a track, when they are known.
+#[repr(C)]
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct TrackMbids {
+    /// The recording, from the `musicbrainz_trackid` tag.
+    pub recording: Mbid,
+    /// The track on the release, from the `musicbrainz_releasetrackid` tag.
+    pub track: Mbid,
+}
+
+/// The MusicBrainz ids of an album, when they are known.
+#[repr(C)]
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct AlbumMbids {
+    /// The release, from the `musicbrainz_albumid` tag.
+    pub release: Mbid,
+    /// The release group, from the `musicbrainz_releasegroupid` tag.
+    pub release_group: Mbid,
+}
+
+/// A composer. Tags have no ids for composers, they are identified by name.
+#[repr(C)]
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Composer {
+    pub name: StringRef,
+}
+
+/// A composition, such as a symphony, of which tracks can be movements.
+#[repr(C)]
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Work {
+    pub title: StringRef,
+}
+
+/// The work that a track belongs to, and which part of it the track is.
+#[repr(C)]
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct TrackWork {
+    pub work_id: WorkId,
+    /// The name of the movement, e.g. "II. Adagio", or the empty string.
+    pub movement_name: StringRef,
+    /// The movement number, or 0 if unknown.
+    pub movement_number: u8,
+    /// Makes the padding explicit, we write the bytes of a track work to the index.
+    pub _padding: [u8; 3],
+}
+
+/// What kind of release an album is, for grouping a discography.
+///
+/// Derived from the MusicBrainz primary and secondary release types. Secondary
+/// types take precedence, a live EP is listed under live releases.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ReleaseKind {
+    Album = 0,
+    Ep = 1,
+    Single = 2,
+    Live = 3,
+    Compilation = 4,
+    Other = 5,
+}
+
+impl ReleaseKind {
+    /// Classify a release from the values of its `releasetype` tags.
+    ///
+    /// Releases without type are assumed to be albums.
+    pub fn from_release_types<'a, I: IntoIterator<Item = &'a str>>(types: I) -> ReleaseKind {
+        let mut primary = None;
+        let mut secondary = None;
+        for release_type in types {
+            match release_type.trim().to_ascii_lowercase().as_ref() {
+                "compilation" => secondary = Some(ReleaseKind::Compilation),
+                "live" => secondary = secondary.or(Some(ReleaseKind::Live)),
+                "album" => primary = primary.or(Some(ReleaseKind::Album)),
+                "ep" => primary = primary.or(Some(ReleaseKind::Ep)),
+                "single" => primary = primary.or(Some(ReleaseKind::Single)),
+                "broadcast" | "other" => primary = primary.or(Some(ReleaseKind::Other)),
+                // Other secondary types, such as soundtrack or remix, do not
+                // get a group of their own.
+                _ => continue,
+            }
+        }
+        secondary.or(primary).unwrap_or(ReleaseKind::Album)
+    }
+
+    /// Convert a value stored in the index back into a kind.
+    ///
+    /// Values that are not a known kind are treated as albums.
+    pub fn from_u8(value: u8) -> ReleaseKind {
+        match value {
+            1 => ReleaseKind::Ep,
+            2 => ReleaseKind::Single,
+            3 => ReleaseKind::Live,
+            4 => ReleaseKind::Compilation,
+            5 => ReleaseKind::Other,
+            _ => ReleaseKind::Album,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReleaseKind::Album => "album",
+            ReleaseKind::Ep => "ep",
+            ReleaseKind::Single => "single",
+            ReleaseKind::Live => "live",
+            ReleaseKind::Compilation => "compilation",
+            ReleaseKind::Other => "other",
+        }
+    }
+}
+
+/// Release metadata of an album that is not needed for playback.
+///
+/// Fields that were not tagged refer to the empty string.
+#[repr(C)]
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct AlbumDetails {
+    pub label: StringRef,
+    pub catalogue_number: StringRef,
+    pub release_country: StringRef,
+    /// The primary release type, as tagged.
+    pub release_type: StringRef,
+    /// E.g. "official" or "bootleg", from the `releasestatus` tag.
+    pub release_status: StringRef,
+    /// A `ReleaseKind` as `u8`. Not the enum itself, because the index is used
+    /// in place, and not every byte is a valid enum value. Use `release_kind()`.
+    pub release_kind: u8,
+    /// Makes the padding explicit, we write the bytes of the details to the index.
+    pub _padding: [u8; 3],
+}
+
+impl AlbumDetails {
+    pub fn release_kind(&self) -> ReleaseKind {
+        ReleaseKind::from_u8(self.release_kind)
+    }
 }
 
 #[repr(C)]
@@ -234,23 +482,52 @@ impl fmt::Display for ArtistId {
     }
 }
 
+impl fmt::Display for ComposerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl fmt::Display for WorkId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// The bits of a track id that hold the album id.
+///
+/// The remaining 20 bits hold the disc number (8 bits) and the track number
+/// (12 bits). An album id is only the 44 most significant bits of the
+/// MusicBrainz id. With 43 random bits (the other one marks fallback ids), we
+/// can have 10k albums at a collision probability of about 0.001%.
+const ALBUM_ID_MASK: u64 = 0xffff_ffff_fff0_0000;
+
+/// The largest track number that fits in a track id.
+pub const MAX_TRACK_NUMBER: u16 = 0xfff;
+
+/// Make an album id from an id derived from tags, see `ALBUM_ID_MASK`.
+pub fn get_album_id(id: u64) -> AlbumId {
+    AlbumId(id & ALBUM_ID_MASK)
+}
+
 pub fn get_track_id(album_id: AlbumId,
                 disc_number: u8,
-                track_number: u8)
+                track_number: u16)
                 -> TrackId {
     // Take the bits from the album id, so all the tracks within one album are
     // adjacent. This is desirable, because two tracks fit in a cache line,
     // halving the memory access cost of looking up an entire album. It also
-    // makes memory access more predictable. Finally, if the 52 most significant
-    // bits uniquely identify the album (which we assume), then all tracks are
-    // guaranteed to be adjacent, and we can use an efficient range query to
-    // find them.
-    let high = album_id.0 & 0xffff_ffff_ffff_f000;
+    // makes memory access more predictable. Finally, because the album id is
+    // a prefix of the track id, all tracks of an album are guaranteed to be
+    // adjacent, and we can use an efficient range query to find them.
+    debug_assert_eq!(album_id.0 & !ALBUM_ID_MASK, 0, "Album id must come from get_album_id.");
+    debug_assert!(track_number <= MAX_TRACK_NUMBER, "Track number must fit in 12 bits.");
+    let high = album_id.0;
 
     // Finally, within an album the disc number and track number should uniquely
     // identify the track.
-    let mid = ((disc_number & 0xf) as u64) << 8;
-    let low = track_number as u64;
+    let mid = (disc_number as u64) << 12;
+    let low = (track_number & MAX_TRACK_NUMBER) as u64;
 
     TrackId(high | mid | low)
 }
@@ -258,14 +535,70 @@ pub fn get_track_id(album_id: AlbumId,
 #[test]
 fn struct_sizes_are_as_expected() {
     use std::mem;
-    // TODO: Enable these again once I sort out how to fit the loudness in and
-    // still keep a (TrackId, Track) 32 bytes.
-    // assert_eq!(mem::size_of::<Track>(), 24);
+    assert_eq!(mem::size_of::<Track>(), 24);
+    // TODO: Enable this again once I sort out how to fit the loudness in.
     // assert_eq!(mem::size_of::<Album>(), 16);
     assert_eq!(mem::size_of::<Artist>(), 8);
-    // assert_eq!(mem::size_of::<(TrackId, Track)>(), 32);
+    assert_eq!(mem::size_of::<(TrackId, Track)>(), 32);
 
-    assert_eq!(mem::align_of::<Track>(), 8);
+    assert_eq!(mem::align_of::<Track>(), 4);
     assert_eq!(mem::align_of::<Album>(), 8);
     assert_eq!(mem::align_of::<Artist>(), 4);
 }
+
+#[test]
+fn track_id_holds_album_disc_and_track() {
+    let album_id = get_album_id(0x7a1b_2c3d_4e5f_6a7b);
+    assert_eq!(album_id, AlbumId(0x7a1b_2c3d_4e50_0000));
+    // Box sets can have more than 15 discs, DJ mixes more than 255 tracks.
+    let a = get_track_id(album_id, 17, 300);
+    let b = get_track_id(album_id, 1, 300);
+    let c = get_track_id(album_id, 17, 1);
+    assert_eq!(a.album_id(), album_id);
+    assert_ne!(a, b);
+    assert_ne!(a, c);
+    assert!(b < c && c < a);
+    assert_eq!(get_track_id(album_id, 255, MAX_TRACK_NUMBER).album_id(), album_id);
+}
+
+#[test]
+fn release_kind_prefers_secondary_types() {
+    assert_eq!(ReleaseKind::from_release_types(vec![]), ReleaseKind::Album);
+    assert_eq!(ReleaseKind::from_release_types(vec!["album"]), ReleaseKind::Album);
+    assert_eq!(ReleaseKind::from_release_types(vec!["EP"]), ReleaseKind::Ep);
+    assert_eq!(ReleaseKind::from_release_types(vec!["single", "live"]), ReleaseKind::Live);
+    assert_eq!(ReleaseKind::from_release_types(vec!["album", "live", "compilation"]), ReleaseKind::Compilation);
+    assert_eq!(ReleaseKind::from_release_types(vec!["broadcast"]), ReleaseKind::Other);
+    assert_eq!(ReleaseKind::from_release_types(vec!["soundtrack", "album"]), ReleaseKind::Album);
+}
+
+#[test]
+fn release_kind_roundtrips_through_u8() {
+    for &kind in &[
+        ReleaseKind::Album,
+        ReleaseKind::Ep,
+        ReleaseKind::Single,
+        ReleaseKind::Live,
+        ReleaseKind::Compilation,
+        ReleaseKind::Other,
+    ] {
+        assert_eq!(ReleaseKind::from_u8(kind as u8), kind);
+    }
+    assert_eq!(ReleaseKind::from_u8(6), ReleaseKind::Album);
+    assert_eq!(ReleaseKind::from_u8(255), ReleaseKind::Album);
+}
+
+#[test]
+fn mbid_roundtrips_through_string() {
+    let uuid = "1070cbb2-ad74-44ce-90a4-7fa1dfd8164e";
+    let mbid = Mbid::parse(uuid).unwrap();
+    assert_eq!(mbid.0[0], 0x10);
+    assert_eq!(mbid.0[15], 0x4e);
+    assert_eq!(mbid.to_string(), uuid);
+    assert_eq!(mbid.get(), Some(mbid));
+    assert_eq!(Mbid::NIL.get(), None);
+
+    assert_eq!(Mbid::parse("1070cbb2ad7444ce90a47fa1dfd8164e"), None);
+    assert_eq!(Mbid::parse("1070cbb2-ad74-44ce-90a4-7fa1dfd8164x"), None);
+    assert_eq!(Mbid::parse("1070cbb2-ad74-44ce-90a4-7fa1dfd8164"), None);
+}
@@ -7,23 +7,32 @@
 
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::mpsc::SyncSender;
 
-use crate::prim::{AlbumId, Album, ArtistId, Artist, TrackId, Track, Date, Lufs, FilenameRef, StringRef, get_track_id};
+use crate::prim::{AlbumId, Album, AlbumDetails, AlbumMbids, ReleaseKind, ArtistId, Artist, ArtistCredit, ComposerId, Composer, TrackId, Track, TrackMbids, TrackWork, WorkId, Work, Date, Lufs, FilenameRef, Mbid, OptionLufs, StringRef, MAX_TRACK_NUMBER, get_album_id, get_track_id};
 use crate::string_utils::{StringDeduper, normalize_words};
 use crate::word_index::{WordMeta};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum IssueDetail {
     /// A required metadata field is missing. Contains the field name.
-    FieldMissingError(&'static str),
+    FieldMissingError(String),
 
     /// A recommended metadata field is missing. Contains the field name.
-    FieldMissingWarning(&'static str),
+    FieldMissingWarning(String),
+
+    /// A MusicBrainz id is missing, we derived an id from the other tags.
+    /// Contains the name of the missing field.
+    FallbackIdUsed(String),
 
     /// A metadata field could be parsed. Contains the field name.
-    FieldParseFailedError(&'static str),
+    FieldParseFailedError(String),
+
+    /// An optional metadata field could not be parsed, we ignored it.
+    /// Contains the field name.
+    FieldParseFailedWarning(String),
 
     /// A track title contains the phrase "(feat. ",
     /// which likely belongs in the artist instead.
@@ -37,9 +46,9 @@ pub enum IssueDetail {
     /// Contains the date used, and the discarded alternative.
     AlbumReleaseDateMismatch(AlbumId, Date, Date),
 
-    /// Two different artists were found for albums with the same mbid.
-    /// Contains the artist used, and the discarded alternative.
-    AlbumArtistMismatch(AlbumId, ArtistId, ArtistId),
+    /// Two different artist credits were found for albums with the same mbid.
+    /// Contains the credit used, and the discarded alternative.
+    AlbumArtistMismatch(AlbumId, String, String),
 
     /// Two different album loudnesses were found for albums with the same mbid.
     /// Contains the loudness used, and the discarded alternative.
@@ -53,11 +62,19 @@ pub enum IssueDetail {
     /// Contains the name used, and the discarded alternative.
     ArtistSortNameMismatch(ArtistId, String, String),
 
+    /// Another file has the same album, disc number, and track number.
+    /// Contains the track, and the file that we kept instead of this one.
+    DuplicateTrack(TrackId, String),
+
     /// The file does not contain exactly two channels.
     NotStereo,
 
     /// The file does not use either 16 or 24 bits per sample.
     UnsupportedBitDepth(u32),
+
+    /// The file could not be read, it is not indexed. Contains the error.
+    /// This can happen when a file is being copied while we scan.
+    ReadFailed(String),
 }
 
 impl IssueDetail {
@@ -67,65 +84,244 @@ impl IssueDetail {
             detail: self,
         }
     }
+
+    /// Return whether this is an error, which needs to be fixed, or a warning.
+    pub fn is_error(&self) -> bool {
+        match *self {
+            IssueDetail::FieldMissingError(..) => true,
+            IssueDetail::FieldParseFailedError(..) => true,
+            IssueDetail::DuplicateTrack(..) => true,
+            IssueDetail::NotStereo => true,
+            IssueDetail::UnsupportedBitDepth(..) => true,
+            IssueDetail::ReadFailed(..) => true,
+            _ => false,
+        }
+    }
+
+    /// All values that `kind` can return.
+    pub const KINDS: &'static [&'static str] = &[
+        "field_missing",
+        "fallback_id_used",
+        "field_parse_failed",
+        "track_title_contains_feat",
+        "album_title_mismatch",
+        "album_release_date_mismatch",
+        "album_artist_mismatch",
+        "album_loudness_mismatch",
+        "artist_name_mismatch",
+        "artist_sort_name_mismatch",
+        "duplicate_track",
+        "not_stereo",
+        "unsupported_bit_depth",
+        "read_failed",
+    ];
+
+    /// Return a short identifier for the kind of issue, for use in the API.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            IssueDetail::FieldMissingError(..) => "field_missing",
+            IssueDetail::FieldMissingWarning(..) => "field_missing",
+            IssueDetail::FallbackIdUsed(..) => "fallback_id_used",
+            IssueDetail::FieldParseFailedError(..) => "field_parse_failed",
+            IssueDetail::FieldParseFailedWarning(..) => "field_parse_failed",
+            IssueDetail::TrackTitleContainsFeat => "track_title_contains_feat",
+            IssueDetail::AlbumTitleMismatch(..) => "album_title_mismatch",
+            IssueDetail::AlbumReleaseDateMismatch(..) => "album_release_date_mismatch",
+            IssueDetail::AlbumArtistMismatch(..) => "album_artist_mismatch",
+            IssueDetail::AlbumLoudnessMismatch(..) => "album_loudness_mismatch",
+            IssueDetail::ArtistNameMismatch(..) => "artist_name_mismatch",
+            IssueDetail::ArtistSortNameMismatch(..) => "artist_sort_name_mismatch",
+            IssueDetail::DuplicateTrack(..) => "duplicate_track",
+            IssueDetail::NotStereo => "not_stereo",
+            IssueDetail::UnsupportedBitDepth(..) => "unsupported_bit_depth",
+            IssueDetail::ReadFailed(..) => "read_failed",
+        }
+    }
+
+    /// Return the tag that the issue is about, if it is about a single tag.
+    pub fn field(&self) -> Option<&str> {
+        match *self {
+            IssueDetail::FieldMissingError(ref field) => Some(field),
+            IssueDetail::FieldMissingWarning(ref field) => Some(field),
+            IssueDetail::FallbackIdUsed(ref field) => Some(field),
+            IssueDetail::FieldParseFailedError(ref field) => Some(field),
+            IssueDetail::FieldParseFailedWarning(ref field) => Some(field),
+            IssueDetail::TrackTitleContainsFeat => Some("title"),
+            IssueDetail::AlbumTitleMismatch(..) => Some("album"),
+            IssueDetail::AlbumReleaseDateMismatch(..) => Some("originaldate"),
+            IssueDetail::AlbumArtistMismatch(..) => Some("albumartist"),
+            IssueDetail::AlbumLoudnessMismatch(..) => Some("bs17704_album_loudness"),
+            IssueDetail::ArtistNameMismatch(..) => Some("albumartist"),
+            IssueDetail::ArtistSortNameMismatch(..) => Some("albumartistsort"),
+            IssueDetail::DuplicateTrack(..) => Some("tracknumber"),
+            IssueDetail::NotStereo => None,
+            IssueDetail::UnsupportedBitDepth(..) => None,
+            IssueDetail::ReadFailed(..) => None,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Issue {
     pub filename: String,
     pub detail: IssueDetail,
 }
 
-impl fmt::Display for Issue {
+impl fmt::Display for IssueDetail {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:\n  ", self.filename)?;
-        match self.detail {
-            IssueDetail::FieldMissingError(field) =>
-                write!(f, "error: field '{}' missing.", field),
-            IssueDetail::FieldMissingWarning(field) =>
-                write!(f, "warning: field '{}' missing.", field),
-            IssueDetail::FieldParseFailedError(field) =>
-                write!(f, "error: failed to parse field '{}'.", field),
+        match *self {
+            IssueDetail::FieldMissingError(ref field) =>
+                write!(f, "field '{}' missing.", field),
+            IssueDetail::FieldMissingWarning(ref field) =>
+                write!(f, "field '{}' missing.", field),
+            IssueDetail::DuplicateTrack(_id, ref kept) =>
+                write!(f, "duplicate of '{}', which was kept instead.", kept),
+            IssueDetail::FallbackIdUsed(ref field) =>
+                write!(f, "field '{}' missing, derived an id from the other tags instead.", field),
+            IssueDetail::FieldParseFailedError(ref field) =>
+                write!(f, "failed to parse field '{}'.", field),
+            IssueDetail::FieldParseFailedWarning(ref field) =>
+                write!(f, "failed to parse field '{}', ignored it.", field),
             IssueDetail::TrackTitleContainsFeat =>
-                write!(f, "warning: track title contains '(feat. '."),
+                write!(f, "track title contains '(feat. '."),
             IssueDetail::NotStereo =>
-                write!(f, "error: the file is not stereo"),
+                write!(f, "the file is not stereo"),
             IssueDetail::UnsupportedBitDepth(bits) =>
-                write!(f, "error: {} bits per sample is not supported", bits),
+                write!(f, "{} bits per sample is not supported", bits),
+            IssueDetail::ReadFailed(ref err) =>
+                write!(f, "failed to read the file: {}", err),
             IssueDetail::AlbumTitleMismatch(_id, ref title, ref alt) =>
-                write!(f, "warning: discarded inconsistent album title '{}' in favour of '{}'.", alt, title),
+                write!(f, "discarded inconsistent album title '{}' in favour of '{}'.", alt, title),
             IssueDetail::AlbumReleaseDateMismatch(_id, ref date, ref alt) =>
-                write!(f, "warning: discarded inconsistent album release date {} in favour of {}.", alt, date),
+                write!(f, "discarded inconsistent album release date {} in favour of {}.", alt, date),
             IssueDetail::AlbumArtistMismatch(_id, ref artist, ref alt) =>
-                write!(f, "warning: discarded inconsistent album artist '{}' in favour of '{}'.", alt, artist),
+                write!(f, "discarded inconsistent album artist '{}' in favour of '{}'.", alt, artist),
             IssueDetail::ArtistNameMismatch(_id, ref name, ref alt) =>
-                write!(f, "warning: discarded inconsistent artist name '{}' in favour of '{}'.", alt, name),
+                write!(f, "discarded inconsistent artist name '{}' in favour of '{}'.", alt, name),
             IssueDetail::ArtistSortNameMismatch(_id, ref sort_name, ref alt) =>
-                write!(f, "warning: discarded inconsistent sort name '{}' in favour of '{}'.", alt, sort_name),
+                write!(f, "discarded inconsistent sort name '{}' in favour of '{}'.", alt, sort_name),
             IssueDetail::AlbumLoudnessMismatch(_id, Some(loudness), Some(alt)) =>
-                write!(f, "warning: discarded inconsistent loudness '{}' in favour of '{}'.", alt, loudness),
+                write!(f, "discarded inconsistent loudness '{}' in favour of '{}'.", alt, loudness),
             IssueDetail::AlbumLoudnessMismatch(_id, Some(loudness), None) =>
-                write!(f, "warning: replaced inconsistently missing loudness with '{}'.", loudness),
+                write!(f, "replaced inconsistently missing loudness with '{}'.", loudness),
             IssueDetail::AlbumLoudnessMismatch(_id, None, Some(alt)) =>
-                write!(f, "warning: ignored loudness '{}' because it is not unanimous.", alt),
+                write!(f, "ignored loudness '{}' because it is not unanimous.", alt),
             IssueDetail::AlbumLoudnessMismatch(_id, None, None) =>
                 panic!("Not actually a loudness mismatch."),
         }
     }
 }
 
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = if self.detail.is_error() { "error" } else { "warning" };
+        write!(f, "{}:\n  {}: {}", self.filename, severity, self.detail)
+    }
+}
+
 #[derive(Debug)]
 pub enum Progress {
+    /// Compared to the previous scan, files were added, changed, or removed.
+    Changes { added: u32, changed: u32, removed: u32 },
     /// A number of files have been indexed.
     Indexed(u32),
     /// An issue with a file was encountered.
     Issue(Issue),
 }
 
+/// What a scan is currently doing.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ScanStage {
+    /// Enumerating the files in the library.
+    Discovering,
+    /// Reading tags of new and changed files, and building the index.
+    Indexing,
+    /// Writing the index to disk.
+    Writing,
+    /// The scan is complete, and the new index is in use.
+    Done,
+    /// The scan failed, the previous index, if any, is still in use.
+    Failed,
+}
+
+/// Progress of a scan, for reporting it while the scan is in progress.
+#[derive(Clone, Debug)]
+pub struct ScanStatus {
+    pub stage: ScanStage,
+    pub files_discovered: u64,
+    pub files_added: u64,
+    pub files_changed: u64,
+    pub files_removed: u64,
+    /// Files processed so far, both those that were read and those whose tags
+    /// were reused from the previous scan.
+    pub files_indexed: u64,
+    /// Number of issues encountered so far.
+    pub issues: u64,
+    /// Why the scan failed, when the stage is `Failed`.
+    pub error: Option<String>,
+}
+
+impl ScanStatus {
+    pub fn new() -> ScanStatus {
+        ScanStatus {
+            stage: ScanStage::Discovering,
+            files_discovered: 0,
+            files_added: 0,
+            files_changed: 0,
+            files_removed: 0,
+            files_indexed: 0,
+            issues: 0,
+            error: None,
+        }
+    }
+}
+
+/// The parts of a file that the index is built from.
+///
+/// These are stored alongside the index, so when the library changes, we only
+/// need to read the files that changed, and we can rebuild the index from the
+/// stored tags for all other files.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileTags {
+    pub channels: u32,
+    pub bits_per_sample: u32,
+    pub sample_rate: u32,
+    pub samples: Option<u64>,
+
+    /// All Vorbis comments, in the order they occur in the file.
+    ///
+    /// We keep the tags that we don't use as well, so that when a future
+    /// version starts using a tag, the stored tags are still complete.
+    pub tags: Vec<(String, String)>,
+}
+
+impl FileTags {
+    pub fn read(path: &Path) -> claxon::Result<FileTags> {
+        let opts = claxon::FlacReaderOptions {
+            metadata_only: true,
+            read_picture: claxon::ReadPicture::Skip,
+            read_vorbis_comment: true,
+        };
+        let reader = claxon::FlacReader::open_ext(path, opts)?;
+        let streaminfo = reader.streaminfo();
+        let result = FileTags {
+            channels: streaminfo.channels,
+            bits_per_sample: streaminfo.bits_per_sample,
+            sample_rate: streaminfo.sample_rate,
+            samples: streaminfo.samples,
+            tags: reader.tags().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        };
+        Ok(result)
+    }
+}
+
 fn parse_date(date_str: &str) -> Option<Date> {
     // We expect at least a year.
     if date_str.len() < 4 { return None }
 
-    let year = u16::from_str(&date_str[0..4]).ok()?;
+    // Use `get` rather than indexing, the string may contain multi-byte
+    // characters, and then slicing could fall inside one.
+    let year = u16::from_str(date_str.get(0..4)?).ok()?;
     let mut month: u8 = 0;
     let mut day: u8 = 0;
 
@@ -134,7 +330,7 @@ fn parse_date(date_str: &str) -> Option<Date> {
     if date_str.len() > 4 {
         if date_str.as_bytes()[4] != b'-' { return None }
         if date_str.len() < 7 { return None }
-        month = u8::from_str(&date_str[5..7]).ok()?;
+        month = u8::from_str(date_str.get(5..7)?).ok()?;
     }
 
     // If there is something following the month, it must be dash, and there
@@ -142,7 +338,7 @@ fn parse_date(date_str: &str) -> Option<Date> {
     if date_str.len() > 7 {
         if date_str.as_bytes()[7] != b'-' { return None }
         if date_str.len() != 10 { return None }
-        day = u8::from_str(&date_str[8..10]).ok()?;
+        day = u8::from_str(date_str.get(8..10)?).ok()?;
     }
 
     // This is not the most strict date well-formedness check that we can do,
@@ -155,6 +351,72 @@ fn parse_date(date_str: &str) -> Option<Date> {
     Some(Date::new(year, month, day))
 }
 
+/// Ids derived from MusicBrainz ids have the most significant bit cleared, ids
+/// derived by hashing tags have it set, so the two never collide.
+pub const FALLBACK_ID_BIT: u64 = 0x8000_0000_0000_0000;
+
+/// Derive an id from tags, for files that lack a MusicBrainz id.
+///
+/// The parts are normalized first, so differences in case or punctuation do
+/// not result in a different id. We use 64-bit FNV-1a rather than the standard
+/// library hasher, because the ids end up in the listen history, so they must
+/// be stable across Rust versions.
+fn fallback_id(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut words = Vec::new();
+    for part in parts {
+        normalize_words(part, &mut words);
+        // Terminate every part with a zero byte, so ("ab", "c") and ("a",
+        // "bc") hash differently.
+        for byte in words.join(" ").bytes().chain(Some(0)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        words.clear();
+    }
+    hash | FALLBACK_ID_BIT
+}
+
+/// Parse a disc or track number, optionally followed by the total, as in "3/12".
+///
+/// Returns the number and the total, if there is one. Disc numbers are `u8`,
+/// track numbers `u16`; numbers that do not fit fail to parse.
+fn parse_number_of_total<T: FromStr>(value: &str) -> Option<(T, Option<T>)> {
+    let mut parts = value.splitn(2, '/');
+    let number = T::from_str(parts.next()?.trim()).ok()?;
+    let total = match parts.next() {
+        Some(total) => Some(T::from_str(total.trim()).ok()?),
+        None => None,
+    };
+    Some((number, total))
+}
+
+/// Parse a track number, which must fit in a track id, see `get_track_id`.
+fn parse_track_number_of_total(value: &str) -> Option<(u16, Option<u16>)> {
+    let (number, total) = parse_number_of_total::<u16>(value)?;
+    if number > MAX_TRACK_NUMBER || total.map_or(false, |t| t > MAX_TRACK_NUMBER) {
+        return None
+    }
+    Some((number, total))
+}
+
+/// Parse a vinyl track number, such as "A1" or "B3", into disc and track number.
+///
+/// From a single file we cannot know how many tracks are on the preceding
+/// sides, so we can't number the tracks consecutively. Instead, every two sides
+/// form a disc, and tracks on the second side are numbered from 101. This
+/// keeps the tracks in order, and the side is still easy to recognize.
+fn parse_vinyl_track_number(value: &str) -> Option<(u8, u8)> {
+    let value = value.trim();
+    let side = value.bytes().next()?.to_ascii_uppercase();
+    if side < b'A' || side > b'Z' { return None }
+    // The side is a single byte, so slicing after it is safe.
+    let number = u8::from_str(&value[1..]).ok()?;
+    if number == 0 || number > 99 { return None }
+    let side_index = side - b'A';
+    Some((side_index / 2 + 1, (side_index % 2) * 100 + number))
+}
+
 /// Parse a part of a 128-bit hexadecimal UUID into a 64-bit unsigned integer.
 fn parse_uuid(uuid: &str) -> Option<u64> {
     // Validate that the textual format of the UUID is as expected.
@@ -169,9 +431,101 @@ fn parse_uuid(uuid: &str) -> Option<u64> {
     // bits. We take the front and back of the string because it is easy, there
     // are no dashes to strip. Also, the non-random version bits are in the
     // middle, so this way we avoid using those.
-    let high = u32::from_str_radix(&uuid[..8], 16).ok()? as u64;
-    let low = u32::from_str_radix(&uuid[28..], 16).ok()? as u64;
-    Some((high << 32) | low)
+    let high = u32::from_str_radix(uuid.get(..8)?, 16).ok()? as u64;
+    let low = u32::from_str_radix(uuid.get(28..)?, 16).ok()? as u64;
+    Some(((high << 32) | low) & !FALLBACK_ID_BIT)
+}
+
+/// Find the join phrases in an artist credit, given the names of the artists.
+///
+/// For example, for the credit "A feat. B" and names "A" and "B", the join
+/// phrases are " feat. " and "". Returns `None` if the credit does not consist
+/// of the names in order.
+fn parse_join_phrases<'a>(credit: &'a str, names: &[&str]) -> Option<Vec<&'a str>> {
+    if names.is_empty() || !credit.starts_with(names[0]) { return None }
+    let mut join_phrases = Vec::with_capacity(names.len());
+    let mut pos = names[0].len();
+    for name in &names[1..] {
+        let start = pos + credit[pos..].find(name)?;
+        join_phrases.push(&credit[pos..start]);
+        pos = start + name.len();
+    }
+    join_phrases.push(&credit[pos..]);
+    Some(join_phrases)
+}
+
+/// Return the names of the `n` artists in an artist credit.
+///
+/// For a single artist, the name is the credit itself. For multiple artists,
+/// we use the names from the tags if there is one for every artist, and
+/// otherwise we try to split the credit.
+fn credited_names<'a>(credit: &'a str, n: usize, names: &[&'a str]) -> Option<Vec<&'a str>> {
+    if n == 1 {
+        Some(vec![credit])
+    } else if names.len() == n {
+        Some(names.to_vec())
+    } else {
+        split_artist_credit(credit, n)
+    }
+}
+
+/// Normalize a name for sorting.
+///
+/// Generally, the only thing the sort name is useful for is to turn e.g. "The
+/// Who" into "Who, The". (Data from Musicbrainz also puts the last name first
+/// for artists who use their real name, but I dislike this.) But this is not
+/// sufficient for sorting alone: there can still be case differences (e.g.
+/// "dEUS" and "deadmau5" sorting last because they are lowercase) and accents
+/// (e.g. "Étienne de Crécy" sorting last, and not with the "E"). The correct
+/// sort ordering depends on locale. I am going to ignore all of that and turn
+/// characters into the lowercase ascii character that looks most like it, then
+/// sort by that.
+fn normalize_sort_name(name: &str) -> String {
+    let mut words = Vec::new();
+    normalize_words(name, &mut words);
+    words.join(" ")
+}
+
+/// Guess the names of the artists in a credit, for files that only have the credit.
+///
+/// Returns `None` if splitting on common join phrases does not yield exactly
+/// `n` names.
+fn split_artist_credit(credit: &str, n: usize) -> Option<Vec<&str>> {
+    let join_phrases = [" & ", ", ", " and ", " feat. ", " x ", " vs. "];
+    let mut splits = Vec::new();
+    for phrase in join_phrases.iter() {
+        for (i, _) in credit.match_indices(phrase) {
+            splits.push((i, i + phrase.len()));
+        }
+    }
+    splits.sort();
+
+    if splits.len() + 1 != n { return None }
+
+    let mut names = Vec::with_capacity(n);
+    let mut pos = 0;
+    for (begin, end) in splits {
+        // Join phrases can overlap, e.g. in "A, and B". Then we can't tell
+        // where the names are.
+        if begin < pos { return None }
+        names.push(&credit[pos..begin]);
+        pos = end;
+    }
+    names.push(&credit[pos..]);
+    Some(names)
+}
+
+/// Of two files for the same track, return whether to keep file `a` over file `b`.
+///
+/// We prefer the higher bit depth. If that is equal, we prefer the file that
+/// sorts first by path, so the choice does not depend on the order in which
+/// the files were scanned.
+pub fn prefer_track_file(bits_a: u32, filename_a: &str, bits_b: u32, filename_b: &str) -> bool {
+    if bits_a != bits_b {
+        bits_a > bits_b
+    } else {
+        filename_a <= filename_b
+    }
 }
 
 /// Return an issue if the two albums are not equal.
@@ -204,20 +558,48 @@ pub fn albums_different(
     if a.loudness != b.loudness {
         return Some(IssueDetail::AlbumLoudnessMismatch(
             id,
-            a.loudness,
-            b.loudness,
+            a.loudness.get(),
+            b.loudness.get(),
         ));
     }
 
-    if a.artist_id != b.artist_id {
-        return Some(IssueDetail::AlbumArtistMismatch(
-            id,
-            a.artist_id,
-            b.artist_id,
-        ));
+    None
+}
+
+/// Return an issue if the two artist credits of an album are not equal.
+///
+/// The credits consist of the credit as displayed, and the credited artists.
+pub fn album_artists_different(
+    id: AlbumId,
+    credit_a: &str,
+    a: &[ArtistCredit],
+    credit_b: &str,
+    b: &[ArtistCredit])
+    -> Option<IssueDetail>
+{
+    let ids_equal = a.len() == b.len() && a
+        .iter()
+        .zip(b)
+        .all(|(x, y)| x.artist_id == y.artist_id);
+
+    if credit_a == credit_b && ids_equal {
+        return None
     }
 
-    None
+    // If only the ids differ, include them, otherwise the message would be
+    // confusing.
+    let describe = |credit: &str, credits: &[ArtistCredit]| if credit_a == credit_b {
+        let ids: Vec<String> = credits.iter().map(|c| c.artist_id.to_string()).collect();
+        format!("{} ({})", credit, ids.join(", "))
+    } else {
+        credit.into()
+    };
+
+    Some(IssueDetail::AlbumArtistMismatch(
+        id,
+        describe(credit_a, a),
+        describe(credit_b, b),
+    ))
 }
 
 /// Return an issue if the two artists are not equal.
@@ -254,14 +636,39 @@ pub fn artists_different(
 
 pub struct BuildMetaIndex {
     pub artists: BTreeMap<ArtistId, Artist>,
+    /// Artists that we have only seen as track artist, not as album artist.
+    pub track_only_artists: BTreeMap<ArtistId, Artist>,
     pub albums: BTreeMap<AlbumId, Album>,
+    pub album_artists: BTreeMap<AlbumId, Vec<ArtistCredit>>,
     pub tracks: BTreeMap<TrackId, Track>,
+    /// Bits per sample of the file of every track, to pick one of duplicates.
+    pub track_bits_per_sample: HashMap<TrackId, u32>,
+    /// The credited track artists, for tracks that have their ids.
+    pub track_artists: BTreeMap<TrackId, Vec<ArtistId>>,
+    /// Full MusicBrainz ids, for tracks and albums that have any.
+    pub track_mbids: BTreeMap<TrackId, TrackMbids>,
+    pub album_mbids: BTreeMap<AlbumId, AlbumMbids>,
+    /// Release details, for albums that have any.
+    pub album_details: BTreeMap<AlbumId, AlbumDetails>,
+    /// The genres of all tracks of the album, in the order we encountered them.
+    pub album_genres: BTreeMap<AlbumId, Vec<StringRef>>,
+    pub composers: BTreeMap<ComposerId, Composer>,
+    pub track_composers: BTreeMap<TrackId, Vec<ComposerId>>,
+    pub works: BTreeMap<WorkId, Work>,
+    pub track_works: BTreeMap<TrackId, TrackWork>,
+    /// Disc subtitles, keyed by the id of track 0 on the disc.
+    pub disc_subtitles: BTreeMap<TrackId, StringRef>,
+    /// The genres and disc subtitle of every track, to update those of the
+    /// album and disc when we remove a duplicate.
+    pub track_genres: HashMap<TrackId, Vec<StringRef>>,
+    pub track_disc_subtitles: HashMap<TrackId, StringRef>,
     pub strings: StringDeduper,
     pub filenames: Vec<String>,
 
     pub words_artist: BTreeSet<(String, ArtistId, WordMeta)>,
     pub words_album: BTreeSet<(String, AlbumId, WordMeta)>,
     pub words_track: BTreeSet<(String, TrackId, WordMeta)>,
+    pub words_composer: BTreeSet<(String, ComposerId, WordMeta)>,
 
     // For album and artist data, store the file from which the metadata was
     // taken. This is later used for error reporting when the builders are
@@ -276,22 +683,96 @@ pub struct BuildMetaIndex {
     // TODO: This option, to drop it when processing is done, is a bit of a
     // hack. It would be nice to not have it in the builder at all.
     pub progress: Option<SyncSender<Progress>>,
+
+    /// Whether to derive ids for files without MusicBrainz ids, rather than
+    /// refusing them.
+    pub fallback_ids: bool,
 }
 
 impl BuildMetaIndex {
-    pub fn new(progress: SyncSender<Progress>) -> BuildMetaIndex {
+    pub fn new(progress: SyncSender<Progress>, fallback_ids: bool) -> BuildMetaIndex {
         BuildMetaIndex {
             artists: BTreeMap::new(),
+            track_only_artists: BTreeMap::new(),
             albums: BTreeMap::new(),
+            album_artists: BTreeMap::new(),
             tracks: BTreeMap::new(),
+            track_bits_per_sample: HashMap::new(),
+            track_artists: BTreeMap::new(),
+            track_mbids: BTreeMap::new(),
+            album_mbids: BTreeMap::new(),
+            album_details: BTreeMap::new(),
+            album_genres: BTreeMap::new(),
+            composers: BTreeMap::new(),
+            track_composers: BTreeMap::new(),
+            works: BTreeMap::new(),
+            track_works: BTreeMap::new(),
+            disc_subtitles: BTreeMap::new(),
+            track_genres: HashMap::new(),
+            track_disc_subtitles: HashMap::new(),
             strings: StringDeduper::new(),
             filenames: Vec::new(),
             words_artist: BTreeSet::new(),
             words_album: BTreeSet::new(),
             words_track: BTreeSet::new(),
+            words_composer: BTreeSet::new(),
             album_sources: HashMap::new(),
             artist_sources: HashMap::new(),
             progress: Some(progress),
+            fallback_ids: fallback_ids,
+        }
+    }
+
+    /// Remove a track that turned out to be a duplicate, see `prefer_track_file`.
+    fn remove_track(&mut self, track_id: TrackId) {
+        let track = self.tracks.remove(&track_id).expect("Can only remove a track that exists.");
+        self.track_bits_per_sample.remove(&track_id);
+        self.track_artists.remove(&track_id);
+        self.track_mbids.remove(&track_id);
+        self.track_composers.remove(&track_id);
+        self.track_works.remove(&track_id);
+        let stale_words: Vec<_> = self.words_track
+            .iter()
+            .filter(|&&(_, id, _)| id == track_id)
+            .cloned()
+            .collect();
+        for word in stale_words {
+            self.words_track.remove(&word);
+        }
+
+        // The genres of the track were merged into those of the album, drop
+        // the ones that no other track of the album has.
+        let album_id = track_id.album_id();
+        if let Some(genres) = self.track_genres.remove(&track_id) {
+            let remaining: Vec<StringRef> = self.track_genres
+                .iter()
+                .filter(|&(id, _)| id.album_id() == album_id)
+                .flat_map(|(_, genres)| genres.iter().cloned())
+                .collect();
+            if let Some(album_genres) = self.album_genres.get_mut(&album_id) {
+                album_genres.retain(|g| !genres.contains(g) || remaining.contains(g));
+                if album_genres.is_empty() {
+                    self.album_genres.remove(&album_id);
+                }
+            }
+        }
+
+        // If the disc subtitle came from this track, take it from another
+        // track on the disc, if any has one.
+        if let Some(subtitle) = self.track_disc_subtitles.remove(&track_id) {
+            let disc_id = get_track_id(album_id, track.disc_number, 0);
+            if self.disc_subtitles.get(&disc_id) == Some(&subtitle) {
+                let tracks = &self.tracks;
+                let replacement = self.track_disc_subtitles
+                    .iter()
+                    .filter(|&(id, _)| id.album_id() == album_id && tracks[id].disc_number == track.disc_number)
+                    .min_by_key(|&(id, _)| *id)
+                    .map(|(_, &subtitle)| subtitle);
+                match replacement {
+                    Some(other) => self.disc_subtitles.insert(disc_id, other),
+                    None => self.disc_subtitles.remove(&disc_id),
+                };
+            }
         }
     }
 
@@ -300,12 +781,20 @@ impl BuildMetaIndex {
         self.progress.as_mut().unwrap().send(Progress::Issue(issue)).unwrap();
     }
 
+    pub fn error_read_failed(&mut self, filename: String, err: claxon::Error) {
+        self.issue(filename, IssueDetail::ReadFailed(err.to_string()));
+    }
+
     fn error_missing_field(&mut self, filename: String, field: &'static str) {
-        self.issue(filename, IssueDetail::FieldMissingError(field));
+        self.issue(filename, IssueDetail::FieldMissingError(field.to_string()));
     }
 
     fn warning_missing_field(&mut self, filename: String, field: &'static str) {
-        self.issue(filename, IssueDetail::FieldMissingWarning(field));
+        self.issue(filename, IssueDetail::FieldMissingWarning(field.to_string()));
+    }
+
+    fn warning_fallback_id_used(&mut self, filename: String, field: &'static str) {
+        self.issue(filename, IssueDetail::FallbackIdUsed(field.to_string()));
     }
 
     fn warning_track_title_contains_feat(&mut self, filename: String) {
@@ -313,7 +802,11 @@ impl BuildMetaIndex {
     }
 
     fn error_parse_failed(&mut self, filename: String, field: &'static str) {
-        self.issue(filename, IssueDetail::FieldParseFailedError(field));
+        self.issue(filename, IssueDetail::FieldParseFailedError(field.to_string()));
+    }
+
+    fn warning_parse_failed(&mut self, filename: String, field: &'static str) {
+        self.issue(filename, IssueDetail::FieldParseFailedWarning(field.to_string()));
     }
 
     fn error_not_stereo(&mut self, filename: String) {
@@ -324,14 +817,12 @@ impl BuildMetaIndex {
         self.issue(filename, IssueDetail::UnsupportedBitDepth(bits));
     }
 
-    pub fn insert(
-        &mut self,
-        filename: &str,
-        streaminfo: &claxon::metadata::StreamInfo,
-        tags: &mut claxon::metadata::Tags
-    ) {
+    pub fn insert(&mut self, filename: &str, file: &FileTags) {
         let mut disc_number = None;
         let mut track_number = None;
+        let mut vinyl_disc_number = None;
+        let mut disc_total = None;
+        let mut track_total = None;
         let mut title = None;
         let mut album = None;
         let mut artist = None;
@@ -343,7 +834,29 @@ impl BuildMetaIndex {
         let mut album_loudness = None;
 
         let mut mbid_album = 0;
-        let mut mbid_artist = 0;
+        let mut mbid_release = Mbid::NIL;
+        let mut mbid_release_group = Mbid::NIL;
+        let mut mbid_recording = Mbid::NIL;
+        let mut mbid_track = Mbid::NIL;
+        let mut mbid_artists = Vec::new();
+        let mut album_artist_names = Vec::new();
+        let mut album_artist_names_for_sort = Vec::new();
+        let mut artist_for_sort = None;
+        let mut mbid_track_artists = Vec::new();
+        let mut track_artist_names = Vec::new();
+        let mut track_artist_names_for_sort = Vec::new();
+        let mut genres = Vec::new();
+        let mut composers = Vec::new();
+        let mut label = None;
+        let mut catalogue_number = None;
+        let mut release_country = None;
+        let mut release_types = Vec::new();
+        let mut release_status = None;
+        let mut mbid_work = 0;
+        let mut work = None;
+        let mut disc_subtitle = None;
+        let mut movement_name = None;
+        let mut movement_number = None;
 
         let filename_id = self.filenames.len() as u32;
         let filename_string = filename.to_string();
@@ -353,35 +866,117 @@ impl BuildMetaIndex {
         // all 16k tracks in my library are stereo. The same holds for bit
         // depths, in practice 16 or 24 bits per sample are used, so for
         // playback I only support these.
-        if streaminfo.channels != 2 {
+        if file.channels != 2 {
             return self.error_not_stereo(filename_string);
         }
-        match streaminfo.bits_per_sample {
+        match file.bits_per_sample {
             16 => { /* Ok, supported. */ }
             24 => { /* Ok, supported. */ }
             n => return self.error_unsupported_bit_depth(filename_string, n),
         }
 
-        for (tag, value) in tags {
+        for &(ref tag, ref value) in &file.tags {
+            let value = &value[..];
             match &tag.to_ascii_lowercase()[..] {
-                // TODO: Replace unwraps here with proper parse error reporting.
                 "album"                     => album = Some(self.strings.insert(value)),
                 "albumartist"               => album_artist = Some(self.strings.insert(value)),
+                "albumartists"              => album_artist_names.push(value),
+                "albumartistssort"          => album_artist_names_for_sort.push(value),
                 "albumartistsort"           => album_artist_for_sort = Some(self.strings.insert(value)),
                 "artist"                    => artist = Some(self.strings.insert(value)),
-                "discnumber"                => disc_number = Some(u8::from_str(value).unwrap()),
-                "musicbrainz_albumartistid" => mbid_artist = match parse_uuid(value) {
-                    Some(id) => id,
-                    None => return self.error_parse_failed(filename_string, "musicbrainz_albumartistid"),
+                "artists"                   => track_artist_names.push(value),
+                "artistssort"               => track_artist_names_for_sort.push(value),
+                "artistsort"                => artist_for_sort = Some(value),
+                "catalognumber"             => catalogue_number = catalogue_number.or(Some(value)),
+                "composer"                  => composers.push(value),
+                "discnumber"                => match parse_number_of_total(value) {
+                    Some((n, total)) => { disc_number = Some(n); disc_total = total.or(disc_total); }
+                    None => return self.error_parse_failed(filename_string, "discnumber"),
+                },
+                "discsubtitle"              => disc_subtitle = Some(value),
+                // Totals are only informative, if one is invalid, we treat it
+                // as unknown.
+                "disctotal" | "totaldiscs"  => match u8::from_str(value.trim()) {
+                    Ok(n) => disc_total = Some(n),
+                    Err(_) => self.warning_parse_failed(filename_string.clone(), "disctotal"),
+                },
+                // There is one tag per artist, but some taggers put all of
+                // them in a single tag, separated by semicolons.
+                "musicbrainz_albumartistid" => for part in value.split(';') {
+                    match parse_uuid(part.trim()) {
+                        Some(id) => mbid_artists.push(id),
+                        None => return self.error_parse_failed(filename_string, "musicbrainz_albumartistid"),
+                    }
+                },
+                // Track artist ids are optional, so if one is invalid, we skip
+                // that artist, but we keep its place to line up with the names.
+                "musicbrainz_artistid"      => for part in value.split(';') {
+                    let id = parse_uuid(part.trim());
+                    if id.is_none() {
+                        self.warning_parse_failed(filename_string.clone(), "musicbrainz_artistid");
+                    }
+                    mbid_track_artists.push(id);
+                },
+                "musicbrainz_albumid"       => match (parse_uuid(value), Mbid::parse(value)) {
+                    (Some(id), Some(mbid)) => { mbid_album = id; mbid_release = mbid; }
+                    _ => return self.error_parse_failed(filename_string, "musicbrainz_albumid"),
                 },
-                "musicbrainz_albumid"       => mbid_album = match parse_uuid(value) {
-                    Some(id) => id,
-                    None => return self.error_parse_failed(filename_string, "musicbrainz_albumid"),
+                // Without a valid work id, we identify the work by composer and title.
+                "musicbrainz_workid"        => match parse_uuid(value) {
+                    Some(id) => mbid_work = id,
+                    None => self.warning_parse_failed(filename_string.clone(), "musicbrainz_workid"),
                 },
+                // We only store these ids, we do not need them to index the
+                // file, so if one is invalid, we ignore it.
+                "musicbrainz_releasegroupid" => match Mbid::parse(value) {
+                    Some(mbid) => mbid_release_group = mbid,
+                    None => self.warning_parse_failed(filename_string.clone(), "musicbrainz_releasegroupid"),
+                },
+                // Confusingly, Picard writes the recording id to 'trackid',
+                // and the id of the track on the release to 'releasetrackid'.
+                "musicbrainz_trackid"       => match Mbid::parse(value) {
+                    Some(mbid) => mbid_recording = mbid,
+                    None => self.warning_parse_failed(filename_string.clone(), "musicbrainz_trackid"),
+                },
+                "musicbrainz_releasetrackid" => match Mbid::parse(value) {
+                    Some(mbid) => mbid_track = mbid,
+                    None => self.warning_parse_failed(filename_string.clone(), "musicbrainz_releasetrackid"),
+                },
+                // There is one tag per genre, but like for artist ids, some
+                // taggers put all of them in a single tag.
+                "genre"                     => genres.extend(
+                    value.split(';').map(|g| g.trim()).filter(|g| !g.is_empty())
+                ),
+                // A release can have multiple labels, we keep the first.
+                "label"                     => label = label.or(Some(value)),
+                // Confusingly, Picard writes the movement number to 'movement'.
+                "movement"                  => match parse_number_of_total(value) {
+                    Some((n, _total)) => movement_number = Some(n),
+                    None => self.warning_parse_failed(filename_string.clone(), "movement"),
+                },
+                "movementname"              => movement_name = Some(value),
                 "originaldate"              => original_date = parse_date(value),
+                "releasecountry"            => release_country = Some(value),
+                "releasestatus"             => release_status = Some(value),
+                // There is one tag per release type, the first one is the
+                // primary type. Some taggers put all of them in a single tag.
+                "releasetype"               => release_types.extend(
+                    value.split(';').map(|t| t.trim()).filter(|t| !t.is_empty())
+                ),
                 "date"                      => date = parse_date(value),
                 "title"                     => title = Some(self.strings.insert(value)),
-                "tracknumber"               => track_number = Some(u8::from_str(value).unwrap()),
+                "tracknumber"               => match parse_track_number_of_total(value) {
+                    Some((n, total)) => { track_number = Some(n); track_total = total.or(track_total); }
+                    None => match parse_vinyl_track_number(value) {
+                        Some((disc, n)) => { track_number = Some(n as u16); vinyl_disc_number = Some(disc); }
+                        None => return self.error_parse_failed(filename_string, "tracknumber"),
+                    },
+                },
+                "work"                      => work = Some(value),
+                "tracktotal" | "totaltracks" => match u16::from_str(value.trim()) {
+                    Ok(n) if n <= MAX_TRACK_NUMBER => track_total = Some(n),
+                    _ => self.warning_parse_failed(filename_string.clone(), "tracktotal"),
+                },
                 "bs17704_track_loudness"    => track_loudness = match Lufs::from_str(value) {
                     Ok(v) => Some(v),
                     // Unfortunately we have no way to include more details
@@ -397,15 +992,10 @@ impl BuildMetaIndex {
             }
         }
 
-        if mbid_album == 0 {
-            return self.error_missing_field(filename_string, "musicbrainz_albumid")
-        }
-        if mbid_artist == 0 {
-            return self.error_missing_field(filename_string, "musicbrainz_albumartistid")
-        }
-
         // TODO: Make a macro for this, this is terrible.
-        let f_disc_number = disc_number.unwrap_or(1);
+        // An explicit disc number takes precedence over the one implied by the
+        // vinyl side.
+        let f_disc_number = disc_number.or(vinyl_disc_number).unwrap_or(1);
         let f_track_number = match track_number {
             Some(t) => t,
             None => return self.error_missing_field(filename_string, "tracknumber"),
@@ -433,6 +1023,36 @@ impl BuildMetaIndex {
             None => return self.error_missing_field(filename_string, "originaldate"),
         };
 
+        // Without MusicBrainz ids, we can still identify the album by its
+        // artist, title, and date, if that is enabled. Emit only one warning,
+        // if one id is missing, the other one likely is too.
+        if mbid_album == 0 {
+            if !self.fallback_ids {
+                return self.error_missing_field(filename_string, "musicbrainz_albumid")
+            }
+            self.warning_fallback_id_used(filename_string.clone(), "musicbrainz_albumid");
+            mbid_album = fallback_id(&[
+                self.strings.get(f_album_artist),
+                self.strings.get(f_album),
+                &f_date.to_string(),
+            ]);
+        }
+        if mbid_artists.is_empty() {
+            if !self.fallback_ids {
+                return self.error_missing_field(filename_string, "musicbrainz_albumartistid")
+            }
+            if mbid_album & FALLBACK_ID_BIT == 0 {
+                self.warning_fallback_id_used(filename_string.clone(), "musicbrainz_albumartistid");
+            }
+            if album_artist_names.is_empty() {
+                mbid_artists.push(fallback_id(&[self.strings.get(f_album_artist)]));
+            } else {
+                for name in &album_artist_names {
+                    mbid_artists.push(fallback_id(&[*name]));
+                }
+            }
+        }
+
         // Emit a warning when loudness is not present. Emit only one of the two
         // warnings, because it is likely that both are absent, and then you get
         // two warnings per file, which is extremely noisy.
@@ -452,10 +1072,46 @@ impl BuildMetaIndex {
             }
         }
 
-        let artist_id = ArtistId(mbid_artist);
-        let album_id = AlbumId(mbid_album);
+        let album_id = get_album_id(mbid_album);
         let track_id = get_track_id(album_id, f_disc_number, f_track_number);
 
+        // The same track can occur twice, for example when an album was ripped
+        // twice. Keep one of the files, and report the other one.
+        if let Some(existing) = self.tracks.get(&track_id) {
+            let existing_filename = self.filenames[existing.filename.0 as usize].clone();
+            let existing_bits = self.track_bits_per_sample[&track_id];
+            if prefer_track_file(existing_bits, &existing_filename, file.bits_per_sample, filename) {
+                return self.issue(filename_string, IssueDetail::DuplicateTrack(track_id, existing_filename))
+            }
+            self.issue(existing_filename, IssueDetail::DuplicateTrack(track_id, filename_string.clone()));
+            self.remove_track(track_id);
+        }
+
+        // The album artist tag holds the credit as displayed, e.g. "A feat. B".
+        // To credit the individual artists, we need their names. Picard writes
+        // those to 'albumartists', if that is missing we try to split the
+        // credit.
+        let credit = self.strings.get(f_album_artist).to_string();
+        let names = match credited_names(&credit, mbid_artists.len(), &album_artist_names) {
+            Some(names) => names,
+            None => {
+                // We can't tell the artists apart, credit only the first one.
+                self.warning_missing_field(filename_string.clone(), "albumartists");
+                mbid_artists.truncate(1);
+                vec![&credit[..]]
+            }
+        };
+        let join_phrases = match parse_join_phrases(&credit, &names) {
+            Some(phrases) => phrases,
+            // The names don't occur in the credit in order, so we don't know
+            // what goes between them. Make up something reasonable.
+            None => {
+                let mut phrases = vec![", "; names.len() - 1];
+                phrases.push("");
+                phrases
+            }
+        };
+
         // Split the title, album, and album artist, on words, and add those to
         // the indexes, to allow finding the track/album/artist later by word.
         let mut words = Vec::new();
@@ -473,9 +1129,7 @@ impl BuildMetaIndex {
             // intersection empty.
             normalize_words(album_artist, &mut words_album_artist);
             for (i, w) in words_album_artist.iter().enumerate() {
-                let meta_rank_2 = WordMeta::new(w.len(), album_artist.len(), i, 2);
                 let meta_rank_0 = WordMeta::new(w.len(), album_artist.len(), i, 0);
-                self.words_artist.insert((w.clone(), artist_id, meta_rank_2));
                 self.words_album.insert((w.clone(),  album_id,  meta_rank_0));
                 self.words_track.insert((w.clone(),  track_id,  meta_rank_0));
             }
@@ -504,94 +1158,279 @@ impl BuildMetaIndex {
             }
         }
 
-        // Normalize the sort artist too. Generally, the only thing it is useful
-        // for is to turn e.g. "The Who" into "Who, The". (Data from Musicbrainz
-        // also puts the last name first for artists who use their real name,
-        // but I dislike this.) But this is not sufficient for sorting alone:
-        // there can still be case differences (e.g. "dEUS" and "deadmau5"
-        // sorting last because they are lowercase) and accents (e.g. "Étienne
-        // de Crécy" sorting last, and not with the "E"). The correct sort
-        // ordering depends on locale. I am going to ignore all of that and turn
-        // characters into the lowercase ascii character that looks most like
-        // it, then sort by that.
-        // TODO: Avoid inserting non-normalized sort artist in the string
-        // deduplicator above; the non-normalized string is never referenced
-        // except here temporarily.
-        normalize_words(&self.strings.get(album_artist_for_sort.unwrap_or(f_album_artist)), &mut words);
-        let sort_artist = words.join(" ");
-        let f_album_artist_for_sort = self.strings.insert(&sort_artist);
-        words.clear();
+        let mut credits = Vec::with_capacity(names.len());
+        let mut artists = Vec::with_capacity(names.len());
+
+        for (i, (&mbid, &name)) in mbid_artists.iter().zip(&names).enumerate() {
+            let artist_id = ArtistId(mbid);
+
+            // The artist index only gets the words of the artist's own name,
+            // so that e.g. "A & B" is not found when searching for "B".
+            normalize_words(name, &mut words);
+            for (j, w) in words.drain(..).enumerate() {
+                let meta_rank_2 = WordMeta::new(w.len(), name.len(), j, 2);
+                self.words_artist.insert((w, artist_id, meta_rank_2));
+            }
+
+            let name_for_sort = if names.len() == 1 {
+                self.strings.get(album_artist_for_sort.unwrap_or(f_album_artist)).to_string()
+            } else if album_artist_names_for_sort.len() == names.len() {
+                album_artist_names_for_sort[i].to_string()
+            } else {
+                name.to_string()
+            };
+            let sort_artist = normalize_sort_name(&name_for_sort);
+
+            let artist = Artist {
+                name: StringRef(self.strings.insert(name)),
+                name_for_sort: StringRef(self.strings.insert(&sort_artist)),
+            };
+            credits.push(ArtistCredit {
+                artist_id: artist_id,
+                join_phrase: StringRef(self.strings.insert(join_phrases[i])),
+                _padding: 0,
+            });
+            artists.push((artist_id, artist));
+        }
+
+        // Track artists are usually the album artists, but for compilations
+        // and feat. artists they are not. Those get an artist entry too, so we
+        // can show the tracks they appear on. Files without track artist ids
+        // are fine, then we only have the credit.
+        let track_credit = self.strings.get(f_track_artist).to_string();
+        let track_names = match credited_names(&track_credit, mbid_track_artists.len(), &track_artist_names) {
+            Some(names) => names,
+            None if mbid_track_artists.is_empty() => Vec::new(),
+            None => {
+                self.warning_missing_field(filename_string.clone(), "artists");
+                mbid_track_artists.truncate(1);
+                vec![&track_credit[..]]
+            }
+        };
+        let mut track_artist_ids = Vec::with_capacity(track_names.len());
+
+        for (i, (&mbid, &name)) in mbid_track_artists.iter().zip(&track_names).enumerate() {
+            let artist_id = match mbid {
+                Some(id) => ArtistId(id),
+                None => continue,
+            };
+            track_artist_ids.push(artist_id);
+
+            // If the artist is an album artist, that entry takes precedence.
+            let is_album_artist = artists.iter().any(|&(id, _)| id == artist_id);
+            if is_album_artist || self.artists.contains_key(&artist_id) {
+                continue
+            }
+
+            normalize_words(name, &mut words);
+            for (j, w) in words.drain(..).enumerate() {
+                let meta_rank_2 = WordMeta::new(w.len(), name.len(), j, 2);
+                self.words_artist.insert((w, artist_id, meta_rank_2));
+            }
+
+            if self.track_only_artists.contains_key(&artist_id) {
+                continue
+            }
 
-        // TODO: Check for u16 overflow.
-        // TODO: Warn if `streaminfo.samples` is None.
-        let samples = streaminfo.samples.unwrap_or(0);
-        let seconds = (samples + streaminfo.sample_rate as u64 / 2) / streaminfo.sample_rate as u64;
+            let name_for_sort = if track_names.len() == 1 {
+                artist_for_sort.unwrap_or(name)
+            } else if track_artist_names_for_sort.len() == track_names.len() {
+                track_artist_names_for_sort[i]
+            } else {
+                name
+            };
+            let artist = Artist {
+                name: StringRef(self.strings.insert(name)),
+                name_for_sort: StringRef(self.strings.insert(&normalize_sort_name(name_for_sort))),
+            };
+            self.track_only_artists.insert(artist_id, artist);
+        }
+
+        // TODO: Warn if `file.samples` is None.
+        let samples = file.samples.unwrap_or(0);
+        let seconds = (samples + file.sample_rate as u64 / 2) / file.sample_rate as u64;
 
         let track = Track {
-            album_id: album_id,
             disc_number: f_disc_number,
             track_number: f_track_number,
+            track_total: track_total.unwrap_or(0),
             title: StringRef(f_title),
             artist: StringRef(f_track_artist),
-            duration_seconds: seconds as u16,
+            duration_seconds: seconds as u32,
             filename: FilenameRef(filename_id),
-            loudness: track_loudness,
+            loudness: OptionLufs::new(track_loudness),
+            _padding: 0,
         };
         let album = Album {
-            artist_id: artist_id,
+            artist_id: credits[0].artist_id,
+            artist: StringRef(f_album_artist),
             title: StringRef(f_album),
             original_release_date: f_date,
-            loudness: album_loudness,
-        };
-        let artist = Artist {
-            name: StringRef(f_album_artist),
-            name_for_sort: StringRef(f_album_artist_for_sort),
+            loudness: OptionLufs::new(album_loudness),
+            disc_total: disc_total.unwrap_or(0),
+            _padding: 0,
         };
 
         let mut add_album = true;
-        let mut add_artist = true;
 
         // Check for consistency if duplicates occur.
-        if self.tracks.get(&track_id).is_some() {
-            // TODO: This should report an `Issue`, not panic.
-            panic!("Duplicate track {}, file {}.", track_id, filename);
-        }
-
         if let Some(existing_album) = self.albums.get(&album_id) {
             if let Some(detail) = albums_different(&self.strings, album_id, existing_album, &album) {
                 let issue = detail.for_file(filename_string.clone());
                 self.progress.as_mut().unwrap().send(Progress::Issue(issue)).unwrap();
             }
+            if let Some(detail) = album_artists_different(
+                album_id,
+                self.strings.get(existing_album.artist.0),
+                &self.album_artists[&album_id],
+                &credit,
+                &credits,
+            ) {
+                let issue = detail.for_file(filename_string.clone());
+                self.progress.as_mut().unwrap().send(Progress::Issue(issue)).unwrap();
+            }
             add_album = false;
         }
 
-        if let Some(existing_artist) = self.artists.get(&artist_id) {
-            if let Some(detail) = artists_different(&self.strings, artist_id, existing_artist, &artist) {
-                let issue = detail.for_file(filename_string.clone());
-                self.progress.as_mut().unwrap().send(Progress::Issue(issue)).unwrap();
+        for (artist_id, artist) in artists {
+            if let Some(existing_artist) = self.artists.get(&artist_id) {
+                if let Some(detail) = artists_different(&self.strings, artist_id, existing_artist, &artist) {
+                    let issue = detail.for_file(filename_string.clone());
+                    self.progress.as_mut().unwrap().send(Progress::Issue(issue)).unwrap();
+                }
+            } else {
+                self.artists.insert(artist_id, artist);
+                self.artist_sources.insert(artist_id, FilenameRef(filename_id));
+                self.track_only_artists.remove(&artist_id);
             }
-            add_artist = false;
         }
 
         self.filenames.push(filename_string);
         self.tracks.insert(track_id, track);
+        self.track_bits_per_sample.insert(track_id, file.bits_per_sample);
+
+        if !track_artist_ids.is_empty() {
+            self.track_artists.insert(track_id, track_artist_ids);
+        }
+
+        if mbid_recording != Mbid::NIL || mbid_track != Mbid::NIL {
+            let mbids = TrackMbids {
+                recording: mbid_recording,
+                track: mbid_track,
+            };
+            self.track_mbids.insert(track_id, mbids);
+        }
+
+        let mut composer_ids = Vec::with_capacity(composers.len());
+        for &name in &composers {
+            // Tags have no ids for composers, so we identify them by name.
+            let composer_id = ComposerId(fallback_id(&[name]));
+            if !self.composers.contains_key(&composer_id) {
+                normalize_words(name, &mut words);
+                for (j, w) in words.drain(..).enumerate() {
+                    let meta_rank_2 = WordMeta::new(w.len(), name.len(), j, 2);
+                    self.words_composer.insert((w, composer_id, meta_rank_2));
+                }
+                let composer = Composer { name: StringRef(self.strings.insert(name)) };
+                self.composers.insert(composer_id, composer);
+            }
+            composer_ids.push(composer_id);
+        }
+        if !composer_ids.is_empty() {
+            self.track_composers.insert(track_id, composer_ids);
+        }
+
+        if let Some(title) = work {
+            // Without a MusicBrainz id, we identify the work by its composer
+            // and title, so a work with the same title by another composer is
+            // a different work.
+            let work_id = match mbid_work {
+                0 => WorkId(fallback_id(&[composers.first().cloned().unwrap_or(""), title])),
+                id => WorkId(id),
+            };
+            if !self.works.contains_key(&work_id) {
+                let work = Work { title: StringRef(self.strings.insert(title)) };
+                self.works.insert(work_id, work);
+            }
+            let track_work = TrackWork {
+                work_id: work_id,
+                movement_name: StringRef(self.strings.insert(movement_name.unwrap_or(""))),
+                movement_number: movement_number.unwrap_or(0),
+                _padding: [0; 3],
+            };
+            self.track_works.insert(track_id, track_work);
+        }
+
+        // The subtitle is tagged on every track of the disc, the first one wins.
+        if let Some(subtitle) = disc_subtitle {
+            let subtitle_ref = StringRef(self.strings.insert(subtitle));
+            let disc_id = get_track_id(album_id, f_disc_number, 0);
+            if !self.disc_subtitles.contains_key(&disc_id) {
+                self.disc_subtitles.insert(disc_id, subtitle_ref);
+            }
+            self.track_disc_subtitles.insert(track_id, subtitle_ref);
+        }
+
+        // Genres are tagged per track, the album gets the genres of all of its
+        // tracks.
+        if !genres.is_empty() {
+            let genre_refs: Vec<StringRef> = genres
+                .iter()
+                .map(|genre| StringRef(self.strings.insert(genre)))
+                .collect();
+            let album_genres = self.album_genres.entry(album_id).or_insert_with(Vec::new);
+            for &genre_ref in &genre_refs {
+                if !album_genres.contains(&genre_ref) {
+                    album_genres.push(genre_ref);
+                }
+            }
+            self.track_genres.insert(track_id, genre_refs);
+        }
 
         if add_album {
+            if mbid_release != Mbid::NIL || mbid_release_group != Mbid::NIL {
+                let mbids = AlbumMbids {
+                    release: mbid_release,
+                    release_group: mbid_release_group,
+                };
+                self.album_mbids.insert(album_id, mbids);
+            }
+            let has_details = label.is_some()
+                || catalogue_number.is_some()
+                || release_country.is_some()
+                || release_status.is_some()
+                || !release_types.is_empty();
+            if has_details {
+                let details = AlbumDetails {
+                    label: StringRef(self.strings.insert(label.unwrap_or(""))),
+                    catalogue_number: StringRef(self.strings.insert(catalogue_number.unwrap_or(""))),
+                    release_country: StringRef(self.strings.insert(release_country.unwrap_or(""))),
+                    release_type: StringRef(self.strings.insert(release_types.first().cloned().unwrap_or(""))),
+                    release_status: StringRef(self.strings.insert(release_status.unwrap_or(""))),
+                    release_kind: ReleaseKind::from_release_types(release_types.iter().cloned()) as u8,
+                    _padding: [0; 3],
+                };
+                self.album_details.insert(album_id, details);
+            }
             self.albums.insert(album_id, album);
+            self.album_artists.insert(album_id, credits);
             self.album_sources.insert(album_id, FilenameRef(filename_id));
         }
-
-        if add_artist {
-            self.artists.insert(artist_id, artist);
-            self.artist_sources.insert(artist_id, FilenameRef(filename_id));
-        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Date};
-    use super::{parse_date};
+    use std::env;
+    use std::io;
+    use std::process;
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+
+    use crate::{ArtistId, MemoryMetaIndex};
+    use crate::index_file::FileMetadata;
+    use super::{BuildMetaIndex, Date, FileTags, Issue, Progress, ScanStatus};
+    use super::{FALLBACK_ID_BIT, fallback_id, parse_date, parse_join_phrases, parse_number_of_total, parse_track_number_of_total, parse_uuid, parse_vinyl_track_number, prefer_track_file, split_artist_credit};
 
     #[test]
     fn parse_date_parses_year() {
@@ -637,4 +1476,238 @@ mod tests {
     fn format_date_formats_year_and_month_and_day() {
         assert_eq!(format!("{}", Date::new(2018, 1, 2)), "2018-01-02");
     }
+
+    #[test]
+    fn parse_join_phrases_finds_text_between_names() {
+        assert_eq!(parse_join_phrases("A", &["A"]), Some(vec![""]));
+        assert_eq!(parse_join_phrases("A feat. B", &["A", "B"]), Some(vec![" feat. ", ""]));
+        assert_eq!(
+            parse_join_phrases("A, B & C (live)", &["A", "B", "C"]),
+            Some(vec![", ", " & ", " (live)"]),
+        );
+        assert_eq!(parse_join_phrases("The A & B", &["A", "B"]), None);
+        assert_eq!(parse_join_phrases("B & A", &["A", "B"]), None);
+    }
+
+    #[test]
+    fn split_artist_credit_splits_on_join_phrases() {
+        assert_eq!(split_artist_credit("A & B", 2), Some(vec!["A", "B"]));
+        assert_eq!(split_artist_credit("A, B and C", 3), Some(vec!["A", "B", "C"]));
+        assert_eq!(split_artist_credit("A feat. B", 2), Some(vec!["A", "B"]));
+        assert_eq!(split_artist_credit("A & B", 3), None);
+        assert_eq!(split_artist_credit("Simon & Garfunkel", 1), None);
+    }
+
+    #[test]
+    fn fallback_id_does_not_collide_with_mbid() {
+        let mbid = parse_uuid("ffffffff-ad74-44ce-90a4-7fa1dfd8164e").unwrap();
+        assert_eq!(mbid & FALLBACK_ID_BIT, 0);
+        assert_eq!(fallback_id(&["Tycho", "Dive", "2011"]) & FALLBACK_ID_BIT, FALLBACK_ID_BIT);
+    }
+
+    #[test]
+    fn fallback_id_is_stable_and_normalized() {
+        let id = fallback_id(&["The Who", "Tommy", "1969"]);
+        assert_eq!(id, fallback_id(&["the who", "TOMMY", "1969"]));
+        assert_ne!(id, fallback_id(&["The Who", "Tommy", "1975"]));
+        assert_ne!(fallback_id(&["ab", "c"]), fallback_id(&["a", "bc"]));
+        // The id ends up in the listen history, it must not change.
+        assert_eq!(id, 0x96c8_56d0_2a2c_28c7);
+    }
+
+    #[test]
+    fn prefer_track_file_prefers_higher_bit_depth_then_path() {
+        assert!(prefer_track_file(24, "/b/01.flac", 16, "/a/01.flac"));
+        assert!(!prefer_track_file(16, "/a/01.flac", 24, "/b/01.flac"));
+        assert!(prefer_track_file(16, "/a/01.flac", 16, "/b/01.flac"));
+        assert!(!prefer_track_file(16, "/b/01.flac", 16, "/a/01.flac"));
+    }
+
+    #[test]
+    fn parse_date_does_not_panic_on_multibyte_characters() {
+        assert_eq!(parse_date("201é"), None);
+        assert_eq!(parse_date("2018-é"), None);
+        assert_eq!(parse_date("2018-01-é"), None);
+    }
+
+    #[test]
+    fn parse_number_of_total_parses_number_and_total() {
+        let parse_disc = parse_number_of_total::<u8>;
+        assert_eq!(parse_disc("3"), Some((3, None)));
+        assert_eq!(parse_disc(" 4"), Some((4, None)));
+        assert_eq!(parse_disc("3/12"), Some((3, Some(12))));
+        assert_eq!(parse_disc("3 / 12"), Some((3, Some(12))));
+        assert_eq!(parse_disc("3/"), None);
+        assert_eq!(parse_disc("A1"), None);
+        assert_eq!(parse_disc("256"), None);
+        assert_eq!(parse_disc(""), None);
+        assert_eq!(parse_track_number_of_total("256/300"), Some((256, Some(300))));
+        assert_eq!(parse_track_number_of_total("4095"), Some((4095, None)));
+        assert_eq!(parse_track_number_of_total("4096"), None);
+        assert_eq!(parse_track_number_of_total("1/4096"), None);
+    }
+
+    #[test]
+    fn parse_vinyl_track_number_numbers_sides() {
+        assert_eq!(parse_vinyl_track_number("A1"), Some((1, 1)));
+        assert_eq!(parse_vinyl_track_number("a2"), Some((1, 2)));
+        assert_eq!(parse_vinyl_track_number("B3"), Some((1, 103)));
+        assert_eq!(parse_vinyl_track_number("C1"), Some((2, 1)));
+        assert_eq!(parse_vinyl_track_number("D12"), Some((2, 112)));
+        assert_eq!(parse_vinyl_track_number("A0"), None);
+        assert_eq!(parse_vinyl_track_number("A100"), None);
+        assert_eq!(parse_vinyl_track_number("Aé"), None);
+        assert_eq!(parse_vinyl_track_number("é1"), None);
+        assert_eq!(parse_vinyl_track_number("1"), None);
+    }
+
+    #[test]
+    fn from_files_reports_unreadable_file_as_issue() {
+        let path = env::temp_dir().join(format!("musium-test-missing-{}.flac", process::id()));
+        let metadata = FileMetadata { size: 0, mtime_secs: 0, mtime_nanos: 0 };
+        let status = Mutex::new(ScanStatus::new());
+        let (_index, scanned_files, issues) = MemoryMetaIndex::from_files(
+            &[(path.clone(), metadata)],
+            Vec::new(),
+            false,
+            &status,
+            io::sink(),
+        ).unwrap();
+
+        // The file is not recorded as scanned, so the next scan tries again.
+        assert!(scanned_files.is_empty());
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].filename, path.to_str().unwrap());
+        assert_eq!(issues[0].detail.kind(), "read_failed");
+    }
+
+    /// Insert one file with the given tags, return the builder and the issues.
+    fn insert_tags(tags: &[(&str, &str)]) -> (BuildMetaIndex, Vec<Issue>) {
+        let (sender, receiver) = mpsc::sync_channel(16);
+        let mut builder = BuildMetaIndex::new(sender, false);
+        let file = FileTags {
+            channels: 2,
+            bits_per_sample: 16,
+            sample_rate: 44_100,
+            samples: Some(44_100),
+            tags: tags.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect(),
+        };
+        builder.insert("/music/a.flac", &file);
+        builder.progress = None;
+        let issues = receiver.iter().filter_map(|p| match p {
+            Progress::Issue(issue) => Some(issue),
+            _ => None,
+        }).collect();
+        (builder, issues)
+    }
+
+    const BASIC_TAGS: &[(&str, &str)] = &[
+        ("album", "Dive"),
+        ("albumartist", "Tycho"),
+        ("artist", "Tycho"),
+        ("title", "A Walk"),
+        ("tracknumber", "1"),
+        ("originaldate", "2011-11-08"),
+        ("musicbrainz_albumid", "1f2a0bc4-4e7b-4b8a-9b2a-5a6c2c3e1d10"),
+        ("musicbrainz_albumartistid", "c2d11d2b-2d34-4b94-b3c9-6d8f1b7fa0b5"),
+    ];
+
+    fn parse_warnings(issues: &[Issue]) -> Vec<&str> {
+        issues
+            .iter()
+            .filter(|issue| !issue.detail.is_error() && issue.detail.kind() == "field_parse_failed")
+            .map(|issue| issue.detail.field().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn insert_skips_invalid_track_artist_id() {
+        let mut tags = BASIC_TAGS.to_vec();
+        tags.push(("artists", "Tycho"));
+        tags.push(("artists", "Benoît Pioulard"));
+        tags.push(("musicbrainz_artistid", "not-an-id; 0c1e4cf5-a2bb-4bd7-94c1-8c3d9e2b1f7a"));
+        let (builder, issues) = insert_tags(&tags);
+
+        assert_eq!(parse_warnings(&issues), vec!["musicbrainz_artistid"]);
+        assert_eq!(builder.tracks.len(), 1);
+        let artists = builder.track_artists.values().next().unwrap();
+        assert_eq!(artists.len(), 1);
+        assert_eq!(artists[0], ArtistId(parse_uuid("0c1e4cf5-a2bb-4bd7-94c1-8c3d9e2b1f7a").unwrap()));
+    }
+
+    #[test]
+    fn insert_ignores_invalid_optional_mbids() {
+        let mut tags = BASIC_TAGS.to_vec();
+        tags.push(("musicbrainz_releasegroupid", "not-an-id"));
+        tags.push(("musicbrainz_trackid", ""));
+        tags.push(("musicbrainz_releasetrackid", "1f2a0bc4"));
+        let (builder, issues) = insert_tags(&tags);
+
+        assert_eq!(
+            parse_warnings(&issues),
+            vec!["musicbrainz_releasegroupid", "musicbrainz_trackid", "musicbrainz_releasetrackid"],
+        );
+        assert_eq!(builder.tracks.len(), 1);
+    }
+
+    #[test]
+    fn insert_drops_genres_and_subtitle_of_duplicate() {
+        let (sender, _receiver) = mpsc::sync_channel(16);
+        let mut builder = BuildMetaIndex::new(sender, false);
+        let file = |genre: &str, subtitle: &str| FileTags {
+            channels: 2,
+            bits_per_sample: 16,
+            sample_rate: 44_100,
+            samples: Some(44_100),
+            tags: BASIC_TAGS
+                .iter()
+                .map(|&(k, v)| (k.to_string(), v.to_string()))
+                .chain(vec![
+                    ("genre".to_string(), genre.to_string()),
+                    ("discsubtitle".to_string(), subtitle.to_string()),
+                ])
+                .collect(),
+        };
+        // The second file sorts first, so it replaces the first one.
+        builder.insert("/music/b.flac", &file("Ambient", "Side A"));
+        builder.insert("/music/a.flac", &file("Electronic", "Part One"));
+
+        assert_eq!(builder.tracks.len(), 1);
+        let genres: Vec<&str> = builder.album_genres
+            .values()
+            .flat_map(|genres| genres.iter().map(|g| builder.strings.get(g.0)))
+            .collect();
+        assert_eq!(genres, vec!["Electronic"]);
+        let subtitles: Vec<&str> = builder.disc_subtitles
+            .values()
+            .map(|s| builder.strings.get(s.0))
+            .collect();
+        assert_eq!(subtitles, vec!["Part One"]);
+    }
+
+    #[test]
+    fn insert_ignores_invalid_totals() {
+        let mut tags = BASIC_TAGS.to_vec();
+        tags.push(("disctotal", "two"));
+        tags.push(("tracktotal", "99999"));
+        let (builder, issues) = insert_tags(&tags);
+
+        assert_eq!(parse_warnings(&issues), vec!["disctotal", "tracktotal"]);
+        assert_eq!(builder.tracks.len(), 1);
+    }
+
+    #[test]
+    fn insert_ignores_invalid_work_id_and_movement() {
+        let mut tags = BASIC_TAGS.to_vec();
+        tags.push(("work", "Symphony No. 9"));
+        tags.push(("musicbrainz_workid", "not-an-id"));
+        tags.push(("movement", "IV"));
+        let (builder, issues) = insert_tags(&tags);
+
+        assert_eq!(parse_warnings(&issues), vec!["musicbrainz_workid", "movement"]);
+        assert_eq!(builder.tracks.len(), 1);
+        let track_work = builder.track_works.values().next().unwrap();
+        assert_eq!(track_work.movement_number, 0);
+        assert_eq!(builder.works.len(), 1);
+    }
 }
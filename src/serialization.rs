@@ -12,8 +12,11 @@ use serde_json;
 use std::io;
 use std::io::Write;
 
-use crate::{Album, AlbumId, Artist, ArtistId, MetaIndex, TrackId};
-use crate::player::{Millibel, TrackSnapshot};
+use crate::{Album, AlbumId, Artist, ArtistId, Issue, MetaIndex, ScanStage, ScanStatus, TrackId};
+use crate::browse::{self, AlbumFilter};
+use crate::cover::{self, AlbumPicture};
+use crate::prim::{AlbumDetails, ComposerId, Track, Composer, Mbid, ReleaseKind, StringRef, WorkId};
+use crate::player::{DeviceStatus, Millibel, TrackSnapshot};
 
 /// Write an album, but only with the album details, not its tracks.
 ///
@@ -32,18 +35,140 @@ pub fn write_brief_album_json<W: Write>(
     write!(w, r#"{{"id":"{}","title":"#, album_id)?;
     serde_json::to_writer(&mut w, index.get_string(album.title))?;
     write!(w, r#","artist_id":"{}","artist":"#, album.artist_id)?;
-    serde_json::to_writer(&mut w, index.get_string(artist.name))?;
+    serde_json::to_writer(&mut w, index.get_string(album.artist))?;
     write!(w, r#","sort_artist":"#)?;
     serde_json::to_writer(&mut w, index.get_string(artist.name_for_sort))?;
     write!(w, r#","date":"{}"}}"#, album.original_release_date)?;
     Ok(())
 }
 
-/// Write a json representation of the album list to the writer.
-pub fn write_albums_json<W: Write>(index: &dyn MetaIndex, mut w: W) -> io::Result<()> {
+/// Write a MusicBrainz id as a json string, or `null` if it is absent.
+fn write_mbid_json<W: Write>(mut w: W, mbid: Option<Mbid>) -> io::Result<()> {
+    match mbid {
+        Some(mbid) => write!(w, r#""{}""#, mbid),
+        None => write!(w, "null"),
+    }
+}
+
+/// Write a string as json, or `null` if it is empty, which means it was not tagged.
+fn write_optional_string_json<W: Write>(mut w: W, value: &str) -> io::Result<()> {
+    match value {
+        "" => write!(w, "null"),
+        _ => Ok(serde_json::to_writer(&mut w, value)?),
+    }
+}
+
+/// Write the strings as a json array, ignoring the ids they are paired with.
+fn write_string_list_json<W: Write, T>(index: &dyn MetaIndex, mut w: W, xs: &[(T, StringRef)]) -> io::Result<()> {
+    write!(w, "[")?;
+    for (i, &(_, sr)) in xs.iter().enumerate() {
+        if i > 0 { write!(w, ",")?; }
+        serde_json::to_writer(&mut w, index.get_string(sr))?;
+    }
+    write!(w, "]")
+}
+
+/// Write a count as a json number, or `null` if it is 0, which means unknown.
+fn write_count_json<W: Write>(mut w: W, n: u16) -> io::Result<()> {
+    match n {
+        0 => write!(w, "null"),
+        n => write!(w, "{}", n),
+    }
+}
+
+/// Return the number of discs of the album.
+///
+/// If the total is not tagged, this is the highest disc number that we have.
+fn get_disc_count(index: &dyn MetaIndex, album_id: AlbumId, album: &Album) -> u8 {
+    match album.disc_total {
+        0 => index.get_album_tracks(album_id).last().map_or(1, |&(_, ref t)| t.disc_number),
+        n => n,
+    }
+}
+
+/// Write the disc number and total of a track, but only for multi-disc albums.
+///
+/// For single-disc albums, both are `null`, there is no point in showing
+/// "disc 1 of 1".
+fn write_disc_of_total_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    album_id: AlbumId,
+    album: &Album,
+    track: &Track,
+) -> io::Result<()> {
+    match get_disc_count(index, album_id, album) {
+        n if n > 1 => write!(w, r#","disc_number":{},"disc_total":{}"#, track.disc_number, n),
+        _ => write!(w, r#","disc_number":null,"disc_total":null"#),
+    }
+}
+
+/// Write the composers of the track as a json array of ids and names.
+fn write_track_composers_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: TrackId) -> io::Result<()> {
+    write!(w, "[")?;
+    for (i, &(_, composer_id)) in index.get_track_composers(id).iter().enumerate() {
+        let composer = index.get_composer(composer_id).unwrap();
+        if i > 0 { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","name":"#, composer_id)?;
+        serde_json::to_writer(&mut w, index.get_string(composer.name))?;
+        write!(w, "}}")?;
+    }
+    write!(w, "]")
+}
+
+/// Group tracks by the work they are part of, in order of first appearance.
+///
+/// Returns the works with their tracks, and the tracks that are not part of a work.
+fn group_by_work<I>(index: &dyn MetaIndex, track_ids: I) -> (Vec<(WorkId, Vec<TrackId>)>, Vec<TrackId>)
+where I: Iterator<Item = TrackId> {
+    let mut works: Vec<(WorkId, Vec<TrackId>)> = Vec::new();
+    let mut other = Vec::new();
+    for track_id in track_ids {
+        let work_id = match index.get_track_work(track_id) {
+            Some(track_work) => track_work.work_id,
+            None => { other.push(track_id); continue }
+        };
+        // There are only a handful of works per album or composer, so a
+        // linear search is fine here.
+        match works.iter_mut().find(|&&mut (id, _)| id == work_id) {
+            Some(&mut (_, ref mut tracks)) => tracks.push(track_id),
+            None => works.push((work_id, vec![track_id])),
+        }
+    }
+    (works, other)
+}
+
+/// Write the title of a work, and the start of its `tracks` array.
+fn write_work_header_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: WorkId) -> io::Result<()> {
+    let work = index.get_work(id).unwrap();
+    write!(w, r#"{{"id":"{}","title":"#, id)?;
+    serde_json::to_writer(&mut w, index.get_string(work.title))?;
+    write!(w, r#","tracks":["#)
+}
+
+/// Write the artists credited for the album, with the join phrases between them.
+fn write_album_artists_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: AlbumId) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for &(_, ref credit) in index.get_album_artists(id) {
+        let artist = index.get_artist(credit.artist_id).unwrap();
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","name":"#, credit.artist_id)?;
+        serde_json::to_writer(&mut w, index.get_string(artist.name))?;
+        write!(w, r#","join_phrase":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(credit.join_phrase))?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write a json representation of the albums that match the filter to the writer.
+pub fn write_albums_json<W: Write>(index: &dyn MetaIndex, mut w: W, filter: &AlbumFilter) -> io::Result<()> {
     write!(w, "[")?;
     let mut first = true;
     for &(id, ref album) in index.get_albums() {
+        if !filter.matches(index, id) { continue }
         if !first { write!(w, ",")?; }
         write_brief_album_json(index, &mut w, id, album)?;
         first = false;
@@ -51,6 +176,18 @@ pub fn write_albums_json<W: Write>(index: &dyn MetaIndex, mut w: W) -> io::Resul
     write!(w, "]")
 }
 
+/// Write a list of names with album counts, such as genres or labels, as json.
+pub fn write_album_counts_json<W: Write>(mut w: W, counts: &[(&str, u32)]) -> io::Result<()> {
+    write!(w, "[")?;
+    for (i, &(name, n)) in counts.iter().enumerate() {
+        if i > 0 { write!(w, ",")?; }
+        write!(w, r#"{{"name":"#)?;
+        serde_json::to_writer(&mut w, name)?;
+        write!(w, r#","albums":{}}}"#, n)?;
+    }
+    write!(w, "]")
+}
+
 /// Write a json representation of the album and its tracks to the writer.
 ///
 /// The album is expected to come from this index, so the artists and
@@ -64,30 +201,179 @@ pub fn write_album_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: AlbumId,
     write!(w, r#"{{"title":"#)?;
     serde_json::to_writer(&mut w, index.get_string(album.title))?;
     write!(w, r#","artist":"#)?;
-    serde_json::to_writer(&mut w, index.get_string(artist.name))?;
+    serde_json::to_writer(&mut w, index.get_string(album.artist))?;
+    write!(w, r#","artists":"#)?;
+    write_album_artists_json(index, &mut w, id)?;
     write!(w, r#","sort_artist":"#)?;
     serde_json::to_writer(&mut w, index.get_string(artist.name_for_sort))?;
+    let album_mbids = index.get_album_mbids(id);
+    write!(w, r#","release_mbid":"#)?;
+    write_mbid_json(&mut w, album_mbids.and_then(|m| m.release.get()))?;
+    write!(w, r#","release_group_mbid":"#)?;
+    write_mbid_json(&mut w, album_mbids.and_then(|m| m.release_group.get()))?;
+    write!(w, r#","genres":"#)?;
+    write_string_list_json(index, &mut w, index.get_album_genres(id))?;
+    let details = index.get_album_details(id);
+    let detail = |field: fn(&AlbumDetails) -> StringRef| details.map_or("", |d| index.get_string(field(d)));
+    write!(w, r#","label":"#)?;
+    write_optional_string_json(&mut w, detail(|d| d.label))?;
+    write!(w, r#","catalogue_number":"#)?;
+    write_optional_string_json(&mut w, detail(|d| d.catalogue_number))?;
+    write!(w, r#","release_country":"#)?;
+    write_optional_string_json(&mut w, detail(|d| d.release_country))?;
+    write!(w, r#","release_type":"#)?;
+    write_optional_string_json(&mut w, detail(|d| d.release_type))?;
+    write!(w, r#","release_status":"#)?;
+    write_optional_string_json(&mut w, detail(|d| d.release_status))?;
+    let kind = details.map_or(ReleaseKind::Album, |d| d.release_kind());
+    write!(w, r#","release_kind":"{}""#, kind.as_str())?;
+    write!(w, r#","disc_total":"#)?;
+    write_count_json(&mut w, album.disc_total.into())?;
     write!(w, r#","date":"{}","tracks":["#, album.original_release_date)?;
     let mut first = true;
     for &(ref tid, ref track) in index.get_album_tracks(id) {
         if !first { write!(w, ",")?; }
-        write!(w, r#"{{"id":"{}","disc_number":{},"track_number":{},"title":"#,
+        write!(w, r#"{{"id":"{}","disc_number":{},"track_number":{},"track_total":"#,
                tid, track.disc_number, track.track_number)?;
+        write_count_json(&mut w, track.track_total)?;
+        write!(w, r#","title":"#)?;
         serde_json::to_writer(&mut w, index.get_string(track.title))?;
         write!(w, r#","artist":"#)?;
         serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+        write!(w, r#","composers":"#)?;
+        write_track_composers_json(index, &mut w, *tid)?;
+        let track_work = index.get_track_work(*tid);
+        match track_work {
+            Some(tw) => write!(w, r#","work_id":"{}","movement":"#, tw.work_id)?,
+            None => write!(w, r#","work_id":null,"movement":"#)?,
+        }
+        write_optional_string_json(&mut w, track_work.map_or("", |tw| index.get_string(tw.movement_name)))?;
+        write!(w, r#","movement_number":"#)?;
+        write_count_json(&mut w, track_work.map_or(0, |tw| tw.movement_number.into()))?;
+        let track_mbids = index.get_track_mbids(*tid);
+        write!(w, r#","recording_mbid":"#)?;
+        write_mbid_json(&mut w, track_mbids.and_then(|m| m.recording.get()))?;
+        write!(w, r#","track_mbid":"#)?;
+        write_mbid_json(&mut w, track_mbids.and_then(|m| m.track.get()))?;
         write!(w, r#","duration_seconds":{}}}"#, track.duration_seconds)?;
         first = false;
     }
+    write!(w, r#"],"works":["#)?;
+    let (works, _) = group_by_work(index, index.get_album_tracks(id).iter().map(|&(tid, _)| tid));
+    for (i, &(work_id, ref track_ids)) in works.iter().enumerate() {
+        if i > 0 { write!(w, ",")?; }
+        write_work_header_json(index, &mut w, work_id)?;
+        for (j, track_id) in track_ids.iter().enumerate() {
+            if j > 0 { write!(w, ",")?; }
+            write!(w, r#""{}""#, track_id)?;
+        }
+        write!(w, "]}}")?;
+    }
+    write!(w, r#"],"discs":["#)?;
+    // Tracks are ordered by disc number first, so discs are contiguous.
+    let mut prev_disc = None;
+    for &(tid, ref track) in index.get_album_tracks(id) {
+        if prev_disc == Some(track.disc_number) {
+            write!(w, r#","{}""#, tid)?;
+            continue
+        }
+        if prev_disc.is_some() { write!(w, "]}},")?; }
+        write!(w, r#"{{"disc_number":{},"subtitle":"#, track.disc_number)?;
+        match index.get_disc_subtitle(id, track.disc_number) {
+            Some(subtitle) => serde_json::to_writer(&mut w, index.get_string(subtitle))?,
+            None => write!(w, "null")?,
+        }
+        write!(w, r#","tracks":["{}""#, tid)?;
+        prev_disc = Some(track.disc_number);
+    }
+    if prev_disc.is_some() { write!(w, "]}}")?; }
+    write!(w, "]}}")
+}
+
+/// Write the pictures of an album, without their data, as json.
+///
+/// The index of a picture in the list is the `n` in `/cover/:album_id/:n`.
+pub fn write_album_pictures_json<W: Write>(
+    mut w: W,
+    album_id: AlbumId,
+    pictures: &[AlbumPicture],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    for (i, picture) in pictures.iter().enumerate() {
+        if i > 0 { write!(w, ",")?; }
+        write!(
+            w,
+            r#"{{"index":{},"url":"/cover/{}/{}","picture_type":{},"kind":"{}","mime_type":"#,
+            i,
+            album_id,
+            i,
+            picture.picture_type,
+            cover::picture_type_name(picture.picture_type),
+        )?;
+        serde_json::to_writer(&mut w, &picture.mime_type)?;
+        write!(w, r#","description":"#)?;
+        serde_json::to_writer(&mut w, &picture.description)?;
+        match picture.dimensions {
+            Some((width, height)) => write!(w, r#","width":{},"height":{}"#, width, height)?,
+            None => write!(w, r#","width":null,"height":null"#)?,
+        }
+        write!(w, r#","size_bytes":{},"embedded":{}}}"#, picture.size_bytes, picture.is_embedded())?;
+    }
+    write!(w, "]")
+}
+
+/// Write all composers, with the number of tracks they composed, as json.
+pub fn write_composers_json<W: Write>(index: &dyn MetaIndex, mut w: W) -> io::Result<()> {
+    write!(w, "[")?;
+    for (i, &(id, ref composer)) in index.get_composers().iter().enumerate() {
+        if i > 0 { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","name":"#, id)?;
+        serde_json::to_writer(&mut w, index.get_string(composer.name))?;
+        write!(w, r#","tracks":{}}}"#, index.get_tracks_by_composer(id).len())?;
+    }
+    write!(w, "]")
+}
+
+/// Write a json representation of the composer, with their works and other tracks.
+///
+/// Works and tracks are ordered by release date of the album they appear on,
+/// so recordings of the same work on different albums are grouped together.
+pub fn write_composer_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    id: ComposerId,
+    composer: &Composer,
+) -> io::Result<()> {
+    let track_ids = index.get_tracks_by_composer(id).iter().map(|&(_, tid)| tid);
+    let (works, other) = group_by_work(index, track_ids);
+
+    write!(w, r#"{{"id":"{}","name":"#, id)?;
+    serde_json::to_writer(&mut w, index.get_string(composer.name))?;
+    write!(w, r#","works":["#)?;
+    for (i, &(work_id, ref track_ids)) in works.iter().enumerate() {
+        if i > 0 { write!(w, ",")?; }
+        write_work_header_json(index, &mut w, work_id)?;
+        for (j, &track_id) in track_ids.iter().enumerate() {
+            if j > 0 { write!(w, ",")?; }
+            write_search_track_json(index, &mut w, track_id)?;
+        }
+        write!(w, "]}}")?;
+    }
+    write!(w, r#"],"tracks":["#)?;
+    for (i, &track_id) in other.iter().enumerate() {
+        if i > 0 { write!(w, ",")?; }
+        write_search_track_json(index, &mut w, track_id)?;
+    }
     write!(w, "]}}")
 }
 
-/// Write a json representation of the artist and its albums.
+/// Write a json representation of the artist, its albums, and the tracks it appears on.
 pub fn write_artist_json<W: Write>(
     index: &dyn MetaIndex,
     mut w: W,
     artist: &Artist,
     albums: &[(ArtistId, AlbumId)],
+    appearances: &[(ArtistId, TrackId)],
 ) -> io::Result<()> {
     write!(w, r#"{{"name":"#)?;
     serde_json::to_writer(&mut w, index.get_string(artist.name))?;
@@ -102,6 +388,33 @@ pub fn write_artist_json<W: Write>(
         write_brief_album_json(index, &mut w, album_id, album)?;
         first = false;
     }
+    write!(w, r#"],"discography":["#)?;
+    for (i, &(kind, ref groups)) in browse::discography(index, albums).iter().enumerate() {
+        if i > 0 { write!(w, ",")?; }
+        write!(w, r#"{{"kind":"{}","releases":["#, kind.as_str())?;
+        for (j, group) in groups.iter().enumerate() {
+            if j > 0 { write!(w, ",")?; }
+            // All editions in a group share the release group, if they have one.
+            let release_group = index.get_album_mbids(group[0]).and_then(|m| m.release_group.get());
+            write!(w, r#"{{"release_group_mbid":"#)?;
+            write_mbid_json(&mut w, release_group)?;
+            write!(w, r#","albums":["#)?;
+            for (k, &album_id) in group.iter().enumerate() {
+                let album = index.get_album(album_id).unwrap();
+                if k > 0 { write!(w, ",")?; }
+                write_brief_album_json(index, &mut w, album_id, album)?;
+            }
+            write!(w, "]}}")?;
+        }
+        write!(w, "]}}")?;
+    }
+    write!(w, r#"],"appears_on":["#)?;
+    let mut first = true;
+    for &(_, track_id) in appearances {
+        if !first { write!(w, ",")?; }
+        write_search_track_json(index, &mut w, track_id)?;
+        first = false;
+    }
     write!(w, "]}}")
 }
 
@@ -111,6 +424,7 @@ pub fn write_search_results_json<W: Write>(
     artists: &[ArtistId],
     albums: &[AlbumId],
     tracks: &[TrackId],
+    composers: &[ComposerId],
 ) -> io::Result<()> {
     write!(w, r#"{{"artists":["#)?;
     let mut first = true;
@@ -133,6 +447,14 @@ pub fn write_search_results_json<W: Write>(
         write_search_track_json(index, &mut w, tid)?;
         first = false;
     }
+    write!(w, r#"],"composers":["#)?;
+    for (i, &cid) in composers.iter().enumerate() {
+        let composer = index.get_composer(cid).unwrap();
+        if i > 0 { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","name":"#, cid)?;
+        serde_json::to_writer(&mut w, index.get_string(composer.name))?;
+        write!(w, "}}")?;
+    }
     write!(w, r#"]}}"#)
 }
 
@@ -153,23 +475,23 @@ pub fn write_search_artist_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: A
 
 pub fn write_search_album_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: AlbumId) -> io::Result<()> {
     let album = index.get_album(id).unwrap();
-    let artist = index.get_artist(album.artist_id).unwrap();
     write!(w, r#"{{"id":"{}","title":"#, id)?;
     serde_json::to_writer(&mut w, index.get_string(album.title))?;
     write!(w, r#","artist":"#)?;
-    serde_json::to_writer(&mut w, index.get_string(artist.name))?;
+    serde_json::to_writer(&mut w, index.get_string(album.artist))?;
     write!(w, r#","date":"{}"}}"#, album.original_release_date)
 }
 
 pub fn write_search_track_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: TrackId) -> io::Result<()> {
     let track = index.get_track(id).unwrap();
-    let album = index.get_album(track.album_id).unwrap();
+    let album = index.get_album(id.album_id()).unwrap();
     write!(w, r#"{{"id":"{}","title":"#, id)?;
     serde_json::to_writer(&mut w, index.get_string(track.title))?;
-    write!(w, r#","album_id":"{}","album":"#, track.album_id)?;
+    write!(w, r#","album_id":"{}","album":"#, id.album_id())?;
     serde_json::to_writer(&mut w, index.get_string(album.title))?;
     write!(w, r#","artist":"#)?;
     serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+    write_disc_of_total_json(index, &mut w, id.album_id(), album, track)?;
     write!(w, r#"}}"#)
 }
 
@@ -181,7 +503,8 @@ fn write_queued_track_json<W: Write>(
     // Same as the search result track format, but additionally includes
     // the duration, and playback information.
     let track = index.get_track(queued_track.track_id).unwrap();
-    let album = index.get_album(track.album_id).unwrap();
+    let album_id = queued_track.track_id.album_id();
+    let album = index.get_album(album_id).unwrap();
     write!(
         w,
         r#"{{"queue_id":"{}","track_id":"{}","title":"#,
@@ -189,10 +512,11 @@ fn write_queued_track_json<W: Write>(
         queued_track.track_id,
     )?;
     serde_json::to_writer(&mut w, index.get_string(track.title))?;
-    write!(w, r#","album_id":"{}","album":"#, track.album_id)?;
+    write!(w, r#","album_id":"{}","album":"#, album_id)?;
     serde_json::to_writer(&mut w, index.get_string(album.title))?;
     write!(w, r#","artist":"#)?;
     serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+    write_disc_of_total_json(index, &mut w, album_id, album, track)?;
     write!(w, r#","duration_seconds":{}"#, track.duration_seconds)?;
 
     let position_seconds = queued_track.position_ms as f32 * 1e-3;
@@ -211,6 +535,10 @@ pub fn write_queue_json<W: Write>(
     write!(w, "[")?;
     let mut first = true;
     for queued_track in tracks.iter() {
+        // After a reindex, the track that is playing, or the one that is being
+        // decoded, may no longer be in the index. We have no metadata to show
+        // for those, so omit them.
+        if index.get_track(queued_track.track_id).is_none() { continue }
         if !first { write!(w, ",")?; }
         write_queued_track_json(index, &mut w, queued_track)?;
         first = false;
@@ -221,3 +549,61 @@ pub fn write_queue_json<W: Write>(
 pub fn write_volume_json<W: Write>(mut w: W, current_volume: Millibel) -> io::Result<()> {
     write!(w, r#"{{"volume_db":{:.02}}}"#, current_volume.0 as f32 * 0.01)
 }
+
+pub fn write_status_json<W: Write>(mut w: W, device_status: &DeviceStatus) -> io::Result<()> {
+    match *device_status {
+        DeviceStatus::Idle => write!(w, r#"{{"audio_device":"idle"}}"#),
+        DeviceStatus::Open => write!(w, r#"{{"audio_device":"open"}}"#),
+        DeviceStatus::Unavailable(ref reason) => {
+            write!(w, r#"{{"audio_device":"unavailable","audio_device_error":"#)?;
+            serde_json::to_writer(&mut w, reason)?;
+            write!(w, "}}")
+        }
+    }
+}
+
+pub fn write_scan_status_json<W: Write>(mut w: W, status: &ScanStatus) -> io::Result<()> {
+    let stage = match status.stage {
+        ScanStage::Discovering => "discovering",
+        ScanStage::Indexing => "indexing",
+        ScanStage::Writing => "writing",
+        ScanStage::Done => "done",
+        ScanStage::Failed => "failed",
+    };
+    write!(
+        w,
+        r#"{{"stage":"{}","files_discovered":{},"files_added":{},"files_changed":{},"files_removed":{},"files_indexed":{},"issues":{},"error":"#,
+        stage,
+        status.files_discovered,
+        status.files_added,
+        status.files_changed,
+        status.files_removed,
+        status.files_indexed,
+        status.issues,
+    )?;
+    serde_json::to_writer(&mut w, &status.error)?;
+    write!(w, "}}")
+}
+
+/// Write a json representation of a single issue.
+pub fn write_issue_json<W: Write>(mut w: W, issue: &Issue) -> io::Result<()> {
+    write!(w, r#"{{"filename":"#)?;
+    serde_json::to_writer(&mut w, &issue.filename)?;
+    let severity = if issue.detail.is_error() { "error" } else { "warning" };
+    write!(w, r#","severity":"{}","kind":"{}","field":"#, severity, issue.detail.kind())?;
+    serde_json::to_writer(&mut w, &issue.detail.field())?;
+    write!(w, r#","message":"#)?;
+    serde_json::to_writer(&mut w, &issue.detail.to_string())?;
+    write!(w, "}}")
+}
+
+pub fn write_issues_json<W: Write>(mut w: W, issues: &[Issue]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for issue in issues {
+        if !first { write!(w, ",")?; }
+        write_issue_json(&mut w, issue)?;
+        first = false;
+    }
+    write!(w, "]")
+}
@@ -0,0 +1,42 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2021 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A shared value that can be replaced while it is in use.
+
+use std::sync::{Arc, Mutex};
+
+/// Holds an `Arc<T>` that can be replaced atomically.
+///
+/// Readers take a snapshot with `get`, and keep using that snapshot for as
+/// long as they need a consistent view, for example for the duration of a
+/// request. When the value is replaced, readers that still hold the old
+/// snapshot keep it alive until they are done.
+///
+/// The mutex is only held to clone or replace the `Arc`, never while the
+/// value is in use, so contention is negligible.
+pub struct Swap<T: ?Sized> {
+    current: Mutex<Arc<T>>,
+}
+
+impl<T: ?Sized> Swap<T> {
+    pub fn new(value: Arc<T>) -> Swap<T> {
+        Swap {
+            current: Mutex::new(value),
+        }
+    }
+
+    /// Return a snapshot of the current value.
+    pub fn get(&self) -> Arc<T> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Replace the current value, return the previous one.
+    pub fn set(&self, value: Arc<T>) -> Arc<T> {
+        let mut current = self.current.lock().unwrap();
+        std::mem::replace(&mut *current, value)
+    }
+}
@@ -0,0 +1,156 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2021 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Integration with systemd: readiness notification, watchdog, and socket activation.
+//!
+//! These implement the parts of the `sd_notify` and `sd_listen_fds` protocols
+//! that I need, without linking libsystemd. When Musium does not run under
+//! systemd, the environment variables are absent, and all of this is a no-op.
+
+use std::env;
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::time::Duration;
+
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+use nix::sys::socket::{AddressFamily, MsgFlags, SockAddr, SockFlag, SockType, UnixAddr};
+use nix::sys::socket::sockopt;
+use nix::sys::socket;
+use nix::unistd;
+
+/// The first file descriptor passed by systemd, see `sd_listen_fds(3)`.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Return whether the environment variable is set to the pid of this process.
+///
+/// Systemd sets `LISTEN_PID` and `WATCHDOG_PID` so that child processes that
+/// inherit the environment know that the other variables are not for them.
+fn is_for_this_process(pid_var: &str) -> bool {
+    match env::var(pid_var) {
+        Ok(pid) => pid.parse() == Ok(unistd::getpid().as_raw()),
+        Err(..) => false,
+    }
+}
+
+/// Send a state change to the service manager, see `sd_notify(3)`.
+///
+/// Does nothing when `NOTIFY_SOCKET` is not set.
+pub fn notify(state: &str) -> nix::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    // A leading @ indicates a socket in the abstract namespace.
+    let path = path.as_bytes();
+    let addr = match path.first() {
+        Some(&b'@') => UnixAddr::new_abstract(&path[1..])?,
+        _ => UnixAddr::new(path)?,
+    };
+
+    let fd = socket::socket(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let result = socket::sendto(fd, state.as_bytes(), &SockAddr::Unix(addr), MsgFlags::empty());
+    unistd::close(fd)?;
+    result.map(|_| ())
+}
+
+/// Tell the service manager that startup is complete.
+pub fn notify_ready() {
+    if let Err(err) = notify("READY=1") {
+        println!("Failed to notify systemd about readiness: {}", err);
+    }
+}
+
+/// Tell the service manager that we are shutting down.
+pub fn notify_stopping() {
+    if let Err(err) = notify("STOPPING=1") {
+        println!("Failed to notify systemd about stopping: {}", err);
+    }
+}
+
+/// Tell the service manager that we are still alive.
+pub fn notify_watchdog() {
+    if let Err(err) = notify("WATCHDOG=1") {
+        println!("Failed to ping systemd watchdog: {}", err);
+    }
+}
+
+/// Return the watchdog timeout, if the service manager enabled the watchdog.
+///
+/// We should ping the watchdog at least once per timeout, systemd recommends
+/// to do so every half timeout.
+pub fn watchdog_timeout() -> Option<Duration> {
+    // WATCHDOG_PID is optional, but when it is set, it must be us.
+    if env::var_os("WATCHDOG_PID").is_some() && !is_for_this_process("WATCHDOG_PID") {
+        return None
+    }
+
+    match env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse().ok()) {
+        Some(0) | None => None,
+        Some(usec) => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Return whether the file descriptor is a listening TCP socket.
+///
+/// The socket unit could be configured with a Unix or datagram socket, which
+/// we can't serve http on.
+fn is_tcp_listener(fd: RawFd) -> bool {
+    let is_stream = socket::getsockopt(fd, sockopt::SockType) == Ok(SockType::Stream);
+    let is_listening = socket::getsockopt(fd, sockopt::AcceptConn) == Ok(true);
+    let is_inet = match socket::getsockname(fd) {
+        Ok(SockAddr::Inet(..)) => true,
+        _ => false,
+    };
+    is_stream && is_listening && is_inet
+}
+
+/// Take the listening socket passed by systemd through socket activation.
+///
+/// Returns `None` when no socket was passed. If multiple sockets were passed,
+/// this uses the first one, Musium only listens on one socket.
+pub fn take_listener() -> Option<TcpListener> {
+    if !is_for_this_process("LISTEN_PID") {
+        return None
+    }
+
+    let n_fds: RawFd = match env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok()) {
+        Some(n) if n > 0 => n,
+        _ => return None,
+    };
+
+    if n_fds > 1 {
+        println!("Received {} sockets from systemd, using only the first one.", n_fds);
+    }
+
+    // Remove the variables, so we do not take the socket twice.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let fd = LISTEN_FDS_START;
+    if !is_tcp_listener(fd) {
+        println!("Socket passed by systemd is not a listening TCP socket, ignoring it.");
+        return None
+    }
+
+    // Systemd does not set close-on-exec on the file descriptors it passes.
+    if let Err(err) = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        println!("Failed to set close-on-exec on socket from systemd: {}", err);
+    }
+
+    // Safety: `sd_listen_fds(3)` guarantees that the file descriptor is open,
+    // we checked that it is a TCP listener, and we removed the environment
+    // variables, so nothing else takes it.
+    Some(unsafe { TcpListener::from_raw_fd(fd) })
+}
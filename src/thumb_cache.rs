@@ -99,6 +99,10 @@ impl ThumbCache {
         let mut references = AlbumTable::new(albums.len(), dummy);
 
         for (_, album_id) in albums {
+            // Albums with multiple artists occur once per artist, but we
+            // only need to load their thumb once.
+            if references.get(*album_id).is_some() { continue }
+
             fname.push(format!("{}.jpg", album_id));
             match fs::File::open(&fname) {
                 Ok(mut f) => {
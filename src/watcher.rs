@@ -0,0 +1,236 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2021 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Watching the library for changes with inotify.
+//!
+//! Inotify is not recursive, so we add a watch for every directory in the
+//! library. Like the scan, we follow symlinks, so a symlinked directory is
+//! watched through its target. When directories are added or removed, we walk
+//! the library again to bring the watches up to date.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, poll};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+use nix::unistd;
+
+/// The events that we watch for on every directory.
+fn watch_flags() -> AddWatchFlags {
+    // A file that was modified is closed afterwards, we only need the close.
+    // Touching a file only produces an attribute change, but that changes the
+    // mtime, and then we would rescan the file, so we watch for that too.
+    AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_ATTRIB
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_MOVED_TO
+}
+
+/// Watches all directories in the library for changes to flac files.
+pub struct Watcher {
+    root: PathBuf,
+    inotify: Inotify,
+    /// The watched directories. A directory reachable through multiple paths
+    /// has a single watch, we store the first path we found it under.
+    watches: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl Watcher {
+    /// Start watching all directories under `root`.
+    pub fn new(root: &Path) -> nix::Result<Watcher> {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)?;
+        let mut watcher = Watcher {
+            root: root.to_path_buf(),
+            inotify: inotify,
+            watches: HashMap::new(),
+        };
+        watcher.update_watches()?;
+        Ok(watcher)
+    }
+
+    /// Return the number of watched directories.
+    pub fn len(&self) -> usize {
+        self.watches.len()
+    }
+
+    /// Watch all directories in the library, and stop watching the ones that left it.
+    fn update_watches(&mut self) -> nix::Result<()> {
+        let mut watches = HashMap::with_capacity(self.watches.len());
+
+        let dirs = walkdir::WalkDir::new(&self.root)
+            .follow_links(true)
+            .into_iter()
+            .filter_map(|e| match e {
+                Ok(entry) => Some(entry),
+                Err(err) => { eprintln!("{}", err); None }
+            })
+            .filter(|e| e.file_type().is_dir());
+
+        for dir in dirs {
+            match self.inotify.add_watch(dir.path(), watch_flags()) {
+                Ok(wd) => { watches.entry(wd).or_insert_with(|| dir.into_path()); }
+                // The directory may have been removed after we listed it. Then
+                // its parent will report that, and we update again.
+                Err(nix::Error::Sys(Errno::ENOENT)) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        for (&wd, _) in self.watches.iter() {
+            if !watches.contains_key(&wd) {
+                // If the directory was deleted, the kernel removed the watch
+                // already, so this can fail, but then there is nothing to do.
+                let _ = self.inotify.rm_watch(wd);
+            }
+        }
+
+        self.watches = watches;
+        Ok(())
+    }
+
+    /// Return whether a new entry is a directory, or a symlink to one.
+    fn is_new_dir(&self, event: &InotifyEvent) -> bool {
+        if event.mask.contains(AddWatchFlags::IN_ISDIR) {
+            return true
+        }
+        match (self.watches.get(&event.wd), &event.name) {
+            (Some(dir), Some(name)) => fs::metadata(dir.join(name)).map(|m| m.is_dir()).unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Return whether a removed entry was a directory, or a symlink to one.
+    fn is_removed_dir(&self, event: &InotifyEvent) -> bool {
+        if event.mask.contains(AddWatchFlags::IN_ISDIR) {
+            return true
+        }
+        // The entry is gone, so we can't tell what it was. If it was a symlink
+        // to a directory, we watch that directory under this path.
+        match (self.watches.get(&event.wd), &event.name) {
+            (Some(dir), Some(name)) => {
+                let path = dir.join(name);
+                self.watches.values().any(|p| *p == path)
+            }
+            _ => false,
+        }
+    }
+
+    /// Wait at most `timeout` for changes.
+    ///
+    /// Returns whether flac files in the library were added, removed, or
+    /// modified. This is conservative: when a directory is added or removed,
+    /// we report a change, even if it contains no flac files.
+    pub fn poll(&mut self, timeout: Duration) -> nix::Result<bool> {
+        let mut fds = [PollFd::new(self.inotify.as_raw_fd(), PollFlags::POLLIN)];
+        if poll(&mut fds, timeout.as_millis() as i32)? == 0 {
+            return Ok(false)
+        }
+
+        let added = AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO;
+        let removed = AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM;
+        let flac_ext = OsStr::new("flac");
+
+        let mut is_changed = false;
+        let mut needs_update = false;
+
+        loop {
+            let events = match self.inotify.read_events() {
+                Ok(events) => events,
+                Err(nix::Error::Sys(Errno::EAGAIN)) => break,
+                Err(err) => return Err(err),
+            };
+
+            for event in events {
+                if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                    // We lost events, so we don't know what changed.
+                    is_changed = true;
+                    needs_update = true;
+                } else if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                    // The directory was deleted, the kernel removed the watch.
+                    self.watches.remove(&event.wd);
+                } else if event.mask.intersects(removed) && self.is_removed_dir(&event) {
+                    is_changed = true;
+                    needs_update = true;
+                } else if event.mask.intersects(added) && self.is_new_dir(&event) {
+                    // Files may have been added to the directory before we
+                    // started watching it, so count this as a change.
+                    is_changed = true;
+                    needs_update = true;
+                } else if event.name.as_ref().map(|n| Path::new(n).extension()) == Some(Some(flac_ext)) {
+                    is_changed = true;
+                }
+            }
+        }
+
+        if needs_update {
+            self.update_watches()?;
+        }
+
+        Ok(is_changed)
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        // Inotify in this version of nix does not close the fd on drop.
+        let _ = unistd::close(self.inotify.as_raw_fd());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::process;
+    use std::time::Duration;
+
+    use super::Watcher;
+
+    #[test]
+    fn watcher_sees_flac_files_in_symlinked_directories() {
+        let base = env::temp_dir().join(format!("musium-test-watcher-{}", process::id()));
+        let library = base.join("library");
+        let elsewhere = base.join("elsewhere");
+        fs::create_dir_all(&library).unwrap();
+        fs::create_dir_all(elsewhere.join("album")).unwrap();
+        symlink(&elsewhere, library.join("linked")).unwrap();
+
+        let timeout = Duration::from_millis(100);
+        let mut watcher = Watcher::new(&library).unwrap();
+        assert_eq!(watcher.len(), 3);
+        assert_eq!(watcher.poll(timeout), Ok(false));
+
+        // Files other than flac files are not interesting.
+        fs::write(elsewhere.join("album").join("cover.jpg"), b"").unwrap();
+        assert_eq!(watcher.poll(timeout), Ok(false));
+
+        fs::write(elsewhere.join("album").join("01.flac"), b"").unwrap();
+        assert_eq!(watcher.poll(timeout), Ok(true));
+
+        // A new directory counts as a change, and afterwards it is watched.
+        fs::create_dir(library.join("new")).unwrap();
+        assert_eq!(watcher.poll(timeout), Ok(true));
+        assert_eq!(watcher.len(), 4);
+        fs::write(library.join("new").join("01.flac"), b"").unwrap();
+        assert_eq!(watcher.poll(timeout), Ok(true));
+
+        // Removing the symlink removes files from the library.
+        fs::remove_file(library.join("linked")).unwrap();
+        assert_eq!(watcher.poll(timeout), Ok(true));
+        assert_eq!(watcher.len(), 2);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -35,8 +35,8 @@
 //! * Use match metadata to rank the matches.
 
 use std::cmp;
-use std::mem;
 use std::fmt;
+use std::mem;
 
 /// Packed metadata about a an entry in the word index.
 ///
@@ -137,15 +137,16 @@ impl WordMeta {
     }
 }
 
-#[repr(align(8))]
+/// A slice of the key data, one word.
+#[repr(C, align(8))]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-struct Key {
-    offset: u32,
-    len: u32,
+pub struct Key {
+    pub offset: u32,
+    pub len: u32,
 }
 
 /// A slice of values in the word index, usually all values associated with a key.
-#[repr(align(8))]
+#[repr(C, align(8))]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Values {
     pub offset: u32,
@@ -287,6 +288,75 @@ impl<T> MemoryWordIndex<T> {
         }
     }
 
+    /// Borrow the arrays of this index.
+    pub fn as_index_ref(&self) -> WordIndexRef<T> {
+        WordIndexRef {
+            key_slices: &self.key_slices,
+            value_slices: &self.value_slices,
+            key_data: &self.key_data,
+            value_data: &self.value_data,
+            meta_data: &self.meta_data,
+        }
+    }
+}
+
+/// A word index that borrows its arrays, for example from a memory-mapped file.
+///
+/// The search logic lives here, `MemoryWordIndex` delegates to it.
+pub struct WordIndexRef<'a, T> {
+    key_slices: &'a [Key],
+    value_slices: &'a [Values],
+    key_data: &'a str,
+    value_data: &'a [T],
+    meta_data: &'a [WordMeta],
+}
+
+impl<'a, T> WordIndexRef<'a, T> {
+    /// Assemble a word index from its arrays.
+    ///
+    /// Returns `None` if a key or value slice is out of bounds, or if a key
+    /// does not fall on character boundaries, so lookups can index into the
+    /// arrays without further checks.
+    pub fn new(
+        key_slices: &'a [Key],
+        value_slices: &'a [Values],
+        key_data: &'a str,
+        value_data: &'a [T],
+        meta_data: &'a [WordMeta],
+    ) -> Option<WordIndexRef<'a, T>> {
+        let keys_ok = key_slices.iter().all(|k| {
+            let begin = k.offset as usize;
+            let end = begin + k.len as usize;
+            end <= key_data.len()
+                && key_data.is_char_boundary(begin)
+                && key_data.is_char_boundary(end)
+        });
+        let values_ok = value_slices.iter().all(|v|
+            v.offset as usize + v.len as usize <= value_data.len()
+        );
+        // Note that an empty index has one (empty) value slice, but no keys.
+        let lens_ok = key_slices.len() <= value_slices.len()
+            && value_data.len() == meta_data.len();
+
+        if !(keys_ok && values_ok && lens_ok) {
+            return None
+        }
+
+        let result = WordIndexRef {
+            key_slices,
+            value_slices,
+            key_data,
+            value_data,
+            meta_data,
+        };
+        Some(result)
+    }
+
+    /// Return the underlying arrays, in the same order as `new` takes them.
+    pub fn into_parts(self) -> (&'a [Key], &'a [Values], &'a str, &'a [T], &'a [WordMeta]) {
+        (self.key_slices, self.value_slices, self.key_data, self.value_data, self.meta_data)
+    }
+
     pub fn size(&self) -> WordIndexSize {
         WordIndexSize {
             key_data_bytes: self.key_data.len(),
@@ -300,8 +370,7 @@ impl<T> MemoryWordIndex<T> {
         }
     }
 
-
-    fn get_key(&self, key: Key) -> &str {
+    fn get_key(&self, key: Key) -> &'a str {
         &self.key_data[key.offset as usize..key.offset as usize + key.len as usize]
     }
 
@@ -378,6 +447,30 @@ impl<T> MemoryWordIndex<T> {
             cmp::Ordering::Greater => max,
         }
     }
+
+    /// Return the value range associated with `word`.
+    ///
+    /// Like `WordIndex::search_exact`, but not tied to the lifetime of `self`.
+    fn exact_values(&self, word: &str) -> Option<Values> {
+        let index = self.find_lower(word);
+
+        if index >= self.key_slices.len() { return None }
+        let key = self.get_key(self.key_slices[index]);
+
+        match key == word {
+            true  => Some(self.value_slices[index]),
+            false => None,
+        }
+    }
+
+    /// Return the value ranges for all keys of which `prefix` is a prefix.
+    ///
+    /// Like `WordIndex::search_prefix`, but not tied to the lifetime of `self`.
+    fn prefix_values(&self, prefix: &str) -> &'a [Values] {
+        let min = self.find_lower(prefix);
+        let max = self.find_upper(prefix);
+        &self.value_slices[min..max]
+    }
 }
 
 impl<T> WordIndex for MemoryWordIndex<T> {
@@ -404,21 +497,43 @@ impl<T> WordIndex for MemoryWordIndex<T> {
     }
 
     fn search_exact(&self, word: &str) -> Option<Values> {
-        let index = self.find_lower(word);
+        self.as_index_ref().exact_values(word)
+    }
 
-        if index >= self.key_slices.len() { return None }
-        let key = self.get_key(self.key_slices[index]);
+    fn search_prefix(&self, prefix: &str) -> &[Values] {
+        self.as_index_ref().prefix_values(prefix)
+    }
+}
 
-        match key == word {
-            true  => Some(self.value_slices[index]),
-            false => None,
-        }
+impl<'a, T> WordIndex for WordIndexRef<'a, T> {
+    type Item = T;
+
+    fn len(&self) -> usize {
+        self.key_slices.len()
+    }
+
+    fn get_values(&self, range: Values) -> &[T] {
+        &self.value_data[range.offset as usize..range.offset as usize + range.len as usize]
+    }
+
+    fn get_metas(&self, range: Values) -> &[WordMeta] {
+        &self.meta_data[range.offset as usize..range.offset as usize + range.len as usize]
+    }
+
+    fn get_value(&self, offset: u32) -> &T {
+        &self.value_data[offset as usize]
+    }
+
+    fn get_meta(&self, offset: u32) -> &WordMeta {
+        &self.meta_data[offset as usize]
+    }
+
+    fn search_exact(&self, word: &str) -> Option<Values> {
+        self.exact_values(word)
     }
 
     fn search_prefix(&self, prefix: &str) -> &[Values] {
-        let min = self.find_lower(prefix);
-        let max = self.find_upper(prefix);
-        &self.value_slices[min..max]
+        self.prefix_values(prefix)
     }
 }
 
@@ -569,42 +684,42 @@ mod test {
         let index = MemoryWordIndex::new(&elems);
 
         // "a" would be before element 0, spans 0..0.
-        assert_eq!(index.find_lower("a"), 0);
-        assert_eq!(index.find_upper("a"), 0);
+        assert_eq!(index.as_index_ref().find_lower("a"), 0);
+        assert_eq!(index.as_index_ref().find_upper("a"), 0);
 
         // "h" spans 0..2
-        assert_eq!(index.find_lower("h"), 0);
-        assert_eq!(index.find_upper("h"), 2);
+        assert_eq!(index.as_index_ref().find_lower("h"), 0);
+        assert_eq!(index.as_index_ref().find_upper("h"), 2);
 
         // "hy" also still
-        assert_eq!(index.find_lower("hy"), 0);
-        assert_eq!(index.find_upper("hy"), 2);
+        assert_eq!(index.as_index_ref().find_lower("hy"), 0);
+        assert_eq!(index.as_index_ref().find_upper("hy"), 2);
 
         // "hyb" matches only 0, "hyp" only 1.
-        assert_eq!(index.find_lower("hyb"), 0);
-        assert_eq!(index.find_upper("hyb"), 1);
-        assert_eq!(index.find_lower("hyp"), 1);
-        assert_eq!(index.find_upper("hyp"), 2);
+        assert_eq!(index.as_index_ref().find_lower("hyb"), 0);
+        assert_eq!(index.as_index_ref().find_upper("hyb"), 1);
+        assert_eq!(index.as_index_ref().find_lower("hyp"), 1);
+        assert_eq!(index.as_index_ref().find_upper("hyp"), 2);
 
         // "k" would fall between 1 and 2, so 2..2.
-        assert_eq!(index.find_lower("k"), 2);
-        assert_eq!(index.find_upper("k"), 2);
+        assert_eq!(index.as_index_ref().find_lower("k"), 2);
+        assert_eq!(index.as_index_ref().find_upper("k"), 2);
 
         // "m" hits 2 exactly.
-        assert_eq!(index.find_lower("m"), 2);
-        assert_eq!(index.find_upper("m"), 3);
+        assert_eq!(index.as_index_ref().find_lower("m"), 2);
+        assert_eq!(index.as_index_ref().find_upper("m"), 3);
 
         // "o" would fall between 2 and 3, so 3..3.
-        assert_eq!(index.find_lower("o"), 3);
-        assert_eq!(index.find_upper("o"), 3);
+        assert_eq!(index.as_index_ref().find_lower("o"), 3);
+        assert_eq!(index.as_index_ref().find_upper("o"), 3);
 
         // "ty" hits 3 exactly.
-        assert_eq!(index.find_lower("ty"), 3);
-        assert_eq!(index.find_upper("ty"), 4);
+        assert_eq!(index.as_index_ref().find_lower("ty"), 3);
+        assert_eq!(index.as_index_ref().find_upper("ty"), 4);
 
         // "v" is past the end.
-        assert_eq!(index.find_lower("v"), 4);
-        assert_eq!(index.find_upper("v"), 4);
+        assert_eq!(index.as_index_ref().find_lower("v"), 4);
+        assert_eq!(index.as_index_ref().find_upper("v"), 4);
     }
 
     #[test]
@@ -618,8 +733,8 @@ mod test {
 
         let index = MemoryWordIndex::new(&elems);
 
-        assert_eq!(index.find_lower("hy"), 0);
-        assert_eq!(index.find_upper("hy"), 1);
+        assert_eq!(index.as_index_ref().find_lower("hy"), 0);
+        assert_eq!(index.as_index_ref().find_upper("hy"), 1);
 
         let vs: Vec<_> = index
             .search_prefix("hy")
@@ -628,8 +743,8 @@ mod test {
             .collect();
         assert_eq!(vs, vec![1]);
 
-        assert_eq!(index.find_lower("e"), 0);
-        assert_eq!(index.find_upper("e"), 0);
+        assert_eq!(index.as_index_ref().find_lower("e"), 0);
+        assert_eq!(index.as_index_ref().find_upper("e"), 0);
     }
 
     #[test]
@@ -641,8 +756,8 @@ mod test {
 
         let index = MemoryWordIndex::new(&elems);
 
-        assert_eq!(index.find_lower("z"), 1);
-        assert_eq!(index.find_upper("z"), 2);
+        assert_eq!(index.as_index_ref().find_lower("z"), 1);
+        assert_eq!(index.as_index_ref().find_upper("z"), 2);
     }
 
     #[test]
@@ -654,8 +769,8 @@ mod test {
 
         let index = MemoryWordIndex::new(&elems);
 
-        assert_eq!(index.find_lower("ク"), 2);
-        assert_eq!(index.find_upper("ク"), 3);
+        assert_eq!(index.as_index_ref().find_lower("ク"), 2);
+        assert_eq!(index.as_index_ref().find_upper("ク"), 3);
     }
 
     #[test]
@@ -79,6 +79,10 @@ class Listen:
     duration_seconds: int
     track_number: int
     disc_number: int
+    recording_mbid: Optional[str]
+    track_mbid: Optional[str]
+    release_mbid: Optional[str]
+    release_group_mbid: Optional[str]
 
     def __post_init__(self) -> None:
         assert self.started_at.tzinfo is not None
@@ -104,6 +108,10 @@ class Listen:
             indexed('albumArtist'): self.album_artist,
         }
 
+        # What Last.fm calls the track mbid, is the recording mbid.
+        if self.recording_mbid is not None:
+            result[indexed('mbid')] = self.recording_mbid
+
         return result
 
     def format_listenbrainz_listen(self) -> Dict[str, Any]:
@@ -111,20 +119,24 @@ class Listen:
         Format as a dict that can be submitted as json to the Listenbrainz API.
         See also https://listenbrainz.readthedocs.io/en/production/dev/json/#json-doc.
         """
+        additional_info: Dict[str, Any] = {
+            'listening_from': 'Musium',
+            'tracknumber': self.track_number,
+            # TODO: Include artist_mbids and ISRC, once we track those in the
+            # listens database.
+        }
+        mbids = {
+            'recording_mbid': self.recording_mbid,
+            'track_mbid': self.track_mbid,
+            'release_mbid': self.release_mbid,
+            'release_group_mbid': self.release_group_mbid,
+        }
+        additional_info.update({k: v for k, v in mbids.items() if v is not None})
+
         return {
             'listened_at': int(self.started_at.timestamp()),
             'track_metadata': {
-                'additional_info': {
-                    'listening_from': 'Musium',
-                    'tracknumber': self.track_number,
-                    # TODO: Include Musicbrainz ids, once we track those in the
-                    # listens database. In particular:
-                    # * artist_mbids
-                    # * release_mbid
-                    # * recording_mbid
-                    # * track_mbid
-                    # * ISRC
-                },
+                'additional_info': additional_info,
                 'artist_name': self.track_artist,
                 'track_name': self.track_title,
                 'release_name': self.album_title,
@@ -156,7 +168,11 @@ def get_listens_to_scrobble(
           album_artist,
           duration_seconds,
           track_number,
-          disc_number
+          disc_number,
+          recording_mbid,
+          track_mbid,
+          release_mbid,
+          release_group_mbid
         from
           listens
         where
@@ -165,11 +181,14 @@ def get_listens_to_scrobble(
           and source = 'musium'
 
           -- Last.fm guidelines say to only scrobble after playing for at least
-          -- 30 seconds. Listenbrainz guidelines say to only scroble full tracks
-          -- or at least 4 minutes, but Musium only plays full tracks (when
-          -- completed_at is not null), so that is implied by the query.
+          -- 30 seconds. Both Last.fm and Listenbrainz guidelines say to only
+          -- scrobble after playing half the track or at least 4 minutes.
+          -- Musium usually plays full tracks, but when it shuts down, the
+          -- listen of the track that was playing ends early.
           and cast(strftime('%s', completed_at) as integer) -
               cast(strftime('%s', started_at) as integer) > 30
+          and cast(strftime('%s', completed_at) as integer) -
+              cast(strftime('%s', started_at) as integer) >= min(duration_seconds / 2, 240)
         """
     )
 
@@ -401,11 +420,11 @@ def iter_requests_listenbrainz(listens: Iterator[Listen]) -> Iterator[Listenbrai
     """
     Break up the stream of listens into submission requests.
     """
-    # At the time of writing (when listens do not include Musicbrainz
-    # identifiers), sizes of individual listens are around 190-240 bytes.
-    # So as a first guess, we are going to create batches that are expected to
-    # fit in one request, assuming 215 bytes per listen.
-    listens_per_batch = LISTENBRAINZ_MAX_BODY_BYTES // 215
+    # Without Musicbrainz identifiers, sizes of individual listens are around
+    # 190-240 bytes, the four identifiers add about 200 bytes. So as a first
+    # guess, we are going to create batches that are expected to fit in one
+    # request, assuming 400 bytes per listen. The batch size adapts below.
+    listens_per_batch = LISTENBRAINZ_MAX_BODY_BYTES // 400
 
     batches = iter_chunks(listens, n=listens_per_batch)
     listens_remaining: List[Listen] = []